@@ -0,0 +1,396 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use quik_util::*;
+
+use crate::wire::frame::AckFrequency;
+use crate::wire::{PacketNumber, VarInt};
+
+// Transport parameter advertising support for ACK_FREQUENCY frames, carrying
+// the smallest ack delay the endpoint can use, in microseconds
+// https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-negotiating-extension-use
+pub const MIN_ACK_DELAY: u64 = 0xff04de1b;
+
+// Defaults match RFC 9000 acknowledgement behavior: every second ack-eliciting
+// packet, and any packet that arrives out of order, is acknowledged right away
+// https://datatracker.ietf.org/doc/html/rfc9000#section-13.2.2
+pub const DEFAULT_MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+pub const DEFAULT_ACK_ELICITING_THRESHOLD: u64 = 1;
+pub const DEFAULT_REORDERING_THRESHOLD: u64 = 1;
+
+// Decides when received packets are acknowledged. The application tunes the
+// thresholds directly, and the peer can change them with ACK_FREQUENCY frames
+// if we advertised min_ack_delay.
+#[derive(Debug)]
+pub struct AckPolicy {
+  local_min_ack_delay: Option<Duration>,
+  peer_min_ack_delay: Option<Duration>,
+  ack_eliciting_threshold: u64,
+  max_ack_delay: Duration,
+  reordering_threshold: u64,
+  // Largest sequence number of the ACK_FREQUENCY frames received
+  recv_seq_num: Option<u64>,
+  // Sequence number of the next ACK_FREQUENCY frame we send
+  send_seq_num: u64,
+  unacked_ack_eliciting: u64,
+  // Largest packet number in the last ACK frame we sent
+  largest_reported: Option<u64>,
+  // Packet numbers received above `largest_reported`, in which missing ones
+  // haven't been reported yet
+  unreported: BTreeSet<u64>,
+  ack_now: bool,
+}
+
+impl Default for AckPolicy {
+  fn default() -> Self {
+    Self {
+      local_min_ack_delay: None,
+      peer_min_ack_delay: None,
+      ack_eliciting_threshold: DEFAULT_ACK_ELICITING_THRESHOLD,
+      max_ack_delay: DEFAULT_MAX_ACK_DELAY,
+      reordering_threshold: DEFAULT_REORDERING_THRESHOLD,
+      recv_seq_num: None,
+      send_seq_num: 0,
+      unacked_ack_eliciting: 0,
+      largest_reported: None,
+      unreported: BTreeSet::new(),
+      ack_now: false,
+    }
+  }
+}
+
+// Ack delays are exchanged in microseconds
+pub(crate) fn micros(delay: Duration) -> Result<VarInt> {
+  Ok(VarInt::try_from(u64::try_from(delay.as_micros())?)?)
+}
+
+impl AckPolicy {
+  // The min_ack_delay we send, None to not support ACK_FREQUENCY
+  pub fn local_min_ack_delay(&self) -> Option<Duration> {
+    self.local_min_ack_delay
+  }
+
+  pub fn set_local_min_ack_delay(&mut self, min_ack_delay: Option<Duration>) {
+    self.local_min_ack_delay = min_ack_delay;
+  }
+
+  // The min_ack_delay the peer sent, if any
+  pub fn set_peer_min_ack_delay(&mut self, min_ack_delay: Option<Duration>) {
+    self.peer_min_ack_delay = min_ack_delay;
+  }
+
+  pub fn ack_eliciting_threshold(&self) -> u64 {
+    self.ack_eliciting_threshold
+  }
+
+  // Number of ack-eliciting packets that can be received without sending an
+  // acknowledgement
+  pub fn set_ack_eliciting_threshold(&mut self, threshold: u64) {
+    self.ack_eliciting_threshold = threshold;
+  }
+
+  pub fn max_ack_delay(&self) -> Duration {
+    self.max_ack_delay
+  }
+
+  pub fn set_max_ack_delay(&mut self, max_ack_delay: Duration) {
+    self.max_ack_delay = max_ack_delay;
+  }
+
+  pub fn reordering_threshold(&self) -> u64 {
+    self.reordering_threshold
+  }
+
+  // How far below the largest received packet number a packet can be missing
+  // before an acknowledgement is sent right away, with 0 ignoring reordering
+  pub fn set_reordering_threshold(&mut self, threshold: u64) {
+    self.reordering_threshold = threshold;
+  }
+
+  // Neither ACK_FREQUENCY nor IMMEDIATE_ACK frames are allowed unless we
+  // advertised min_ack_delay
+  // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-negotiating-extension-use
+  fn check_negotiated(local_min_ack_delay: Option<Duration>) -> Result<Duration> {
+    local_min_ack_delay.ok_or_else(|| {
+      Error::transport(
+        TransportErrorCode::ProtocolViolation,
+        "Ack frequency wasn't negotiated",
+      )
+    })
+  }
+
+  // An ACK_FREQUENCY frame can't ask for a max ack delay smaller than our
+  // min_ack_delay
+  // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-ack_frequency-frame
+  pub fn check_recv(local_min_ack_delay: Option<Duration>, frame: &AckFrequency) -> Result<()> {
+    let min_ack_delay = Self::check_negotiated(local_min_ack_delay)?;
+    if frame.request_max_ack_delay < micros(min_ack_delay)? {
+      Err(Error::transport(
+        TransportErrorCode::ProtocolViolation,
+        "Requested max ack delay below min_ack_delay",
+      ))?;
+    }
+    Ok(())
+  }
+
+  // Applies the peer's request, unless a newer one was already applied
+  pub fn on_ack_frequency(&mut self, frame: &AckFrequency) {
+    let seq_num = frame.seq_num.into_inner();
+    if self.recv_seq_num.is_some_and(|recv| seq_num <= recv) {
+      return;
+    }
+    self.recv_seq_num = Some(seq_num);
+    self.ack_eliciting_threshold = frame.ack_eliciting_threshold.into_inner();
+    self.max_ack_delay = Duration::from_micros(frame.request_max_ack_delay.into_inner());
+    self.reordering_threshold = frame.reordering_threshold.into_inner();
+  }
+
+  pub fn check_recv_immediate_ack(local_min_ack_delay: Option<Duration>) -> Result<()> {
+    Self::check_negotiated(local_min_ack_delay).map(|_| ())
+  }
+
+  // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-immediate_ack-frame
+  pub fn on_immediate_ack(&mut self) {
+    self.ack_now = true;
+  }
+
+  // Records a packet received after `largest_received`
+  pub fn on_packet_received(
+    &mut self,
+    packet_number: PacketNumber,
+    largest_received: Option<PacketNumber>,
+    ack_eliciting: bool,
+  ) {
+    let packet_number = u64::from(packet_number);
+    if self.largest_reported < Some(packet_number) {
+      self.unreported.insert(packet_number);
+    }
+    if !ack_eliciting {
+      return;
+    }
+    self.unacked_ack_eliciting += 1;
+    if self.unacked_ack_eliciting > self.ack_eliciting_threshold {
+      self.ack_now = true;
+    }
+    if self.reordering_threshold == 0 {
+      return;
+    }
+
+    // Filling in a gap is only reported right away with the RFC 9000 behavior
+    // https://datatracker.ietf.org/doc/html/rfc9000#section-13.2.1
+    let fills_gap = largest_received.is_some_and(|largest| packet_number < u64::from(largest));
+    if fills_gap && self.reordering_threshold == 1 {
+      self.ack_now = true;
+    }
+    // A missing packet is reported once enough larger ones arrived after it,
+    // whether they came all at once or one at a time
+    // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-expediting-congestion-signa
+    let (Some(missing), Some(&largest_unacked)) =
+      (self.smallest_unreported_missing(), self.unreported.last())
+    else {
+      return;
+    };
+    if largest_unacked - missing >= self.reordering_threshold {
+      self.ack_now = true;
+    }
+  }
+
+  // Smallest packet number above the largest one we reported that is missing
+  // below the largest one received. Before the first ACK frame, only gaps
+  // after the first packet received count.
+  fn smallest_unreported_missing(&self) -> Option<u64> {
+    let first = match self.largest_reported {
+      Some(largest_reported) => largest_reported + 1,
+      None => *self.unreported.first()?,
+    };
+    (first..)
+      .zip(&self.unreported)
+      .find(|(expected, received)| expected != *received)
+      .map(|(missing, _)| missing)
+  }
+
+  // Whether an acknowledgement should be sent without waiting for the max ack
+  // delay to expire
+  pub fn should_ack_now(&self) -> bool {
+    self.ack_now
+  }
+
+  pub fn has_unacked(&self) -> bool {
+    self.unacked_ack_eliciting > 0
+  }
+
+  pub fn on_ack_sent(&mut self) {
+    if let Some(&largest) = self.unreported.last() {
+      self.largest_reported = Some(largest);
+    }
+    self.unreported.clear();
+    self.unacked_ack_eliciting = 0;
+    self.ack_now = false;
+  }
+
+  // Frame asking the peer to acknowledge less (or more) often, which it only
+  // accepts if it advertised min_ack_delay
+  pub fn request(
+    &mut self,
+    ack_eliciting_threshold: u64,
+    max_ack_delay: Duration,
+    reordering_threshold: u64,
+  ) -> Result<AckFrequency> {
+    let Some(peer_min_ack_delay) = self.peer_min_ack_delay else {
+      Err("Peer doesn't support ACK_FREQUENCY")?
+    };
+    if max_ack_delay < peer_min_ack_delay {
+      Err("Max ack delay below the peer's min_ack_delay")?;
+    }
+    let frame = AckFrequency {
+      seq_num: VarInt::try_from(self.send_seq_num)?,
+      ack_eliciting_threshold: VarInt::try_from(ack_eliciting_threshold)?,
+      request_max_ack_delay: micros(max_ack_delay)?,
+      reordering_threshold: VarInt::try_from(reordering_threshold)?,
+    };
+    self.send_seq_num += 1;
+    Ok(frame)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pn(pn: u32) -> PacketNumber {
+    pn.into()
+  }
+
+  fn ack_frequency(seq_num: u32, threshold: u32, delay_us: u32, reordering: u32) -> AckFrequency {
+    AckFrequency {
+      seq_num: seq_num.into(),
+      ack_eliciting_threshold: threshold.into(),
+      request_max_ack_delay: delay_us.into(),
+      reordering_threshold: reordering.into(),
+    }
+  }
+
+  // Receives packets in order, returning whether each was acked right away
+  fn receive(policy: &mut AckPolicy, packet_numbers: impl IntoIterator<Item = u32>) -> Vec<bool> {
+    let mut largest = None;
+    let mut acked = Vec::new();
+    for packet_number in packet_numbers {
+      policy.on_packet_received(pn(packet_number), largest, true);
+      largest = largest.max(Some(pn(packet_number)));
+      acked.push(policy.should_ack_now());
+      if policy.should_ack_now() {
+        policy.on_ack_sent();
+      }
+    }
+    acked
+  }
+
+  #[test]
+  fn default_acks_every_other_packet() {
+    let mut policy = AckPolicy::default();
+    assert_eq!(receive(&mut policy, 0..4), [false, true, false, true]);
+
+    policy.on_packet_received(pn(4), Some(pn(3)), false);
+    assert!(!policy.has_unacked());
+  }
+
+  #[test]
+  fn default_acks_reordering_immediately() {
+    let mut policy = AckPolicy::default();
+    assert_eq!(receive(&mut policy, [0, 2, 1]), [false, true, true]);
+  }
+
+  #[test]
+  fn ack_frequency_needs_min_ack_delay() {
+    let frame = ack_frequency(0, 10, 1000, 0);
+    let err = AckPolicy::check_recv(None, &frame).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+
+    assert!(AckPolicy::check_recv(Some(Duration::from_millis(1)), &frame).is_ok());
+    let err = AckPolicy::check_recv(Some(Duration::from_millis(2)), &frame).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+  }
+
+  #[test]
+  fn ack_frequency_follows_peer_request() {
+    let mut policy = AckPolicy::default();
+    policy.on_ack_frequency(&ack_frequency(1, 3, 50_000, 0));
+    assert_eq!(policy.max_ack_delay(), Duration::from_millis(50));
+    assert_eq!(
+      receive(&mut policy, [0, 1, 2, 3, 5, 4, 9, 10]),
+      [false, false, false, true, false, false, false, true]
+    );
+
+    // Older requests are ignored
+    policy.on_ack_frequency(&ack_frequency(0, 1, 1000, 1));
+    assert_eq!(policy.ack_eliciting_threshold(), 3);
+  }
+
+  #[test]
+  fn reordering_threshold_allows_small_gaps() {
+    let mut policy = AckPolicy::default();
+    policy.set_ack_eliciting_threshold(10);
+    policy.set_reordering_threshold(3);
+    assert_eq!(
+      receive(&mut policy, [0, 3, 1, 7]),
+      [false, false, false, true]
+    );
+  }
+
+  #[test]
+  fn reordering_threshold_counts_gaps_crossed_one_packet_at_a_time() {
+    let mut policy = AckPolicy::default();
+    policy.set_ack_eliciting_threshold(10);
+    policy.set_reordering_threshold(3);
+    // 11 is missing, and reported once 14 arrived
+    assert_eq!(
+      receive(&mut policy, [10, 12, 13, 14]),
+      [false, false, false, true]
+    );
+    // It isn't reported again, and arriving late isn't reported either
+    assert_eq!(
+      receive(&mut policy, [15, 16, 17, 11]),
+      [false, false, false, false]
+    );
+    // A gap right after the last ACK frame counts as well
+    assert_eq!(
+      receive(&mut policy, [19, 20, 21, 22]),
+      [false, false, true, false]
+    );
+  }
+
+  #[test]
+  fn immediate_ack_needs_min_ack_delay() {
+    let err = AckPolicy::check_recv_immediate_ack(None).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert!(AckPolicy::check_recv_immediate_ack(Some(Duration::from_millis(1))).is_ok());
+  }
+
+  #[test]
+  fn immediate_ack() {
+    let mut policy = AckPolicy::default();
+    policy.set_ack_eliciting_threshold(10);
+    policy.on_packet_received(pn(0), None, true);
+    assert!(!policy.should_ack_now());
+    policy.on_immediate_ack();
+    assert!(policy.should_ack_now());
+  }
+
+  #[test]
+  fn request_needs_peer_support() {
+    let mut policy = AckPolicy::default();
+    let max_ack_delay = Duration::from_millis(100);
+    assert!(policy.request(10, max_ack_delay, 0).is_err());
+
+    policy.set_peer_min_ack_delay(Some(Duration::from_millis(1)));
+    assert_eq!(
+      policy.request(10, max_ack_delay, 0).unwrap(),
+      ack_frequency(0, 10, 100_000, 0)
+    );
+    assert_eq!(
+      policy.request(20, max_ack_delay, 2).unwrap(),
+      ack_frequency(1, 20, 100_000, 2)
+    );
+    assert!(policy.request(10, Duration::from_micros(999), 0).is_err());
+  }
+}
@@ -0,0 +1,103 @@
+use quik_util::*;
+
+use crate::wire::{ConnectionId, MAX_CID_LEN};
+
+// Issues the Connection IDs we hand to the peer. Servers behind a load
+// balancer can encode routing or shard information in them, as long as they
+// can't be linked by an observer.
+// https://datatracker.ietf.org/doc/html/rfc9000#section-5.1-3
+pub trait ConnectionIdGenerator {
+  // All issued Connection IDs have this length, as short headers don't carry
+  // it
+  fn cid_len(&self) -> usize;
+  fn generate(&self) -> Result<ConnectionId>;
+}
+
+pub struct RandomConnectionIdGenerator {
+  len: usize,
+}
+
+impl RandomConnectionIdGenerator {
+  pub const DEFAULT_LEN: usize = 8;
+
+  pub fn new(len: usize) -> Result<Self> {
+    if len > MAX_CID_LEN {
+      Err("Connection ID longer than 160 bits")?;
+    }
+    Ok(Self { len })
+  }
+}
+
+impl Default for RandomConnectionIdGenerator {
+  fn default() -> Self {
+    Self {
+      len: Self::DEFAULT_LEN,
+    }
+  }
+}
+
+impl ConnectionIdGenerator for RandomConnectionIdGenerator {
+  fn cid_len(&self) -> usize {
+    self.len
+  }
+
+  fn generate(&self) -> Result<ConnectionId> {
+    ConnectionId::random(self.len)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Prefixes a shard byte to random bytes, like a server routing on it would
+  struct ShardGenerator {
+    shard: u8,
+    random: RandomConnectionIdGenerator,
+  }
+
+  impl ConnectionIdGenerator for ShardGenerator {
+    fn cid_len(&self) -> usize {
+      1 + self.random.cid_len()
+    }
+
+    fn generate(&self) -> Result<ConnectionId> {
+      let random = self.random.generate()?;
+      ConnectionId::new(&[&[self.shard][..], random.as_bytes()].concat())
+    }
+  }
+
+  #[test]
+  fn random_generator_uses_length() {
+    for len in [0, 4, 8, MAX_CID_LEN] {
+      let generator = RandomConnectionIdGenerator::new(len).unwrap();
+      assert_eq!(generator.cid_len(), len);
+      assert_eq!(generator.generate().unwrap().len(), len);
+    }
+    assert_eq!(
+      RandomConnectionIdGenerator::default()
+        .generate()
+        .unwrap()
+        .len(),
+      RandomConnectionIdGenerator::DEFAULT_LEN
+    );
+    assert!(RandomConnectionIdGenerator::new(MAX_CID_LEN + 1).is_err());
+  }
+
+  #[test]
+  fn random_generator_is_unpredictable() {
+    let generator = RandomConnectionIdGenerator::default();
+    assert_ne!(generator.generate().unwrap(), generator.generate().unwrap());
+  }
+
+  #[test]
+  fn custom_generator_carries_shard() {
+    let generator = ShardGenerator {
+      shard: 7,
+      random: RandomConnectionIdGenerator::default(),
+    };
+    let cid = generator.generate().unwrap();
+    assert_eq!(cid.len(), generator.cid_len());
+    assert_eq!(cid.as_bytes()[0], 7);
+  }
+}
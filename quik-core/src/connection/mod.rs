@@ -4,11 +4,16 @@ use quik_util::*;
 
 use crate::wire::ConnectionId;
 
+mod id;
+
+pub use id::*;
+
 pub trait Connection {
   fn dropped(&self) -> impl Future<Output = Result<()>>;
 }
 
 pub struct DefaultConnection {
+  #[allow(dead_code)]
   cid: ConnectionId,
 }
 
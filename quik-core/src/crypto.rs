@@ -2,6 +2,24 @@ use quik_util::*;
 
 use crate::wire::ConnectionId;
 
+// All AEADs used by QUIC v1 have a 16 byte authentication tag
+pub const AEAD_TAG_LEN: usize = 16;
+
+// Header protection samples this many bytes of the protected payload
+pub const HP_SAMPLE_LEN: usize = 16;
+
+// https://datatracker.ietf.org/doc/html/rfc9001#name-encryption-levels
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum EncryptionLevel {
+  Initial,
+  ZeroRtt,
+  Handshake,
+  OneRtt,
+}
+
+// `is_server` is whether the packet was sent by the server, which decides
+// whether the client or server keys are used. Initial keys are derived from
+// `cid` and `version`.
 pub trait Crypto {
   fn decrypt_initial_data(
     &self,
@@ -10,4 +28,22 @@ pub trait Crypto {
     is_server: bool,
     data: &mut impl Buffer,
   ) -> impl Future<Output = Result<Vec<u8>>>;
+
+  // Mask over the first byte and the packet number of a packet, computed from
+  // a sample of its protected payload
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-header-protection
+  fn header_protection_mask(
+    &self,
+    level: EncryptionLevel,
+    cid: &ConnectionId,
+    version: u32,
+    is_server: bool,
+    sample: &[u8; HP_SAMPLE_LEN],
+  ) -> Result<[u8; 5]>;
+
+  // Tag of a Retry packet with the fixed key and nonce of `version`, over the
+  // pseudo-packet made of the original Destination Connection ID and the Retry
+  // packet without its tag
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-retry-packet-integrity
+  fn retry_integrity_tag(&self, version: u32, pseudo_packet: &[u8]) -> Result<[u8; AEAD_TAG_LEN]>;
 }
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use quik_util::*;
+
+use crate::wire::frame::Datagram;
+
+// Transport parameter advertising support for DATAGRAM frames, along with the
+// largest DATAGRAM frame the endpoint accepts
+// https://datatracker.ietf.org/doc/html/rfc9221#name-transport-parameter
+pub const MAX_DATAGRAM_FRAME_SIZE: u64 = 0x20;
+
+pub const DEFAULT_RECV_QUEUE_LEN: usize = 64;
+
+// Unreliable datagrams, which can only be sent once the peer sent
+// max_datagram_frame_size, and only received if we sent it
+// https://datatracker.ietf.org/doc/html/rfc9221
+pub struct Datagrams {
+  local_max_frame_size: Option<u64>,
+  peer_max_frame_size: Option<u64>,
+  recv_queue: VecDeque<Vec<u8>>,
+  recv_queue_len: usize,
+}
+
+impl Default for Datagrams {
+  fn default() -> Self {
+    Self {
+      local_max_frame_size: None,
+      peer_max_frame_size: None,
+      recv_queue: VecDeque::new(),
+      recv_queue_len: DEFAULT_RECV_QUEUE_LEN,
+    }
+  }
+}
+
+impl Datagrams {
+  // The max_datagram_frame_size we send, None to not support datagrams
+  pub fn local_max_frame_size(&self) -> Option<u64> {
+    self.local_max_frame_size
+  }
+
+  pub fn set_local_max_frame_size(&mut self, max_frame_size: Option<u64>) {
+    self.local_max_frame_size = max_frame_size;
+  }
+
+  // The max_datagram_frame_size the peer sent, if any
+  pub fn set_peer_max_frame_size(&mut self, max_frame_size: Option<u64>) {
+    self.peer_max_frame_size = max_frame_size;
+  }
+
+  // Received datagrams beyond this many are dropped, oldest first
+  pub fn set_recv_queue_len(&mut self, len: usize) {
+    self.recv_queue_len = len;
+    while self.recv_queue.len() > len {
+      self.recv_queue.pop_front();
+    }
+  }
+
+  // Largest datagram the peer accepts, None if it doesn't support datagrams
+  pub fn max_size(&self) -> Option<usize> {
+    let max_frame_size = usize::try_from(self.peer_max_frame_size?).unwrap_or(usize::MAX);
+    // Frame type and the length prefix, which is shorter for shorter data
+    let max_size = [(1, 0x3f), (2, 0x3fff), (4, 0x3fff_ffff), (8, usize::MAX)]
+      .into_iter()
+      .map(|(len_prefix_len, max_len)| {
+        max_frame_size
+          .saturating_sub(1 + len_prefix_len)
+          .min(max_len)
+      })
+      .max();
+    Some(max_size.unwrap_or(0))
+  }
+
+  pub fn check_send(&self, data: &[u8]) -> Result<()> {
+    match self.max_size() {
+      None => Err("Peer doesn't support datagrams")?,
+      Some(max_size) if data.len() > max_size => Err("Datagram too large for the peer")?,
+      Some(_) => Ok(()),
+    }
+  }
+
+  // Receiving datagrams we didn't ask for, or that are larger than we allow,
+  // is a PROTOCOL_VIOLATION
+  // https://datatracker.ietf.org/doc/html/rfc9221#section-3-4
+  pub fn check_recv(local_max_frame_size: Option<u64>, datagram: &Datagram) -> Result<()> {
+    let Some(max_frame_size) = local_max_frame_size else {
+      Err(Error::transport(
+        TransportErrorCode::ProtocolViolation,
+        "Datagrams weren't negotiated",
+      ))?
+    };
+    // The smallest encoding of the frame, as the peer may have left out the
+    // length
+    if 1 + datagram.data.len() as u64 > max_frame_size {
+      Err(Error::transport(
+        TransportErrorCode::ProtocolViolation,
+        "Datagram larger than max_datagram_frame_size",
+      ))?;
+    }
+    Ok(())
+  }
+
+  pub fn push(&mut self, data: Vec<u8>) {
+    if self.recv_queue_len == 0 {
+      return;
+    }
+    if self.recv_queue.len() == self.recv_queue_len {
+      self.recv_queue.pop_front();
+    }
+    self.recv_queue.push_back(data);
+  }
+
+  pub fn recv(&mut self) -> Option<Vec<u8>> {
+    self.recv_queue.pop_front()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wire::Frame;
+
+  #[test]
+  fn max_size_accounts_for_frame_overhead() {
+    let mut datagrams = Datagrams::default();
+    assert_eq!(datagrams.max_size(), None);
+
+    for max_frame_size in [0, 1, 2, 3, 65, 66, 1200, 16386, 16387, 16388] {
+      datagrams.set_peer_max_frame_size(Some(max_frame_size));
+      let max_size = datagrams.max_size().unwrap();
+      let data = vec![0; max_size];
+      let frame = Frame::Datagram(Datagram { data: &data });
+      if max_frame_size >= 2 {
+        assert!(frame.encoded_len() as u64 <= max_frame_size);
+      }
+      let data = vec![0; max_size + 1];
+      let frame = Frame::Datagram(Datagram { data: &data });
+      assert!(frame.encoded_len() as u64 > max_frame_size);
+    }
+
+    datagrams.set_peer_max_frame_size(Some(u64::MAX));
+    assert!(datagrams.max_size().unwrap() > 0);
+  }
+
+  #[test]
+  fn send_needs_peer_support() {
+    let mut datagrams = Datagrams::default();
+    assert!(datagrams.check_send(b"hi").is_err());
+    datagrams.set_peer_max_frame_size(Some(4));
+    assert!(datagrams.check_send(b"hi").is_ok());
+    assert!(datagrams.check_send(b"hi!").is_err());
+  }
+
+  #[test]
+  fn recv_needs_local_support() {
+    let datagram = Datagram { data: b"hello" };
+    let err = Datagrams::check_recv(None, &datagram).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+
+    assert!(Datagrams::check_recv(Some(6), &datagram).is_ok());
+    let err = Datagrams::check_recv(Some(5), &datagram).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+  }
+
+  #[test]
+  fn recv_queue_is_bounded() {
+    let mut datagrams = Datagrams::default();
+    datagrams.set_recv_queue_len(2);
+    for i in 0..3 {
+      datagrams.push(vec![i]);
+    }
+    assert_eq!(datagrams.recv(), Some(vec![1]));
+    assert_eq!(datagrams.recv(), Some(vec![2]));
+    assert_eq!(datagrams.recv(), None);
+
+    datagrams.push(vec![3]);
+    datagrams.push(vec![4]);
+    datagrams.set_recv_queue_len(1);
+    assert_eq!(datagrams.recv(), Some(vec![4]));
+
+    datagrams.set_recv_queue_len(0);
+    datagrams.push(vec![5]);
+    assert_eq!(datagrams.recv(), None);
+  }
+}
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use quik_util::*;
+
+use crate::crypto::Crypto;
+use crate::handler::Handler;
+use crate::reset::{ResetTokenGenerator, StatelessResets};
+use crate::transport::{Connection, Io};
+use crate::wire::packet::first_dst_cid;
+
+// Routes the datagrams of a socket to the connections they belong to. Short
+// header packets that belong to none of them are from a peer whose connection
+// we lost the state of, which is told so with a stateless reset.
+// https://datatracker.ietf.org/doc/html/rfc9000#name-stateless-reset
+pub struct Endpoint<C: Crypto, I: Io, H: Handler, G: ResetTokenGenerator> {
+  io: I,
+  local_cid_len: usize,
+  connections: Mutex<Vec<Arc<Connection<C, I, H>>>>,
+  resets: Mutex<StatelessResets<G>>,
+}
+
+impl<C: Crypto, I: Io, H: Handler, G: ResetTokenGenerator> Endpoint<C, I, H, G> {
+  pub fn new(io: I, local_cid_len: usize, resets: StatelessResets<G>) -> Self {
+    Self {
+      io,
+      local_cid_len,
+      connections: Mutex::new(Vec::new()),
+      resets: Mutex::new(resets),
+    }
+  }
+
+  pub async fn add(&self, connection: Arc<Connection<C, I, H>>) {
+    self.connections.lock().await.push(connection);
+  }
+
+  // Packets of a removed connection get stateless resets from then on
+  pub async fn remove(&self, connection: &Arc<Connection<C, I, H>>) {
+    let mut connections = self.connections.lock().await;
+    connections.retain(|known| !Arc::ptr_eq(known, connection));
+  }
+
+  // Returns whether a connection took the datagram. Long header packets that
+  // belong to none are the first of a new connection, which is up to the
+  // caller to set up and add.
+  pub async fn recv(&self, datagram: &[u8]) -> Result<bool> {
+    let Ok(dst_cid) = first_dst_cid(datagram, self.local_cid_len) else {
+      return Ok(false);
+    };
+    let mut owner = None;
+    for connection in self.connections.lock().await.iter() {
+      if connection.has_local_cid(dst_cid).await {
+        owner = Some(connection.clone());
+        break;
+      }
+    }
+    if let Some(connection) = owner {
+      connection.recv(datagram).await?;
+      return Ok(true);
+    }
+
+    let mut resets = self.resets.lock().await;
+    if let Some(reset) = resets.reset(datagram, self.local_cid_len, Instant::now())? {
+      drop(resets);
+      self.io.send(&reset).await?;
+    }
+    Ok(false)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::reset::PeerResetTokens;
+  use crate::transport::tests::*;
+  use crate::wire::frame::{NewConnectionId, RetireConnectionId};
+  use crate::wire::packet::{Initial, OneRtt};
+  use crate::wire::{ConnectionId, Frame, Packet, Side, VERSION_1};
+
+  // Not a secure derivation, every Connection ID gets the same token
+  struct FixedToken(u128);
+
+  impl ResetTokenGenerator for FixedToken {
+    fn token(&self, _cid: &ConnectionId) -> Result<u128> {
+      Ok(self.0)
+    }
+  }
+
+  fn one_rtt(dst_cid: &[u8], packet_number: u32) -> Packet<'static> {
+    Packet::OneRtt(OneRtt {
+      dst_cid: cid(dst_cid),
+      spin: 0,
+      key_phase: 0,
+      packet_number: packet_number.into(),
+    })
+  }
+
+  #[tokio::test]
+  async fn unknown_cids_get_stateless_resets() {
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = Arc::new(connection(Side::Server, &io, &handler));
+    let endpoint = Endpoint::new(&io, 2, StatelessResets::new(FixedToken(0x1234)));
+    endpoint.add(server.clone()).await;
+    let initial = Packet::Initial(Initial {
+      src_cid: cid(&[0x5e, 0x5e]),
+      dst_cid: cid(&[0x0c]),
+      version: VERSION_1,
+      token: &[],
+      packet_number: 0u32.into(),
+    });
+    server
+      .send(initial, std::iter::once(Frame::Ping))
+      .await
+      .unwrap();
+    io.last_sent();
+
+    let datagram = client_datagram(one_rtt(&[0x5e, 0x5e], 0), vec![Frame::Ping]).await;
+    assert!(endpoint.recv(&datagram).await.unwrap());
+    assert_eq!(handler.frame_types.lock().unwrap()[0], 0x01);
+    assert!(io.sent.lock().unwrap().is_empty());
+
+    let unknown = client_datagram(one_rtt(&[0x0a, 0x0a], 1), vec![Frame::Ping]).await;
+    assert!(!endpoint.recv(&unknown).await.unwrap());
+    let reset = io.last_sent();
+    assert!(reset.len() < unknown.len());
+    let mut tokens = PeerResetTokens::default();
+    tokens.insert(0, 0x1234);
+    assert!(tokens.matches(&reset));
+
+    // Issuing the Connection ID makes it the server's, until the client
+    // retires it
+    let new_cid = Frame::NewConnectionId(NewConnectionId {
+      seq_num: 1u32.into(),
+      retire_prior_to: 0u32.into(),
+      cid: cid(&[0x0a, 0x0a]),
+      stateless_reset_token: 0x1234,
+    });
+    server
+      .send(one_rtt(&[0x0c, 0x0c], 0), std::iter::once(new_cid))
+      .await
+      .unwrap();
+    io.last_sent();
+    assert!(endpoint.recv(&unknown).await.unwrap());
+    assert!(io.sent.lock().unwrap().is_empty());
+
+    let retire = Frame::RetireConnectionId(RetireConnectionId {
+      seq_num: 1u32.into(),
+    });
+    let datagram = client_datagram(one_rtt(&[0x5e, 0x5e], 2), vec![retire]).await;
+    assert!(endpoint.recv(&datagram).await.unwrap());
+    assert!(!endpoint.recv(&unknown).await.unwrap());
+    assert!(tokens.matches(&io.last_sent()));
+  }
+
+  #[tokio::test]
+  async fn removed_connections_get_stateless_resets() {
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let client = Arc::new(connection(Side::Client, &io, &handler));
+    let endpoint = Endpoint::new(&io, 2, StatelessResets::new(FixedToken(0x1234)));
+    endpoint.add(client.clone()).await;
+    let initial = Packet::Initial(Initial {
+      src_cid: cid(&[0x0c, 0x0c]),
+      dst_cid: cid(&[0x5e, 0x5e]),
+      version: VERSION_1,
+      token: &[],
+      packet_number: 0u32.into(),
+    });
+    client
+      .send(initial, std::iter::once(Frame::Ping))
+      .await
+      .unwrap();
+    io.last_sent();
+
+    // Garbage addressed to a live connection is only dropped by it
+    let mut garbage = vec![0x00, 0x0c, 0x0c];
+    garbage.resize(50, 0xff);
+    assert!(endpoint.recv(&garbage).await.unwrap());
+    assert!(io.sent.lock().unwrap().is_empty());
+
+    endpoint.remove(&client).await;
+    assert!(!endpoint.recv(&garbage).await.unwrap());
+    assert!(io.last_sent().len() < garbage.len());
+  }
+}
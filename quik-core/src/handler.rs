@@ -8,4 +8,11 @@ pub trait Handler {
     packet: Packet<'a>,
     frames: impl Iterator<Item = Result<Frame<'a>>>,
   ) -> impl Future<Output = Result<()>>;
+
+  // Packets that can't be parsed or decrypted are dropped without closing the
+  // connection, and end up here with the packet type, field and offset that
+  // failed, if known
+  fn dropped(&self, _err: Error) -> impl Future<Output = ()> {
+    async {}
+  }
 }
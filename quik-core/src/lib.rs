@@ -1,10 +1,13 @@
-#![feature(slice_as_chunks)]
-
+pub mod ack;
 pub mod connection;
 pub mod crypto;
+pub mod datagram;
+pub mod endpoint;
 pub mod handler;
 pub mod provider;
+pub mod reset;
 pub mod server;
 pub mod stream;
 pub mod transport;
+pub mod version;
 pub mod wire;
@@ -18,25 +18,20 @@ pub trait Provider {
   ) -> impl Future<Output = Result<Self::StreamRx>>;
 }
 
+type StreamCallback = Box<
+  dyn Fn(
+    &mut <DefaultProvider as Provider>::Connection,
+    &<DefaultProvider as Provider>::StreamRx,
+  ) -> Result<()>,
+>;
+
 pub struct DefaultProvider {
-  cb: Box<
-    dyn Fn(
-      &mut <DefaultProvider as Provider>::Connection,
-      &<DefaultProvider as Provider>::StreamRx,
-    ) -> Result<()>,
-  >,
+  cb: StreamCallback,
 }
 
 impl DefaultProvider {
-  pub fn new(
-    cb: Box<
-      dyn Fn(
-        &mut <DefaultProvider as Provider>::Connection,
-        &<DefaultProvider as Provider>::StreamRx,
-      ) -> Result<()>,
-    >,
-  ) -> Self {
-    Self { cb: Box::new(cb) }
+  pub fn new(cb: StreamCallback) -> Self {
+    Self { cb }
   }
 }
 
@@ -64,6 +59,7 @@ mod tests {
   use std::sync::Arc;
 
   use super::*;
+  use crate::wire::{Side, StreamDir};
 
   #[tokio::test]
   async fn provider_creation() -> Result<()> {
@@ -77,13 +73,13 @@ mod tests {
       });
       Ok(())
     }));
-    
+
     let conn = &mut DefaultConnection::new(ConnectionId::parse(&mut (&[1, 0x12][..]))?);
-    let sid = StreamId::parse(&mut (&[0x12][..]))?;
+    let sid = StreamId::new(Side::Client, StreamDir::Unidirectional, 4)?;
     provider.create_stream(conn, sid).await?;
-    
+
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-    assert_eq!(*(_called.lock().await), true);
+    assert!(*(_called.lock().await));
     Ok(())
   }
 }
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use quik_util::*;
+use rand::RngCore;
+
+use crate::wire::ConnectionId;
+
+pub const RESET_TOKEN_LEN: usize = 16;
+
+// A stateless reset has to pass for a short header packet with at least 5
+// unpredictable bytes before the token
+// https://datatracker.ietf.org/doc/html/rfc9000#section-10.3-7
+const MIN_RESET_LEN: usize = 5 + RESET_TOKEN_LEN;
+// Resets are made as large as allowed to look like other packets, up to the
+// smallest datagram size every path supports
+const MAX_RESET_LEN: usize = 1200;
+
+pub const DEFAULT_MAX_RESETS: u32 = 100;
+pub const DEFAULT_RESET_INTERVAL: Duration = Duration::from_secs(1);
+
+// Derives the stateless reset token of a Connection ID from a static key, so
+// that an endpoint that lost all state can still produce it. Tokens must not
+// be predictable without the key.
+// https://datatracker.ietf.org/doc/html/rfc9000#name-calculating-a-stateless-res
+pub trait ResetTokenGenerator: Send + Sync {
+  fn token(&self, cid: &ConnectionId) -> Result<u128>;
+}
+
+// Compares without returning early, so that timing doesn't reveal how much of
+// a token was guessed right
+fn ct_eq(a: u128, b: u128) -> bool {
+  let diff = (a ^ b).to_ne_bytes().iter().fold(0, |acc, byte| acc | byte);
+  std::hint::black_box(diff) == 0
+}
+
+// Tokens of the Connection IDs the peer issued to us, which the peer can end
+// the connection with once it lost its state
+// https://datatracker.ietf.org/doc/html/rfc9000#name-detecting-a-stateless-reset
+#[derive(Debug, Default)]
+pub struct PeerResetTokens {
+  // Keyed by the sequence number of the Connection ID
+  tokens: BTreeMap<u64, u128>,
+}
+
+impl PeerResetTokens {
+  pub fn insert(&mut self, seq_num: u64, token: u128) {
+    self.tokens.insert(seq_num, token);
+  }
+
+  // Forgets the tokens of retired Connection IDs
+  pub fn retire_prior_to(&mut self, seq_num: u64) {
+    self.tokens = self.tokens.split_off(&seq_num);
+  }
+
+  // Whether `datagram` ends in one of the tokens, checking all of them
+  // regardless of which one matched
+  pub fn matches(&self, datagram: &[u8]) -> bool {
+    if datagram.len() < MIN_RESET_LEN {
+      return false;
+    }
+    let Some(tail) = datagram.last_chunk::<RESET_TOKEN_LEN>() else {
+      return false;
+    };
+    let tail = u128::from_be_bytes(*tail);
+    self
+      .tokens
+      .values()
+      .fold(false, |found, token| found | ct_eq(*token, tail))
+  }
+}
+
+// Sends stateless resets for packets of connections this endpoint has no
+// state for, at most `max_resets` every `interval`
+// https://datatracker.ietf.org/doc/html/rfc9000#name-stateless-reset
+pub struct StatelessResets<G: ResetTokenGenerator> {
+  generator: G,
+  max_resets: u32,
+  interval: Duration,
+  window_start: Option<Instant>,
+  sent: u32,
+}
+
+impl<G: ResetTokenGenerator> StatelessResets<G> {
+  pub fn new(generator: G) -> Self {
+    Self {
+      generator,
+      max_resets: DEFAULT_MAX_RESETS,
+      interval: DEFAULT_RESET_INTERVAL,
+      window_start: None,
+      sent: 0,
+    }
+  }
+
+  pub fn set_rate_limit(&mut self, max_resets: u32, interval: Duration) {
+    self.max_resets = max_resets;
+    self.interval = interval;
+  }
+
+  // Token to send in NEW_CONNECTION_ID frames and transport parameters
+  pub fn token(&self, cid: &ConnectionId) -> Result<u128> {
+    self.generator.token(cid)
+  }
+
+  fn allow(&mut self, now: Instant) -> bool {
+    match self.window_start {
+      Some(start) if now.duration_since(start) < self.interval => {}
+      _ => {
+        self.window_start = Some(now);
+        self.sent = 0;
+      }
+    }
+    if self.sent >= self.max_resets {
+      return false;
+    }
+    self.sent += 1;
+    true
+  }
+
+  // Stateless reset answering `datagram`, None if it shouldn't be answered.
+  // Only short header packets get one. The reset is always smaller than the
+  // packet that triggered it rather than padded up to its size: two endpoints
+  // that both lost their state would otherwise keep resetting each other, and
+  // shrinking every time is what ends that loop.
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-looping
+  pub fn reset(
+    &mut self,
+    datagram: &[u8],
+    local_cid_len: usize,
+    now: Instant,
+  ) -> Result<Option<Vec<u8>>> {
+    let Some(&first_byte) = datagram.first() else {
+      return Ok(None);
+    };
+    let len = datagram.len().saturating_sub(1).min(MAX_RESET_LEN);
+    if first_byte >> 7 != 0 || len < MIN_RESET_LEN {
+      return Ok(None);
+    }
+    let mut data = &datagram[1..];
+    let dst_cid = ConnectionId::parse_with_len(&mut data, local_cid_len)?;
+    let token = self.generator.token(&dst_cid)?;
+    if !self.allow(now) {
+      return Ok(None);
+    }
+
+    // Header Form (1) = 0, Fixed Bit (1) = 1, the rest is unpredictable
+    let mut reset = vec![0; len];
+    let (random, tail) = reset.split_at_mut(len - RESET_TOKEN_LEN);
+    rand::thread_rng().fill_bytes(random);
+    random[0] = 0b0100_0000 | (random[0] & 0b0011_1111);
+    tail.copy_from_slice(&token.to_be_bytes());
+    Ok(Some(reset))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Not a secure derivation, but enough to tell Connection IDs apart
+  struct XorGenerator(u128);
+
+  impl ResetTokenGenerator for XorGenerator {
+    fn token(&self, cid: &ConnectionId) -> Result<u128> {
+      let mut buf = [0; RESET_TOKEN_LEN];
+      buf[..cid.len()].copy_from_slice(cid.as_bytes());
+      Ok(self.0 ^ u128::from_be_bytes(buf))
+    }
+  }
+
+  fn short_packet(dst_cid: &[u8], len: usize) -> Vec<u8> {
+    let mut packet = vec![0x41];
+    packet.extend_from_slice(dst_cid);
+    packet.resize(len, 0xaa);
+    packet
+  }
+
+  #[test]
+  fn reset_is_smaller_than_trigger() {
+    let mut resets = StatelessResets::new(XorGenerator(0x1234));
+    let now = Instant::now();
+    for len in [MIN_RESET_LEN + 1, 43, 100, 1500] {
+      let packet = short_packet(&[0x01, 0x02], len);
+      let reset = resets.reset(&packet, 2, now).unwrap().unwrap();
+      assert!(reset.len() < packet.len());
+      assert!(reset.len() >= MIN_RESET_LEN);
+      assert_eq!(reset[0] & 0b1100_0000, 0b0100_0000);
+
+      let token = resets.token(&ConnectionId::new(&[0x01, 0x02]).unwrap());
+      let mut tokens = PeerResetTokens::default();
+      tokens.insert(0, token.unwrap());
+      assert!(tokens.matches(&reset));
+    }
+
+    let too_small = short_packet(&[0x01, 0x02], MIN_RESET_LEN);
+    assert_eq!(resets.reset(&too_small, 2, now).unwrap(), None);
+  }
+
+  #[test]
+  fn reset_only_for_short_headers() {
+    let mut resets = StatelessResets::new(XorGenerator(0x1234));
+    let mut packet = short_packet(&[0x01], 100);
+    packet[0] = 0xc0;
+    assert_eq!(resets.reset(&packet, 1, Instant::now()).unwrap(), None);
+  }
+
+  #[test]
+  fn resets_are_rate_limited() {
+    let mut resets = StatelessResets::new(XorGenerator(0x1234));
+    resets.set_rate_limit(2, Duration::from_secs(1));
+    let packet = short_packet(&[0x01], 100);
+    let start = Instant::now();
+    let mut sent = |now| resets.reset(&packet, 1, now).unwrap().is_some();
+    assert!(sent(start));
+    assert!(sent(start));
+    assert!(!sent(start + Duration::from_millis(999)));
+    assert!(sent(start + Duration::from_secs(1)));
+  }
+
+  #[test]
+  fn peer_tokens_match_tail() {
+    let mut tokens = PeerResetTokens::default();
+    let token = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeffu128;
+    tokens.insert(3, token);
+
+    let mut datagram = vec![0x40; 30];
+    datagram[14..].copy_from_slice(&token.to_be_bytes());
+    assert!(tokens.matches(&datagram));
+    assert!(!tokens.matches(&datagram[..datagram.len() - 1]));
+    assert!(tokens.matches(&datagram[9..]));
+    // Too short to be a stateless reset
+    assert!(!tokens.matches(&datagram[10..]));
+
+    tokens.retire_prior_to(3);
+    assert!(tokens.matches(&datagram));
+    tokens.retire_prior_to(4);
+    assert!(!tokens.matches(&datagram));
+  }
+}
@@ -1,5 +1,3 @@
-use quik_util::*;
-
 use crate::crypto::Crypto;
 use crate::handler::Handler;
 use crate::transport::Io;
@@ -54,6 +54,7 @@ impl StreamRx for DefaultStreamRx {
 }
 
 impl DefaultStreamRx {
+  #[allow(dead_code)]
   async fn read_async(&self, buf: &mut [u8]) -> std::io::Result<usize> {
     let mut inner = self.inner.lock().await;
     if inner.eof {
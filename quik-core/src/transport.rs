@@ -1,11 +1,30 @@
-use std::iter::Empty;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
 
 use quik_util::*;
 
-use crate::crypto::Crypto;
+use crate::ack::{micros, AckPolicy};
+use crate::crypto::{Crypto, EncryptionLevel, AEAD_TAG_LEN};
+use crate::datagram::Datagrams;
 use crate::handler::Handler;
-use crate::wire::packet::RemainingBuf;
-use crate::wire::{Frame, Packet};
+use crate::reset::PeerResetTokens;
+use crate::version::Versions;
+use crate::wire::frame::{
+  AckFrequency, ConnectionClose, Datagram, NewConnectionId, RetireConnectionId,
+};
+use crate::wire::packet::{
+  Coalesced, Handshake, Initial, PacketContext, RemainingBuf, VersionNegotiation, ZeroRTT,
+};
+use crate::wire::{
+  ConnectionId, ExtensionRegistry, Frame, Packet, PacketNumber, PacketNumberSpaces,
+  TransportParameters, VarInt,
+};
+
+// Smallest payload (excluding the AEAD tag) that leaves room for the header
+// protection sample regardless of the packet number length
+const MIN_PAYLOAD_LEN: usize = 4;
 
 pub trait Io {
   fn send(&self, data: &[u8]) -> impl Future<Output = Result<()>>;
@@ -13,34 +32,963 @@ pub trait Io {
   fn close(self) -> impl Future<Output = ()>;
 }
 
+// What the frames of a received packet change about the connection, applied
+// once the handler has seen them
+#[derive(Default)]
+struct FrameEffects {
+  // Lets packet numbers we send be truncated
+  largest_acked: Option<PacketNumber>,
+  ack_eliciting: bool,
+  datagrams: Vec<Vec<u8>>,
+  ack_frequencies: Vec<AckFrequency>,
+  immediate_ack: bool,
+  new_cids: Vec<NewConnectionId>,
+  retired_cids: Vec<u64>,
+}
+
+// CONNECTION_CLOSE of a connection closed because of an error, which is all it
+// sends from then on
+// https://datatracker.ietf.org/doc/html/rfc9000#name-immediate-close
+struct Closing {
+  err_code: VarInt,
+  frame_type: Option<VarInt>,
+  reason_phrase: Vec<u8>,
+}
+
+impl From<&Error> for Closing {
+  fn from(err: &Error) -> Self {
+    let close = ConnectionClose::from(err);
+    Self {
+      err_code: close.err_code,
+      frame_type: close.frame_type,
+      reason_phrase: close.reason_phrase.to_vec(),
+    }
+  }
+}
+
+impl Closing {
+  fn frame(&self) -> Frame<'_> {
+    Frame::ConnectionClose(ConnectionClose {
+      err_code: self.err_code,
+      frame_type: self.frame_type,
+      reason_phrase: &self.reason_phrase,
+    })
+  }
+}
+
+#[derive(Default)]
+struct PacketNumbers {
+  largest_received: PacketNumberSpaces<Option<PacketNumber>>,
+  largest_acked: PacketNumberSpaces<Option<PacketNumber>>,
+}
+
 pub struct Connection<C: Crypto, I: Io, H: Handler> {
   crypto: C,
   io: I,
   handler: H,
+  ctx: PacketContext,
+  packet_numbers: Mutex<PacketNumbers>,
+  extensions: Mutex<ExtensionRegistry>,
+  datagrams: Mutex<Datagrams>,
+  ack_policy: Mutex<AckPolicy>,
+  peer_grease_quic_bit: AtomicBool,
+  peer_reset_tokens: Mutex<PeerResetTokens>,
+  // Connection IDs we issued that the peer may still send to, keyed by
+  // sequence number
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-issuing-connection-ids
+  local_cids: Mutex<BTreeMap<u64, ConnectionId>>,
+  versions: Mutex<Versions>,
+  // Version in use, which a server may switch to one compatible with the
+  // client's first Initial
+  version: AtomicU32,
+  after_version_negotiation: AtomicBool,
+  // Version a client starts over with after a Version Negotiation, 0 if none
+  // as that is never a version packets are protected with
+  next_version: AtomicU32,
+  draining: AtomicBool,
+  closing: Mutex<Option<Closing>>,
 }
 
 impl<C: Crypto, I: Io, H: Handler> Connection<C, I, H> {
-  pub fn send<'a>(&self, packet: Packet, frames: impl Iterator<Item = Frame<'a>>) {
-    // Send data using the underlying UDP transport
+  pub fn new(crypto: C, io: I, handler: H, ctx: PacketContext) -> Self {
+    Self {
+      crypto,
+      io,
+      handler,
+      packet_numbers: Mutex::new(PacketNumbers::default()),
+      extensions: Mutex::new(ExtensionRegistry::default()),
+      datagrams: Mutex::new(Datagrams::default()),
+      ack_policy: Mutex::new(AckPolicy::default()),
+      peer_grease_quic_bit: AtomicBool::new(false),
+      peer_reset_tokens: Mutex::new(PeerResetTokens::default()),
+      local_cids: Mutex::new(BTreeMap::new()),
+      versions: Mutex::new(Versions::default()),
+      version: AtomicU32::new(ctx.version),
+      after_version_negotiation: AtomicBool::new(false),
+      next_version: AtomicU32::new(0),
+      draining: AtomicBool::new(false),
+      closing: Mutex::new(None),
+      ctx,
+    }
+  }
+
+  // Extensions are registered up front, and enabled once the peer's transport
+  // parameters are known
+  pub fn extensions(&self) -> &Mutex<ExtensionRegistry> {
+    &self.extensions
+  }
+
+  // Datagram support is configured up front, and usable once the peer's
+  // transport parameters are known
+  pub fn datagrams(&self) -> &Mutex<Datagrams> {
+    &self.datagrams
+  }
+
+  // Supported versions are configured up front, and checked against the
+  // peer's version information once its transport parameters are known
+  pub fn versions(&self) -> &Mutex<Versions> {
+    &self.versions
+  }
+
+  pub fn version(&self) -> u32 {
+    self.version.load(Ordering::Relaxed)
   }
 
-  pub async fn recv(&self, data: &[u8]) -> Result<()> {
-    let (packet, remainder) = Packet::parse(&self.crypto, data).await?;
-    match remainder {
-      RemainingBuf::Decrypted(data) => {
-        self
-          .handler
-          .handle(packet, Frame::parse_multiple(&data))
-          .await?;
+  // A client that started over after a Version Negotiation makes sure the
+  // server's version information shows it wasn't forged to force a downgrade
+  pub fn set_after_version_negotiation(&self, after_version_negotiation: bool) {
+    self
+      .after_version_negotiation
+      .store(after_version_negotiation, Ordering::Relaxed);
+  }
+
+  // Version a client should start a new connection attempt with, once a
+  // Version Negotiation made it give up on this one
+  pub fn next_version(&self) -> Option<u32> {
+    let version = self.next_version.load(Ordering::Relaxed);
+    (version != 0).then_some(version)
+  }
+
+  pub async fn max_datagram_size(&self) -> Option<usize> {
+    self.datagrams.lock().await.max_size()
+  }
+
+  pub async fn send_datagram(&self, packet: Packet<'_>, data: &[u8]) -> Result<()> {
+    self.datagrams.lock().await.check_send(data)?;
+    let frame = Frame::Datagram(Datagram { data });
+    self.send(packet, std::iter::once(frame)).await
+  }
+
+  // Oldest datagram received that hasn't been read yet
+  pub async fn recv_datagram(&self) -> Option<Vec<u8>> {
+    self.datagrams.lock().await.recv()
+  }
+
+  // Adds the transport parameters of the extensions configured on this
+  // connection to the ones we send
+  pub async fn fill_transport_parameters(&self, params: &mut TransportParameters) -> Result<()> {
+    let max_frame_size = self.datagrams.lock().await.local_max_frame_size();
+    params.max_datagram_frame_size = max_frame_size.map(VarInt::try_from).transpose()?;
+    let min_ack_delay = self.ack_policy.lock().await.local_min_ack_delay();
+    params.min_ack_delay = min_ack_delay.map(micros).transpose()?;
+    params.grease_quic_bit = self.ctx.grease_quic_bit;
+    let information = self.versions.lock().await.information(self.version());
+    params.version_information = Some(information);
+    for id in self.extensions.lock().await.transport_parameters() {
+      let id = VarInt::try_from(id)?;
+      if !params.unknown.iter().any(|(known, _)| *known == id) {
+        params.unknown.push((id, Vec::new()));
       }
-      RemainingBuf::None => {
-        self.handler.handle(packet, Empty::default()).await?;
+    }
+    Ok(())
+  }
+
+  // Enables the extensions the peer's transport parameters allow us to use.
+  // A server switches to the compatible version both endpoints prefer here, so
+  // it should fill in its own transport parameters afterwards.
+  pub async fn set_peer_transport_parameters(&self, params: &TransportParameters) -> Result<()> {
+    let peer_information = params.version_information.as_ref();
+    let versions = self.versions.lock().await;
+    if self.ctx.is_server {
+      let version = versions.choose_compatible(self.ctx.version, peer_information)?;
+      self.version.store(version, Ordering::Relaxed);
+    } else {
+      let after_version_negotiation = self.after_version_negotiation.load(Ordering::Relaxed);
+      versions.validate_server(self.version(), peer_information, after_version_negotiation)?;
+    }
+    drop(versions);
+
+    let max_frame_size = params.max_datagram_frame_size.map(VarInt::into_inner);
+    self
+      .datagrams
+      .lock()
+      .await
+      .set_peer_max_frame_size(max_frame_size);
+    let min_ack_delay = params
+      .min_ack_delay
+      .map(|delay| Duration::from_micros(delay.into_inner()));
+    self
+      .ack_policy
+      .lock()
+      .await
+      .set_peer_min_ack_delay(min_ack_delay);
+    self.set_peer_grease_quic_bit(params.grease_quic_bit);
+    // The server's token belongs to the Connection ID of its Initial packets,
+    // which always has sequence number 0
+    if let Some(token) = params.stateless_reset_token {
+      self.peer_reset_tokens.lock().await.insert(0, token);
+    }
+    let ids = params.unknown.iter().map(|(id, _)| id.into_inner());
+    self.extensions.lock().await.negotiate(ids);
+    Ok(())
+  }
+
+  // Once the peer sent grease_quic_bit, the fixed bit of the packets we send
+  // is randomized
+  pub fn set_peer_grease_quic_bit(&self, grease_quic_bit: bool) {
+    self
+      .peer_grease_quic_bit
+      .store(grease_quic_bit, Ordering::Relaxed);
+  }
+
+  // Whether the peer reset the connection or a Version Negotiation ended the
+  // connection attempt, after which nothing is sent and received packets are
+  // dropped
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-draining-state
+  pub fn is_draining(&self) -> bool {
+    self.draining.load(Ordering::Relaxed)
+  }
+
+  // Whether the connection was closed because of an error, after which
+  // received packets are dropped and only CONNECTION_CLOSE can be sent
+  pub async fn is_closing(&self) -> bool {
+    self.closing.lock().await.is_some()
+  }
+
+  // Closes the connection because of `err`, unless it's already closing
+  async fn close_with(&self, err: &Error) {
+    let mut closing = self.closing.lock().await;
+    closing.get_or_insert_with(|| Closing::from(err));
+  }
+
+  // Sends the CONNECTION_CLOSE of a connection closed because of an error
+  pub async fn send_connection_close(&self, packet: Packet<'_>) -> Result<()> {
+    let closing = self.closing.lock().await;
+    let Some(closing) = closing.as_ref() else {
+      Err("Connection isn't closing")?
+    };
+    self
+      .send_packet(packet, std::iter::once(closing.frame()))
+      .await
+  }
+
+  // Whether packets sent to `cid` belong to this connection: it's the one in
+  // our long headers or one we issued in NEW_CONNECTION_ID and the peer didn't
+  // retire yet. A server also gets the client's first packets, which are sent
+  // to a Connection ID the client picked.
+  pub async fn has_local_cid(&self, cid: &[u8]) -> bool {
+    let client_picked = self.ctx.initial_dst_cid.as_ref();
+    if self.ctx.is_server && client_picked.is_some_and(|picked| picked.as_bytes() == cid) {
+      return true;
+    }
+    let local_cids = self.local_cids.lock().await;
+    local_cids.values().any(|local| local.as_bytes() == cid)
+  }
+
+  // Decides when received packets should be acknowledged
+  pub fn ack_policy(&self) -> &Mutex<AckPolicy> {
+    &self.ack_policy
+  }
+
+  // Asks the peer to change how often it acknowledges our packets
+  pub async fn send_ack_frequency(
+    &self,
+    packet: Packet<'_>,
+    ack_eliciting_threshold: u64,
+    max_ack_delay: Duration,
+    reordering_threshold: u64,
+  ) -> Result<()> {
+    let frame = self.ack_policy.lock().await.request(
+      ack_eliciting_threshold,
+      max_ack_delay,
+      reordering_threshold,
+    )?;
+    let frame = Frame::AckFrequency(frame);
+    self.send(packet, std::iter::once(frame)).await
+  }
+
+  pub async fn send<'a>(
+    &self,
+    packet: Packet<'_>,
+    frames: impl Iterator<Item = Frame<'a>>,
+  ) -> Result<()> {
+    if self.is_draining() {
+      Err("Connection is draining")?;
+    }
+    if self.is_closing().await {
+      Err("Connection is closing")?;
+    }
+    self.send_packet(packet, frames).await
+  }
+
+  async fn send_packet<'a>(
+    &self,
+    packet: Packet<'_>,
+    frames: impl Iterator<Item = Frame<'a>>,
+  ) -> Result<()> {
+    let mut buf = Vec::new();
+    if let Packet::VersionNegotiation(_) | Packet::Retry(_) = packet {
+      // These carry no payload, so the header is the whole packet
+      packet.encode_header(0, None, &mut buf)?;
+      return self.io.send(&buf).await;
+    }
+
+    let mut local_cids = self.local_cids.lock().await;
+    // The Connection ID of our long headers is the one issued during the
+    // handshake, which has sequence number 0
+    if let Packet::Initial(Initial { src_cid, .. })
+    | Packet::ZeroRTT(ZeroRTT { src_cid, .. })
+    | Packet::Handshake(Handshake { src_cid, .. }) = &packet
+    {
+      local_cids.entry(0).or_insert_with(|| src_cid.clone());
+    }
+    let mut payload = Vec::new();
+    for frame in frames {
+      frame.encode(&mut payload)?;
+      if let Frame::NewConnectionId(new_cid) = &frame {
+        local_cids.insert(new_cid.seq_num.into_inner(), new_cid.cid.clone());
+      }
+    }
+    drop(local_cids);
+    // Header protection samples 16 bytes starting 4 bytes after the start of
+    // the packet number, so pad out short payloads to always have a sample
+    if payload.len() < MIN_PAYLOAD_LEN {
+      payload.resize(MIN_PAYLOAD_LEN, 0);
+    }
+
+    let largest_acked = match packet.space() {
+      Some(space) => self.packet_numbers.lock().await.largest_acked[space],
+      None => None,
+    };
+    let header = packet.encode_header(payload.len() + AEAD_TAG_LEN, largest_acked, &mut buf)?;
+    buf.extend_from_slice(&payload);
+    // TODO: encrypt the payload into the tag slot
+    buf.resize(buf.len() + AEAD_TAG_LEN, 0);
+
+    if let Some(level) = packet.encryption_level() {
+      let version = packet.version().unwrap_or(self.version());
+      let dst_cid = ConnectionId::new(packet.dst_cid())?;
+      let mask = self.crypto.header_protection_mask(
+        level,
+        self.ctx.key_cid(level, &dst_cid),
+        version,
+        self.ctx.is_server,
+        header.hp_sample(&buf)?,
+      )?;
+      header.protect(&mut buf, mask);
+    }
+    if self.peer_grease_quic_bit.load(Ordering::Relaxed) {
+      header.grease_fixed_bit(&mut buf, &mut rand::thread_rng());
+    }
+
+    // Send data using the underlying UDP transport
+    self.io.send(&buf).await
+  }
+
+  pub async fn recv(&self, datagram: &[u8]) -> Result<()> {
+    if self.is_draining() || self.is_closing().await {
+      return Ok(());
+    }
+    // Servers answer versions they don't support, anything malformed is left
+    // to be dropped with its packets
+    if self.ctx.is_server {
+      let versions = self.versions.lock().await;
+      if let Ok(Some(vn)) = versions.version_negotiation(datagram) {
+        drop(versions);
+        return self.io.send(&vn).await;
       }
     }
+    let mut dropped = Vec::new();
+    for data in Coalesced::new(datagram, &self.ctx) {
+      dropped.extend(self.recv_packet(data).await?);
+    }
+    if !dropped.is_empty() {
+      self.drop_datagram(datagram, dropped).await;
+    }
     Ok(())
   }
 
+  // A datagram that can't be processed might be a stateless reset, which the
+  // peer sends once it lost the connection's state. Otherwise the handler is
+  // told why its packets were dropped.
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-detecting-a-stateless-reset
+  async fn drop_datagram(&self, datagram: &[u8], dropped: Vec<Error>) {
+    if self.peer_reset_tokens.lock().await.matches(datagram) {
+      self.draining.store(true, Ordering::Relaxed);
+      return;
+    }
+    for err in dropped {
+      self.handler.dropped(err).await;
+    }
+  }
+
+  // Returns why the packet was dropped, None if it was processed
+  async fn recv_packet(&self, data: &[u8]) -> Result<Option<Error>> {
+    let largest_received = self.packet_numbers.lock().await.largest_received.clone();
+    // Short headers are parsed with the version the server may have switched to
+    let version = self.version();
+    let ctx = if version == self.ctx.version {
+      Cow::Borrowed(&self.ctx)
+    } else {
+      Cow::Owned(PacketContext {
+        version,
+        ..self.ctx.clone()
+      })
+    };
+    // Packets that can't be parsed or decrypted are dropped, without affecting
+    // the other packets coalesced in the same datagram
+    let (packet, remainder) = match Packet::parse(&self.crypto, data, &ctx, &largest_received).await
+    {
+      Ok(parsed) => parsed,
+      Err(err) => return Ok(Some(err)),
+    };
+    if let Packet::VersionNegotiation(vn) = &packet {
+      self.on_version_negotiation(vn, &largest_received).await?;
+      return Ok(None);
+    }
+    let numbered = packet.space().zip(packet.packet_number());
+    let long_header_version = packet.version();
+
+    // Every frame is checked before the handler sees any of them, so that a
+    // packet breaking the rules closes the connection without being acted on
+    let mut effects = FrameEffects::default();
+    let frames = match (&remainder, packet.encryption_level()) {
+      (RemainingBuf::Decrypted { payload, offset }, Some(level)) => {
+        let packet_type = packet.type_name();
+        match self.check_frames(level, payload, &mut effects).await {
+          Ok(frames) => frames,
+          Err(err) => {
+            // Blame frames that fail to parse on their place in the packet
+            let err = err.at_offset(*offset).in_packet(packet_type);
+            self.close_with(&err).await;
+            return Err(err);
+          }
+        }
+      }
+      _ => Vec::new(),
+    };
+    self
+      .handler
+      .handle(packet, frames.into_iter().map(Ok))
+      .await?;
+
+    // The server's packets carry the version it switched to, if it did
+    // https://datatracker.ietf.org/doc/html/rfc9368#name-compatible-versions
+    if let Some(version) = long_header_version.filter(|_| !self.ctx.is_server) {
+      if version != self.version() && self.versions.lock().await.is_supported(version) {
+        self.version.store(version, Ordering::Relaxed);
+      }
+    }
+
+    {
+      let mut ack_policy = self.ack_policy.lock().await;
+      for ack_frequency in &effects.ack_frequencies {
+        ack_policy.on_ack_frequency(ack_frequency);
+      }
+      if effects.immediate_ack {
+        ack_policy.on_immediate_ack();
+      }
+      if let Some((space, packet_number)) = numbered {
+        ack_policy.on_packet_received(
+          packet_number,
+          largest_received[space],
+          effects.ack_eliciting,
+        );
+      }
+    }
+
+    if let Some((space, packet_number)) = numbered {
+      let mut pns = self.packet_numbers.lock().await;
+      pns.largest_received[space] = pns.largest_received[space].max(Some(packet_number));
+      pns.largest_acked[space] = pns.largest_acked[space].max(effects.largest_acked);
+    }
+
+    {
+      let mut tokens = self.peer_reset_tokens.lock().await;
+      for NewConnectionId {
+        seq_num,
+        retire_prior_to,
+        stateless_reset_token,
+        ..
+      } in effects.new_cids
+      {
+        tokens.insert(seq_num.into_inner(), stateless_reset_token);
+        tokens.retire_prior_to(retire_prior_to.into_inner());
+      }
+    }
+
+    {
+      let mut local_cids = self.local_cids.lock().await;
+      for seq_num in effects.retired_cids {
+        local_cids.remove(&seq_num);
+      }
+    }
+
+    let mut queue = self.datagrams.lock().await;
+    for datagram in effects.datagrams {
+      queue.push(datagram);
+    }
+    Ok(None)
+  }
+
+  // A client gives up on the connection attempt once the server answers with a
+  // Version Negotiation, picking the version to start over with. It's ignored
+  // by servers, once any other packet was received, and when it lists the
+  // version the client used.
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-6.2
+  async fn on_version_negotiation(
+    &self,
+    vn: &VersionNegotiation<'_>,
+    largest_received: &PacketNumberSpaces<Option<PacketNumber>>,
+  ) -> Result<()> {
+    if self.ctx.is_server || *largest_received != PacketNumberSpaces::default() {
+      return Ok(());
+    }
+    let versions = self.versions.lock().await;
+    match versions.on_version_negotiation(vn, self.ctx.version) {
+      Ok(None) => Ok(()),
+      Ok(Some(version)) => {
+        self.next_version.store(version, Ordering::Relaxed);
+        self.draining.store(true, Ordering::Relaxed);
+        Ok(())
+      }
+      Err(err) => {
+        self.draining.store(true, Ordering::Relaxed);
+        Err(err)
+      }
+    }
+  }
+
+  // Parses the frames of a packet sent at `level`, failing on the first one
+  // that is malformed or that the peer isn't allowed to send
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-12.4
+  async fn check_frames<'a>(
+    &self,
+    level: EncryptionLevel,
+    payload: &'a [u8],
+    effects: &mut FrameEffects,
+  ) -> Result<Vec<Frame<'a>>> {
+    let sender = self.ctx.side().peer();
+    // Frames don't borrow from the registry, so it's only locked while parsing
+    let extensions = self.extensions.lock().await;
+    let datagram_limit = self.datagrams.lock().await.local_max_frame_size();
+    let min_ack_delay = self.ack_policy.lock().await.local_min_ack_delay();
+    let mut frames = Vec::new();
+    for frame in Frame::parse_multiple(payload, &extensions, &self.ctx.limits) {
+      let frame = frame?;
+      frame.check_recv(level, sender)?;
+      effects.ack_eliciting |= frame.is_ack_eliciting();
+      match &frame {
+        Frame::Ack(ack) => {
+          let acked = PacketNumber::from(ack.largest_acked);
+          effects.largest_acked = effects.largest_acked.max(Some(acked));
+        }
+        Frame::Datagram(datagram) => {
+          Datagrams::check_recv(datagram_limit, datagram)?;
+          effects.datagrams.push(datagram.data.to_vec());
+        }
+        Frame::AckFrequency(ack_frequency) => {
+          AckPolicy::check_recv(min_ack_delay, ack_frequency)?;
+          effects.ack_frequencies.push(ack_frequency.clone());
+        }
+        Frame::ImmediateAck => {
+          AckPolicy::check_recv_immediate_ack(min_ack_delay)?;
+          effects.immediate_ack = true;
+        }
+        Frame::NewConnectionId(new_cid) => effects.new_cids.push(new_cid.clone()),
+        Frame::RetireConnectionId(RetireConnectionId { seq_num }) => {
+          effects.retired_cids.push(seq_num.into_inner());
+        }
+        _ => {}
+      }
+      frames.push(frame);
+    }
+    Ok(frames)
+  }
+
   pub fn close(self) {
     // Close connection
   }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::crypto::HP_SAMPLE_LEN;
+  use crate::version::VersionInformation;
+  use crate::wire::frame::Stream;
+  use crate::wire::packet::OneRtt;
+  use crate::wire::{Side, StreamDir, StreamId, VERSION_1, VERSION_2};
+
+  // Leaves packets unprotected
+  pub(crate) struct PlaintextCrypto;
+
+  impl Crypto for PlaintextCrypto {
+    async fn decrypt_initial_data(
+      &self,
+      _cid: ConnectionId,
+      _version: u32,
+      _is_server: bool,
+      data: &mut impl Buffer,
+    ) -> Result<Vec<u8>> {
+      let mut payload = Vec::new();
+      data.read_to_end(&mut payload)?;
+      Ok(payload)
+    }
+
+    fn header_protection_mask(
+      &self,
+      _level: EncryptionLevel,
+      _cid: &ConnectionId,
+      _version: u32,
+      _is_server: bool,
+      _sample: &[u8; HP_SAMPLE_LEN],
+    ) -> Result<[u8; 5]> {
+      Ok([0; 5])
+    }
+
+    fn retry_integrity_tag(
+      &self,
+      _version: u32,
+      _pseudo_packet: &[u8],
+    ) -> Result<[u8; AEAD_TAG_LEN]> {
+      Ok([0; AEAD_TAG_LEN])
+    }
+  }
+
+  // Keeps every datagram sent
+  #[derive(Default)]
+  pub(crate) struct RecordIo {
+    pub(crate) sent: std::sync::Mutex<Vec<Vec<u8>>>,
+  }
+
+  impl RecordIo {
+    pub(crate) fn last_sent(&self) -> Vec<u8> {
+      self.sent.lock().unwrap().pop().unwrap()
+    }
+  }
+
+  impl Io for &RecordIo {
+    async fn send(&self, data: &[u8]) -> Result<()> {
+      self.sent.lock().unwrap().push(data.to_vec());
+      Ok(())
+    }
+
+    async fn recv(&self, _data: &mut [u8]) -> Result<()> {
+      Ok(())
+    }
+
+    async fn close(self) {}
+  }
+
+  // Keeps the types of the frames it was handed
+  #[derive(Default)]
+  pub(crate) struct RecordHandler {
+    pub(crate) frame_types: std::sync::Mutex<Vec<u64>>,
+    dropped: std::sync::Mutex<Vec<Error>>,
+  }
+
+  impl Handler for &RecordHandler {
+    async fn handle<'a>(
+      &self,
+      _packet: Packet<'a>,
+      frames: impl Iterator<Item = Result<Frame<'a>>>,
+    ) -> Result<()> {
+      for frame in frames {
+        let typ = frame?.frame_type().into_inner();
+        self.frame_types.lock().unwrap().push(typ);
+      }
+      Ok(())
+    }
+
+    async fn dropped(&self, err: Error) {
+      self.dropped.lock().unwrap().push(err);
+    }
+  }
+
+  pub(crate) type TestConnection<'t> = Connection<PlaintextCrypto, &'t RecordIo, &'t RecordHandler>;
+
+  fn ctx(side: Side) -> PacketContext {
+    PacketContext {
+      is_server: side == Side::Server,
+      local_cid_len: 2,
+      version: VERSION_1,
+      ..Default::default()
+    }
+  }
+
+  pub(crate) fn connection<'t>(
+    side: Side,
+    io: &'t RecordIo,
+    handler: &'t RecordHandler,
+  ) -> TestConnection<'t> {
+    Connection::new(PlaintextCrypto, io, handler, ctx(side))
+  }
+
+  pub(crate) fn cid(bytes: &[u8]) -> ConnectionId {
+    ConnectionId::new(bytes).unwrap()
+  }
+
+  // Initial to the other endpoint, whose Connection ID is `dst_cid`
+  fn initial(dst_cid: &[u8], packet_number: u32) -> Packet<'static> {
+    Packet::Initial(Initial {
+      src_cid: cid(&[0x0c]),
+      dst_cid: cid(dst_cid),
+      version: VERSION_1,
+      token: &[],
+      packet_number: packet_number.into(),
+    })
+  }
+
+  fn version_negotiation(versions: &[u32]) -> Vec<u8> {
+    let supported_versions: Vec<[u8; 4]> = versions
+      .iter()
+      .map(|version| version.to_be_bytes())
+      .collect();
+    let packet = Packet::VersionNegotiation(VersionNegotiation {
+      src_cid: &[0x5e, 0x5e],
+      dst_cid: &[0x0c],
+      supported_versions: &supported_versions,
+    });
+    let mut buf = Vec::new();
+    packet.encode_header(0, None, &mut buf).unwrap();
+    buf
+  }
+
+  // Datagram a client sends with `frames`, which it doesn't check
+  pub(crate) async fn client_datagram(packet: Packet<'_>, frames: Vec<Frame<'_>>) -> Vec<u8> {
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let client = connection(Side::Client, &io, &handler);
+    client.send(packet, frames.into_iter()).await.unwrap();
+    io.last_sent()
+  }
+
+  #[tokio::test]
+  async fn allowed_frames_reach_handler() {
+    let datagram = client_datagram(initial(&[0x5e, 0x5e], 0), vec![Frame::Ping]).await;
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = connection(Side::Server, &io, &handler);
+    server.recv(&datagram).await.unwrap();
+    // The rest is padding, and the tag that isn't removed without decryption
+    let frame_types = handler.frame_types.lock().unwrap().clone();
+    assert_eq!(frame_types[0], 0x01);
+    assert!(frame_types[1..].iter().all(|&typ| typ == 0x00));
+    assert!(!server.is_closing().await);
+  }
+
+  #[tokio::test]
+  async fn forbidden_frame_closes_connection() {
+    let stream = Frame::Stream(Stream {
+      stream_id: StreamId::new(Side::Client, StreamDir::Bidirectional, 0).unwrap(),
+      offset: 0,
+      has_len: true,
+      fin: false,
+      data: b"early",
+    });
+    let datagram = client_datagram(initial(&[0x5e, 0x5e], 0), vec![Frame::Ping, stream]).await;
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = connection(Side::Server, &io, &handler);
+    let err = server.recv(&datagram).await.unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert_eq!(err.context().unwrap().packet_type, Some("Initial"));
+    // Not even the frames before the violation are handled
+    assert!(handler.frame_types.lock().unwrap().is_empty());
+    assert!(server.is_closing().await);
+
+    // Everything after is dropped, and only CONNECTION_CLOSE is sent
+    let ping = client_datagram(initial(&[0x5e, 0x5e], 1), vec![Frame::Ping]).await;
+    server.recv(&ping).await.unwrap();
+    assert!(handler.frame_types.lock().unwrap().is_empty());
+    let frames = std::iter::once(Frame::Ping);
+    assert!(server.send(initial(&[0x0c], 0), frames).await.is_err());
+
+    server
+      .send_connection_close(initial(&[0x0c], 0))
+      .await
+      .unwrap();
+    let sent = io.last_sent();
+    let (_, remaining) = Packet::parse(
+      &PlaintextCrypto,
+      &sent,
+      &ctx(Side::Client),
+      &PacketNumberSpaces::default(),
+    )
+    .await
+    .unwrap();
+    let RemainingBuf::Decrypted { payload, .. } = remaining else {
+      panic!("Initial without payload");
+    };
+    let Frame::ConnectionClose(close) = Frame::parse(&payload).unwrap().0 else {
+      panic!("Not a CONNECTION_CLOSE frame");
+    };
+    let code = TransportErrorCode::ProtocolViolation.code();
+    assert_eq!(close.err_code, VarInt::try_from(code).unwrap());
+    // STREAM with only the Length bit set
+    assert_eq!(close.frame_type, Some(VarInt::from(0x0au32)));
+  }
+
+  #[tokio::test]
+  async fn immediate_ack_needs_min_ack_delay() {
+    let packet = Packet::OneRtt(OneRtt {
+      dst_cid: cid(&[0x5e, 0x5e]),
+      spin: 0,
+      key_phase: 0,
+      packet_number: 0u32.into(),
+    });
+    let datagram = client_datagram(packet, vec![Frame::ImmediateAck]).await;
+
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = connection(Side::Server, &io, &handler);
+    let err = server.recv(&datagram).await.unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert!(server.is_closing().await);
+
+    let server = connection(Side::Server, &io, &handler);
+    let min_ack_delay = Some(Duration::from_millis(1));
+    let mut ack_policy = server.ack_policy().lock().await;
+    ack_policy.set_local_min_ack_delay(min_ack_delay);
+    ack_policy.set_ack_eliciting_threshold(10);
+    drop(ack_policy);
+    server.recv(&datagram).await.unwrap();
+    assert!(server.ack_policy().lock().await.should_ack_now());
+  }
+
+  #[tokio::test]
+  async fn server_answers_unknown_versions() {
+    let mut datagram = vec![0xc0];
+    datagram.extend_from_slice(&0x1a2a_3a4au32.to_be_bytes());
+    datagram.extend_from_slice(&[0x02, 0x5e, 0x5e, 0x01, 0x0c]);
+    datagram.resize(1200, 0);
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = connection(Side::Server, &io, &handler);
+    server.recv(&datagram).await.unwrap();
+
+    let sent = io.last_sent();
+    let (packet, _) = Packet::parse(
+      &PlaintextCrypto,
+      &sent,
+      &ctx(Side::Client),
+      &PacketNumberSpaces::default(),
+    )
+    .await
+    .unwrap();
+    let Packet::VersionNegotiation(vn) = packet else {
+      panic!("Not a Version Negotiation packet");
+    };
+    assert_eq!(vn.dst_cid, [0x0c]);
+    assert_eq!(
+      vn.versions().take(2).collect::<Vec<_>>(),
+      [VERSION_1, VERSION_2]
+    );
+    assert!(handler.frame_types.lock().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn client_gives_up_after_version_negotiation() {
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let client = connection(Side::Client, &io, &handler);
+    // Lists the version the client used, so it's ignored
+    let vn = version_negotiation(&[VERSION_2, VERSION_1]);
+    client.recv(&vn).await.unwrap();
+    assert!(!client.is_draining());
+
+    client
+      .recv(&version_negotiation(&[VERSION_2]))
+      .await
+      .unwrap();
+    assert!(client.is_draining());
+    assert_eq!(client.next_version(), Some(VERSION_2));
+
+    let client = connection(Side::Client, &io, &handler);
+    let err = client
+      .recv(&version_negotiation(&[0xff00_001d]))
+      .await
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::VersionNegotiationError);
+    assert!(client.is_draining());
+    assert_eq!(client.next_version(), None);
+  }
+
+  #[tokio::test]
+  async fn server_switches_to_compatible_version() {
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = connection(Side::Server, &io, &handler);
+    *server.versions().lock().await = Versions::new(vec![VERSION_2, VERSION_1]).unwrap();
+    let client_params = TransportParameters {
+      version_information: Some(VersionInformation {
+        chosen_version: VERSION_1,
+        available_versions: vec![VERSION_1, VERSION_2],
+      }),
+      ..Default::default()
+    };
+    server
+      .set_peer_transport_parameters(&client_params)
+      .await
+      .unwrap();
+    assert_eq!(server.version(), VERSION_2);
+    let mut server_params = TransportParameters::default();
+    server
+      .fill_transport_parameters(&mut server_params)
+      .await
+      .unwrap();
+    let information = server_params.version_information.as_ref().unwrap();
+    assert_eq!(information.chosen_version, VERSION_2);
+
+    // The client only accepts the switch once the server's packets show it
+    let client = connection(Side::Client, &io, &handler);
+    let err = client
+      .set_peer_transport_parameters(&server_params)
+      .await
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::VersionNegotiationError);
+
+    let client = connection(Side::Client, &io, &handler);
+    let packet = Packet::Initial(Initial {
+      src_cid: cid(&[0x5e, 0x5e]),
+      dst_cid: cid(&[0x0c, 0x0c]),
+      version: VERSION_2,
+      token: &[],
+      packet_number: 0u32.into(),
+    });
+    server
+      .send(packet, std::iter::once(Frame::Ping))
+      .await
+      .unwrap();
+    client.recv(&io.last_sent()).await.unwrap();
+    assert_eq!(client.version(), VERSION_2);
+    client
+      .set_peer_transport_parameters(&server_params)
+      .await
+      .unwrap();
+  }
+
+  #[tokio::test]
+  async fn dropped_packets_reach_handler() {
+    // Retry with an empty token, which the server can't parse either way
+    let mut datagram = vec![0xf0];
+    datagram.extend_from_slice(&VERSION_1.to_be_bytes());
+    datagram.extend_from_slice(&[0x02, 0x5e, 0x5e, 0x01, 0x0c]);
+    datagram.extend_from_slice(&[0; AEAD_TAG_LEN]);
+    let (io, handler) = (RecordIo::default(), RecordHandler::default());
+    let server = connection(Side::Server, &io, &handler);
+    server.recv(&datagram).await.unwrap();
+    assert!(!server.is_closing().await);
+
+    let dropped = handler.dropped.lock().unwrap();
+    let context = dropped[0].context().unwrap();
+    assert_eq!(context.packet_type, Some("Retry"));
+    assert_eq!(context.field, Some("Retry Token"));
+    assert_eq!(context.offset, Some(datagram.len() - AEAD_TAG_LEN));
+    assert_eq!(dropped.len(), 1);
+  }
+}
@@ -0,0 +1,390 @@
+use quik_util::*;
+
+use crate::wire::packet::VersionNegotiation;
+use crate::wire::{Packet, VERSION_1, VERSION_2};
+
+// Transport parameter carrying the version the sender chose and the versions
+// it would have been willing to use
+// https://datatracker.ietf.org/doc/html/rfc9368#name-version-information
+pub const VERSION_INFORMATION: u64 = 0x11;
+
+// Datagrams with a client's first Initial are at least this large, and
+// smaller ones with an unknown version aren't worth a Version Negotiation
+// https://datatracker.ietf.org/doc/html/rfc9000#section-14.1
+const MIN_INITIAL_DATAGRAM_LEN: usize = 1200;
+
+// Versions that quik implements
+fn is_known(version: u32) -> bool {
+  matches!(version, VERSION_1 | VERSION_2)
+}
+
+// Versions whose first flights can be converted into each other, so that a
+// server can switch between them during the handshake
+// https://datatracker.ietf.org/doc/html/rfc9369#name-compatible-negotiation-requ
+fn is_compatible(original_version: u32, negotiated_version: u32) -> bool {
+  is_known(original_version) && is_known(negotiated_version)
+}
+
+fn version_negotiation_error(reason: &'static str) -> Error {
+  Error::transport(TransportErrorCode::VersionNegotiationError, reason)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInformation {
+  pub chosen_version: u32,
+  pub available_versions: Vec<u32>,
+}
+
+impl VersionInformation {
+  // Parses the transport parameter value, neither field can hold version 0
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    let error = |reason| Error::transport(TransportErrorCode::TransportParameterError, reason);
+    let (versions, remainder) = data.as_chunks::<4>();
+    if !remainder.is_empty() {
+      Err(error("Version information has a partial version"))?;
+    }
+    let mut versions = versions.iter().map(|version| u32::from_be_bytes(*version));
+    let chosen_version = versions
+      .next()
+      .ok_or_else(|| error("Version information without a chosen version"))?;
+    let available_versions: Vec<u32> = versions.collect();
+    if chosen_version == 0 || available_versions.contains(&0) {
+      Err(error("Version information with version 0"))?;
+    }
+    Ok(Self {
+      chosen_version,
+      available_versions,
+    })
+  }
+
+  pub fn encoded_len(&self) -> usize {
+    4 * (1 + self.available_versions.len())
+  }
+
+  pub fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    dst.write_u32::<NetworkEndian>(self.chosen_version)?;
+    for version in &self.available_versions {
+      dst.write_u32::<NetworkEndian>(*version)?;
+    }
+    Ok(())
+  }
+}
+
+// Versions an endpoint supports, in order of preference
+// https://datatracker.ietf.org/doc/html/rfc9000#name-version-negotiation
+// https://datatracker.ietf.org/doc/html/rfc9368
+#[derive(Debug, Clone)]
+pub struct Versions {
+  supported: Vec<u32>,
+}
+
+impl Default for Versions {
+  fn default() -> Self {
+    Self {
+      supported: vec![VERSION_1, VERSION_2],
+    }
+  }
+}
+
+impl Versions {
+  pub fn new(supported: Vec<u32>) -> Result<Self> {
+    if supported.is_empty() {
+      Err("No supported versions")?;
+    }
+    if !supported.iter().all(|version| is_known(*version)) {
+      Err("Unsupported version")?;
+    }
+    Ok(Self { supported })
+  }
+
+  pub fn supported(&self) -> &[u32] {
+    &self.supported
+  }
+
+  pub fn is_supported(&self, version: u32) -> bool {
+    self.supported.contains(&version)
+  }
+
+  // Version Negotiation packet answering a datagram whose first packet has a
+  // version we don't support, None if it shouldn't be answered. A reserved
+  // version is listed as well, so that clients don't come to rely on the list.
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-6.1
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-6.3
+  pub fn version_negotiation(&self, datagram: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut data = datagram;
+    let first_byte = data.read_u8()?;
+    // Short headers don't have a version, and Version Negotiation packets
+    // aren't answered
+    if first_byte >> 7 == 0 {
+      return Ok(None);
+    }
+    let version = data.read_u32::<NetworkEndian>()?;
+    if version == 0 || self.is_supported(version) || datagram.len() < MIN_INITIAL_DATAGRAM_LEN {
+      return Ok(None);
+    }
+    // Connection IDs of any version can be up to 255 bytes long
+    // https://datatracker.ietf.org/doc/html/rfc8999#name-long-header
+    let dst_cid_len = data.read_u8()?;
+    let dst_cid = data.slice(dst_cid_len.into())?;
+    let src_cid_len = data.read_u8()?;
+    let src_cid = data.slice(src_cid_len.into())?;
+
+    let reserved_version = rand::random::<u32>() & 0xf0f0_f0f0 | 0x0a0a_0a0a;
+    let supported_versions: Vec<[u8; 4]> = self
+      .supported
+      .iter()
+      .chain([&reserved_version])
+      .map(|version| version.to_be_bytes())
+      .collect();
+    let packet = Packet::VersionNegotiation(VersionNegotiation {
+      src_cid: dst_cid,
+      dst_cid: src_cid,
+      supported_versions: &supported_versions,
+    });
+    let mut buf = Vec::new();
+    packet.encode_header(0, None, &mut buf)?;
+    Ok(Some(buf))
+  }
+
+  // Version a client retries with after a Version Negotiation packet, None if
+  // the packet should be ignored as it lists the version the client used
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-6.2
+  pub fn on_version_negotiation(
+    &self,
+    vn: &VersionNegotiation,
+    original_version: u32,
+  ) -> Result<Option<u32>> {
+    if vn.versions().any(|version| version == original_version) {
+      return Ok(None);
+    }
+    let version = self
+      .supported
+      .iter()
+      .find(|version| vn.versions().any(|offered| offered == **version))
+      .ok_or_else(|| version_negotiation_error("No version in common with the server"))?;
+    Ok(Some(*version))
+  }
+
+  // Version information sent by either endpoint for the version in use
+  pub fn information(&self, chosen_version: u32) -> VersionInformation {
+    VersionInformation {
+      chosen_version,
+      available_versions: self.supported.clone(),
+    }
+  }
+
+  // Version the server switches to, given the version of the client's first
+  // Initial and the client's version information
+  // https://datatracker.ietf.org/doc/html/rfc9368#name-compatible-versions
+  pub fn choose_compatible(
+    &self,
+    original_version: u32,
+    client: Option<&VersionInformation>,
+  ) -> Result<u32> {
+    let Some(client) = client else {
+      return Ok(original_version);
+    };
+    if client.chosen_version != original_version {
+      Err(version_negotiation_error(
+        "Client chose a different version",
+      ))?;
+    }
+    let negotiated_version = self
+      .supported
+      .iter()
+      .copied()
+      .find(|version| {
+        client.available_versions.contains(version) && is_compatible(original_version, *version)
+      })
+      .unwrap_or(original_version);
+    Ok(negotiated_version)
+  }
+
+  // Checks the server's version information on the client, which prevents an
+  // attacker from forcing a downgrade with a forged Version Negotiation
+  // https://datatracker.ietf.org/doc/html/rfc9368#name-version-downgrade-preventio
+  pub fn validate_server(
+    &self,
+    negotiated_version: u32,
+    server: Option<&VersionInformation>,
+    after_version_negotiation: bool,
+  ) -> Result<()> {
+    let Some(server) = server else {
+      if after_version_negotiation {
+        Err(version_negotiation_error("Missing version information"))?;
+      }
+      return Ok(());
+    };
+    if server.chosen_version != negotiated_version {
+      Err(version_negotiation_error(
+        "Server chose a different version",
+      ))?;
+    }
+    if after_version_negotiation {
+      let expected = self
+        .supported
+        .iter()
+        .find(|version| server.available_versions.contains(version));
+      if expected != Some(&negotiated_version) {
+        Err(version_negotiation_error("Version was downgraded"))?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn info(chosen_version: u32, available_versions: &[u32]) -> VersionInformation {
+    VersionInformation {
+      chosen_version,
+      available_versions: available_versions.to_vec(),
+    }
+  }
+
+  fn initial_datagram(version: u32) -> Vec<u8> {
+    let mut datagram = vec![0xc0];
+    datagram.extend_from_slice(&version.to_be_bytes());
+    datagram.extend_from_slice(&[0x02, 0xaa, 0xbb, 0x01, 0xcc]);
+    datagram.resize(MIN_INITIAL_DATAGRAM_LEN, 0);
+    datagram
+  }
+
+  #[test]
+  fn version_information_round_trips() {
+    let info = info(VERSION_2, &[VERSION_2, VERSION_1]);
+    let mut buf = Vec::new();
+    info.encode(&mut buf).unwrap();
+    assert_eq!(buf.len(), info.encoded_len());
+    assert_eq!(
+      buf,
+      [0x6b, 0x33, 0x43, 0xcf, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x00, 0x00, 0x01]
+    );
+    assert_eq!(VersionInformation::parse(&buf).unwrap(), info);
+  }
+
+  #[test]
+  fn version_information_invalid_fails() {
+    for buf in [
+      &[][..],
+      &[0, 0, 0, 1, 0],
+      &[0, 0, 0, 0],
+      &[0, 0, 0, 1, 0, 0, 0, 0],
+    ] {
+      let err = VersionInformation::parse(buf).unwrap_err();
+      assert_eq!(err.code(), TransportErrorCode::TransportParameterError);
+    }
+  }
+
+  #[test]
+  fn versions_must_be_known() {
+    assert!(Versions::new(vec![]).is_err());
+    assert!(Versions::new(vec![0xff00_001d]).is_err());
+    assert!(Versions::new(vec![VERSION_2]).is_ok());
+  }
+
+  #[test]
+  fn server_answers_unknown_versions() {
+    let versions = Versions::default();
+    assert_eq!(
+      versions.version_negotiation(&initial_datagram(1)).unwrap(),
+      None
+    );
+    let short = &initial_datagram(0x1a2a_3a4a)[..MIN_INITIAL_DATAGRAM_LEN - 1];
+    assert_eq!(versions.version_negotiation(short).unwrap(), None);
+
+    let vn = versions
+      .version_negotiation(&initial_datagram(0x1a2a_3a4a))
+      .unwrap()
+      .unwrap();
+    assert_eq!(versions.version_negotiation(&vn).unwrap(), None);
+
+    // Connection IDs are swapped, and the versions follow
+    assert_eq!(vn[..10], [0xc0, 0, 0, 0, 0, 0x01, 0xcc, 0x02, 0xaa, 0xbb]);
+    let (offered, _) = vn[10..].as_chunks::<4>();
+    let offered: Vec<u32> = offered.iter().map(|v| u32::from_be_bytes(*v)).collect();
+    assert_eq!(offered[..2], [VERSION_1, VERSION_2]);
+    assert_eq!(offered[2] & 0x0f0f_0f0f, 0x0a0a_0a0a);
+    assert_eq!(offered.len(), 3);
+  }
+
+  #[test]
+  fn client_picks_a_version_or_aborts() {
+    let versions = Versions::new(vec![VERSION_2, VERSION_1]).unwrap();
+    let offer = |offered: &[u32]| {
+      let supported_versions: Vec<[u8; 4]> = offered
+        .iter()
+        .map(|version| version.to_be_bytes())
+        .collect();
+      let vn = VersionNegotiation {
+        src_cid: &[],
+        dst_cid: &[],
+        supported_versions: &supported_versions,
+      };
+      versions.on_version_negotiation(&vn, 0x1a2a_3a4a)
+    };
+
+    assert_eq!(offer(&[VERSION_1]).unwrap(), Some(VERSION_1));
+    assert_eq!(offer(&[VERSION_1, VERSION_2]).unwrap(), Some(VERSION_2));
+    assert_eq!(offer(&[0x1a2a_3a4a, VERSION_1]).unwrap(), None);
+    let err = offer(&[0xff00_001d]).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::VersionNegotiationError);
+  }
+
+  #[test]
+  fn server_upgrades_compatible_versions() {
+    let server = Versions::new(vec![VERSION_2, VERSION_1]).unwrap();
+    let client = info(VERSION_1, &[VERSION_1, VERSION_2]);
+    assert_eq!(
+      server.choose_compatible(VERSION_1, Some(&client)).unwrap(),
+      VERSION_2
+    );
+    assert_eq!(
+      server.choose_compatible(VERSION_1, None).unwrap(),
+      VERSION_1
+    );
+
+    let client = info(VERSION_1, &[VERSION_1]);
+    assert_eq!(
+      server.choose_compatible(VERSION_1, Some(&client)).unwrap(),
+      VERSION_1
+    );
+
+    let err = server
+      .choose_compatible(VERSION_2, Some(&client))
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::VersionNegotiationError);
+  }
+
+  #[test]
+  fn client_detects_downgrades() {
+    let client = Versions::new(vec![VERSION_2, VERSION_1]).unwrap();
+    let server = info(VERSION_2, &[VERSION_2, VERSION_1]);
+    assert!(client
+      .validate_server(VERSION_2, Some(&server), false)
+      .is_ok());
+    assert!(client
+      .validate_server(VERSION_2, Some(&server), true)
+      .is_ok());
+    assert!(client.validate_server(VERSION_1, None, false).is_ok());
+
+    let err = client
+      .validate_server(VERSION_1, Some(&server), false)
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::VersionNegotiationError);
+    assert!(client.validate_server(VERSION_1, None, true).is_err());
+
+    // A forged Version Negotiation made the client pick v1, while the server
+    // would have accepted v2
+    let server = info(VERSION_1, &[VERSION_1, VERSION_2]);
+    assert!(client
+      .validate_server(VERSION_1, Some(&server), false)
+      .is_ok());
+    let err = client
+      .validate_server(VERSION_1, Some(&server), true)
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::VersionNegotiationError);
+  }
+}
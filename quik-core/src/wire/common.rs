@@ -1,31 +1,260 @@
-use std::io;
+use std::hash::{Hash, Hasher};
+use std::ops::{Index, IndexMut};
+use std::{fmt, io};
 
 use quik_util::*;
+use rand::RngCore;
 
-pub struct PacketNumber;
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum PacketNumberSpace {
+  Initial,
+  Handshake,
+  ApplicationData,
+}
+
+// Some state kept separately for each packet number space
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketNumberSpaces<T>([T; 3]);
+
+impl<T> Index<PacketNumberSpace> for PacketNumberSpaces<T> {
+  type Output = T;
+
+  fn index(&self, space: PacketNumberSpace) -> &T {
+    &self.0[space as usize]
+  }
+}
+
+impl<T> IndexMut<PacketNumberSpace> for PacketNumberSpaces<T> {
+  fn index_mut(&mut self, space: PacketNumberSpace) -> &mut T {
+    &mut self.0[space as usize]
+  }
+}
+
+// Full 62-bit packet number. Only the least significant 1-4 bytes are sent on
+// the wire, the rest is recovered from the largest packet number seen so far.
+// https://datatracker.ietf.org/doc/html/rfc9000#name-packet-number-encoding-and-
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PacketNumber {
+  inner: u64,
+}
+
+impl From<VarInt> for PacketNumber {
+  fn from(val: VarInt) -> Self {
+    Self {
+      inner: val.into_inner(),
+    }
+  }
+}
+
+impl From<u32> for PacketNumber {
+  fn from(val: u32) -> Self {
+    Self { inner: val as u64 }
+  }
+}
+
+impl From<PacketNumber> for u64 {
+  fn from(val: PacketNumber) -> Self {
+    val.inner
+  }
+}
+
+impl TryFrom<u64> for PacketNumber {
+  type Error = VarIntOutOfRange;
+
+  fn try_from(val: u64) -> std::result::Result<Self, Self::Error> {
+    VarInt::try_from(val).map(PacketNumber::from)
+  }
+}
+
+impl fmt::Display for PacketNumber {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self.inner.fmt(f)
+  }
+}
 
 impl PacketNumber {
-  // Assumes length has correct bounds, or panics
-  pub fn parse(src: &mut impl Buffer, len: usize) -> Result<u32> {
+  pub const ZERO: PacketNumber = PacketNumber { inner: 0 };
+  pub const MAX: PacketNumber = PacketNumber {
+    inner: VarInt::MAX.into_inner(),
+  };
+
+  pub const fn into_inner(self) -> u64 {
+    self.inner
+  }
+
+  // Reads a truncated packet number of `len` bytes and recovers the full
+  // packet number. Assumes length has correct bounds, or panics
+  pub fn parse(
+    src: &mut impl Buffer,
+    len: usize,
+    largest_received: Option<PacketNumber>,
+  ) -> Result<Self> {
     let mut buf = [0u8; 4];
-    src.read_exact(&mut buf[..len])?;
-    Ok(NetworkEndian::read_u32(&buf))
+    src.read_exact(&mut buf[4 - len..])?;
+    let truncated = NetworkEndian::read_u32(&buf);
+    Ok(Self::decode(truncated, len, largest_received))
+  }
+
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-sample-packet-number-decodi
+  pub fn decode(truncated: u32, len: usize, largest_received: Option<PacketNumber>) -> Self {
+    let expected = largest_received.map_or(0, |pn| pn.inner + 1);
+    let win = 1u64 << (len * 8);
+    let hwin = win / 2;
+    let mask = win - 1;
+
+    // The candidate is the value closest to the next expected packet number
+    // with the same least significant bits as the truncated packet number
+    let candidate = (expected & !mask) | truncated as u64;
+    let inner = if candidate + hwin <= expected && candidate < (1 << 62) - win {
+      candidate + win
+    } else if candidate > expected + hwin && candidate >= win {
+      candidate - win
+    } else {
+      candidate
+    };
+    Self { inner }
+  }
+
+  // Number of bytes needed so that the peer can recover this packet number,
+  // given the largest packet number it has acknowledged.
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-sample-packet-number-encodi
+  pub fn encoded_len(self, largest_acked: Option<PacketNumber>) -> usize {
+    let num_unacked = match largest_acked {
+      Some(largest_acked) => self.inner.saturating_sub(largest_acked.inner),
+      None => self.inner + 1,
+    };
+    // ceil(log2(num_unacked)) + 1, so the window is at least twice as large
+    let min_bits = (u64::BITS - num_unacked.saturating_sub(1).leading_zeros()) as usize + 1;
+    // More than 2^31 packets in flight cannot be represented unambiguously,
+    // but a sender never gets anywhere close to that
+    min_bits.div_ceil(8).min(4)
+  }
+
+  // Writes the least significant `len` bytes. Assumes length has correct
+  // bounds, or panics
+  pub fn encode(self, len: usize, dst: &mut impl WriteBytesExt) -> Result<()> {
+    dst.write_all(&self.inner.to_be_bytes()[8 - len..])?;
+    Ok(())
   }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+// 62 bits max, variable length
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VarInt {
   inner: u64,
 }
 
-impl From<VarInt> for usize {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VarIntOutOfRange;
+
+impl fmt::Display for VarIntOutOfRange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "value out of range for a VarInt")
+  }
+}
+
+impl std::error::Error for VarIntOutOfRange {}
+
+impl From<VarIntOutOfRange> for Error {
+  fn from(err: VarIntOutOfRange) -> Self {
+    Error::Other(err.to_string().into())
+  }
+}
+
+impl From<VarInt> for u64 {
   fn from(val: VarInt) -> Self {
-    val.inner as usize
+    val.inner
+  }
+}
+
+impl TryFrom<VarInt> for usize {
+  type Error = std::num::TryFromIntError;
+
+  fn try_from(val: VarInt) -> std::result::Result<Self, Self::Error> {
+    usize::try_from(val.inner)
+  }
+}
+
+impl From<u32> for VarInt {
+  fn from(val: u32) -> Self {
+    Self::from_u32(val)
+  }
+}
+
+impl TryFrom<u64> for VarInt {
+  type Error = VarIntOutOfRange;
+
+  fn try_from(val: u64) -> std::result::Result<Self, Self::Error> {
+    if val > VarInt::MAX.inner {
+      return Err(VarIntOutOfRange);
+    }
+    Ok(Self { inner: val })
+  }
+}
+
+impl TryFrom<usize> for VarInt {
+  type Error = VarIntOutOfRange;
+
+  fn try_from(val: usize) -> std::result::Result<Self, Self::Error> {
+    u64::try_from(val)
+      .map_err(|_| VarIntOutOfRange)
+      .and_then(VarInt::try_from)
+  }
+}
+
+impl fmt::Display for VarInt {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self.inner.fmt(f)
   }
 }
 
 impl VarInt {
   pub const ZERO: VarInt = VarInt { inner: 0 };
+  pub const MAX: VarInt = VarInt {
+    inner: (1 << 62) - 1,
+  };
+
+  pub const fn from_u32(val: u32) -> Self {
+    Self { inner: val as u64 }
+  }
+
+  pub const fn into_inner(self) -> u64 {
+    self.inner
+  }
+
+  pub fn checked_add(self, rhs: VarInt) -> Option<VarInt> {
+    self
+      .inner
+      .checked_add(rhs.inner)
+      .and_then(|inner| VarInt::try_from(inner).ok())
+  }
+
+  pub fn checked_sub(self, rhs: VarInt) -> Option<VarInt> {
+    self
+      .inner
+      .checked_sub(rhs.inner)
+      .map(|inner| Self { inner })
+  }
+
+  // Number of bytes needed for the minimal encoding of this value
+  pub fn encoded_len(&self) -> usize {
+    match self.inner {
+      0..=0x3f => 1,
+      0x40..=0x3fff => 2,
+      0x4000..=0x3fff_ffff => 4,
+      _ => 8,
+    }
+  }
+
+  pub fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    match self.encoded_len() {
+      1 => dst.write_u8(self.inner as u8)?,
+      2 => dst.write_u16::<NetworkEndian>(0b01 << 14 | self.inner as u16)?,
+      4 => dst.write_u32::<NetworkEndian>(0b10 << 30 | self.inner as u32)?,
+      _ => dst.write_u64::<NetworkEndian>(0b11 << 62 | self.inner)?,
+    }
+    Ok(())
+  }
 
   pub fn parse(src: &mut impl Buffer) -> Result<Self> {
     let mut buf = [0u8; 8];
@@ -46,7 +275,7 @@ impl VarInt {
         }
         0b11 => {
           src.read_exact(&mut buf[1..8])?;
-          NetworkEndian::read_u64(&buf) as u64
+          NetworkEndian::read_u64(&buf)
         }
         _ => unreachable!(),
       },
@@ -54,25 +283,271 @@ impl VarInt {
   }
 }
 
-pub type StreamId = VarInt;
+// Endpoint that initiated a stream or connection
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Side {
+  Client,
+  Server,
+}
+
+impl Side {
+  pub const fn peer(self) -> Side {
+    match self {
+      Side::Client => Side::Server,
+      Side::Server => Side::Client,
+    }
+  }
+}
+
+// Stream limits are kept separately for each direction
+// https://datatracker.ietf.org/doc/html/rfc9000#name-stream-types-and-identifier
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum StreamDir {
+  Bidirectional,
+  Unidirectional,
+}
+
+// The two lowest bits identify the initiator and direction, and the rest is
+// the index of the stream among those of the same type
+// https://datatracker.ietf.org/doc/html/rfc9000#name-stream-types-and-identifier
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+  inner: VarInt,
+}
+
+impl From<VarInt> for StreamId {
+  fn from(inner: VarInt) -> Self {
+    Self { inner }
+  }
+}
+
+impl From<StreamId> for VarInt {
+  fn from(id: StreamId) -> Self {
+    id.inner
+  }
+}
+
+impl From<StreamId> for u64 {
+  fn from(id: StreamId) -> Self {
+    id.inner.into_inner()
+  }
+}
+
+impl fmt::Display for StreamId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self.inner.fmt(f)
+  }
+}
+
+impl StreamId {
+  // Largest index of a stream of any type
+  pub const MAX_INDEX: u64 = (1 << 60) - 1;
+
+  pub fn new(initiator: Side, dir: StreamDir, index: u64) -> Result<Self, VarIntOutOfRange> {
+    if index > Self::MAX_INDEX {
+      return Err(VarIntOutOfRange);
+    }
+    let initiator_bit = match initiator {
+      Side::Client => 0b00,
+      Side::Server => 0b01,
+    };
+    let dir_bit = match dir {
+      StreamDir::Bidirectional => 0b00,
+      StreamDir::Unidirectional => 0b10,
+    };
+    Ok(Self {
+      inner: VarInt::try_from(index << 2 | dir_bit | initiator_bit)?,
+    })
+  }
+
+  pub const fn into_inner(self) -> VarInt {
+    self.inner
+  }
+
+  pub fn initiator(self) -> Side {
+    if self.inner.into_inner() & 0b01 == 0 {
+      Side::Client
+    } else {
+      Side::Server
+    }
+  }
+
+  pub fn dir(self) -> StreamDir {
+    if self.inner.into_inner() & 0b10 == 0 {
+      StreamDir::Bidirectional
+    } else {
+      StreamDir::Unidirectional
+    }
+  }
+
+  pub fn index(self) -> u64 {
+    self.inner.into_inner() >> 2
+  }
+
+  // Only the initiator of a stream can open it
+  pub fn can_open(self, side: Side) -> bool {
+    self.initiator() == side
+  }
+
+  // Unidirectional streams only carry data from their initiator
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-2.1-3
+  pub fn can_send(self, side: Side) -> bool {
+    self.dir() == StreamDir::Bidirectional || self.initiator() == side
+  }
+
+  pub fn can_recv(self, side: Side) -> bool {
+    self.can_send(side.peer())
+  }
+
+  pub fn encoded_len(&self) -> usize {
+    self.inner.encoded_len()
+  }
+
+  pub fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    self.inner.encode(dst)
+  }
+
+  pub fn parse(src: &mut impl Buffer) -> Result<Self> {
+    Ok(Self {
+      inner: VarInt::parse(src)?,
+    })
+  }
+}
+
+// Offset of `data` in `start`, which it has to be a part of
+pub(crate) fn offset_in(start: &[u8], data: &[u8]) -> usize {
+  data.as_ptr() as usize - start.as_ptr() as usize
+}
+
+// Parses a field with `parse`, classifying its errors as `code` and blaming
+// them on the field by name and by its offset in `start`
+pub(crate) fn parse_field<'a, T>(
+  code: TransportErrorCode,
+  start: &[u8],
+  data: &mut &'a [u8],
+  name: &'static str,
+  parse: impl FnOnce(&mut &'a [u8]) -> Result<T>,
+) -> Result<T> {
+  let offset = offset_in(start, data);
+  parse(data).map_err(|err| err.classify(code, None).in_field(name, offset))
+}
+
+// https://datatracker.ietf.org/doc/html/rfc9000#section-15
+pub const VERSION_1: u32 = 0x0000_0001;
+// https://datatracker.ietf.org/doc/html/rfc9369#name-version-field
+pub const VERSION_2: u32 = 0x6b33_43cf;
 
-// 160 bits max, variable length
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+// Longest Connection ID allowed in QUIC v1, 160 bits
+// https://datatracker.ietf.org/doc/html/rfc9000#section-17.2-3.8
+pub const MAX_CID_LEN: usize = 20;
+
+// Variable length, with only the first `length` bytes of `buf` in use
+#[derive(Clone)]
 pub struct ConnectionId {
-  pub length: usize,
-  pub buf: [u8; 20],
+  length: usize,
+  buf: [u8; MAX_CID_LEN],
+}
+
+impl PartialEq for ConnectionId {
+  fn eq(&self, other: &Self) -> bool {
+    self.as_bytes() == other.as_bytes()
+  }
+}
+
+impl Eq for ConnectionId {}
+
+impl Hash for ConnectionId {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.as_bytes().hash(state)
+  }
+}
+
+impl fmt::Display for ConnectionId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for byte in self.as_bytes() {
+      write!(f, "{byte:02x}")?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for ConnectionId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "ConnectionId({self})")
+  }
 }
 
 impl ConnectionId {
+  pub const EMPTY: ConnectionId = ConnectionId {
+    length: 0,
+    buf: [0; MAX_CID_LEN],
+  };
+
+  pub fn new(bytes: &[u8]) -> Result<Self> {
+    let mut buf = [0; MAX_CID_LEN];
+    buf
+      .get_mut(..bytes.len())
+      .ok_or("Connection ID longer than 160 bits")?
+      .copy_from_slice(bytes);
+    Ok(Self {
+      length: bytes.len(),
+      buf,
+    })
+  }
+
+  // Connection IDs must be unpredictable so that they can't be linked
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-5.1-4
+  pub fn random(length: usize) -> Result<Self> {
+    Self::random_with(&mut rand::thread_rng(), length)
+  }
+
+  pub fn random_with(rng: &mut impl RngCore, length: usize) -> Result<Self> {
+    let mut buf = [0; MAX_CID_LEN];
+    rng.fill_bytes(
+      buf
+        .get_mut(..length)
+        .ok_or("Connection ID longer than 160 bits")?,
+    );
+    Ok(Self { length, buf })
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.buf[..self.length]
+  }
+
+  pub fn len(&self) -> usize {
+    self.length
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.length == 0
+  }
+
   pub fn parse(src: &mut impl Buffer) -> Result<Self> {
     let length = src.read_u8()? as usize;
-    let mut buf = [0; 20];
+    Self::parse_with_len(src, length)
+  }
+
+  // Short headers don't encode the length of the Connection ID, the receiver
+  // has to know the length of the Connection IDs it issued
+  pub fn parse_with_len(src: &mut impl Buffer, length: usize) -> Result<Self> {
+    let mut buf = [0; MAX_CID_LEN];
     let (bufref, _) = buf
       .split_at_mut_checked(length)
       .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "length longer than 160 bits"))?;
     src.read_exact(bufref)?;
     Ok(Self { length, buf })
   }
+
+  pub fn encoded_len(&self) -> usize {
+    1 + self.length
+  }
+
+  pub fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    dst.write_u8(self.length as u8)?;
+    dst.write_all(self.as_bytes())?;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
@@ -86,17 +561,16 @@ mod tests {
     let buf = Vec::<u8>::new();
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn varint_parse_one_byte_succeeds() {
-    let buf = vec![0b0011_0101, 0x34];
+    let buf = [0b0011_0101, 0x34];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
     assert_eq!(res.ok(), Some(VarInt { inner: 0b0011_0101 }));
@@ -105,7 +579,7 @@ mod tests {
 
   #[test]
   fn varint_parse_two_byte_succeeds() {
-    let buf = vec![0b0110_0101, 0x34, 0x12];
+    let buf = [0b0110_0101, 0x34, 0x12];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
     assert_eq!(res.ok(), Some(VarInt { inner: 0x2534 }));
@@ -114,7 +588,7 @@ mod tests {
 
   #[test]
   fn varint_parse_four_byte_succeeds() {
-    let buf = vec![0b1010_0101, 0x12, 0x34, 0x56, 0x78];
+    let buf = [0b1010_0101, 0x12, 0x34, 0x56, 0x78];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
     assert_eq!(res.ok(), Some(VarInt { inner: 0x25123456 }));
@@ -123,7 +597,7 @@ mod tests {
 
   #[test]
   fn varint_parse_eight_byte_succeeds() {
-    let buf = vec![0b1110_0101, 0x12, 0x34, 0x56, 0x78, 0x90, 0x11, 0x22, 0xaa];
+    let buf = [0b1110_0101, 0x12, 0x34, 0x56, 0x78, 0x90, 0x11, 0x22, 0xaa];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
     assert_eq!(
@@ -137,137 +611,373 @@ mod tests {
 
   #[test]
   fn varint_parse_one_byte_size_fails() {
-    let buf = vec![0b0100_0000];
+    let buf = [0b0100_0000];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
 
-    let buf = vec![0b1000_0000];
+    let buf = [0b1000_0000];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
 
-    let buf = vec![0b1100_0000];
+    let buf = [0b1100_0000];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn varint_parse_two_byte_fails() {
-    let buf = vec![0b0110_0101];
+    let buf = [0b0110_0101];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn varint_parse_four_byte_fails() {
-    let buf = vec![0b1010_0101, 0x12, 0x34];
+    let buf = [0b1010_0101, 0x12, 0x34];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn varint_parse_eight_byte_fails() {
-    let buf = vec![0b1110_0101, 0x12, 0x34, 0x56, 0x78, 0x90, 0x11];
+    let buf = [0b1110_0101, 0x12, 0x34, 0x56, 0x78, 0x90, 0x11];
     let mut bufref = &buf[..];
     let res = VarInt::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
+  fn encode(v: VarInt) -> Vec<u8> {
+    let mut buf = Vec::new();
+    v.encode(&mut buf).unwrap();
+    assert_eq!(buf.len(), v.encoded_len());
+    buf
+  }
+
+  #[test]
+  fn varint_encode_is_minimal() {
+    assert_eq!(encode(VarInt::from(0x25u32)), [0x25]);
+    assert_eq!(encode(VarInt::from(0x3fu32)), [0x3f]);
+    assert_eq!(encode(VarInt::from(0x40u32)), [0x40, 0x40]);
+    assert_eq!(encode(VarInt::from(0x3fffu32)), [0x7f, 0xff]);
+    assert_eq!(encode(VarInt::from(0x4000u32)), [0x80, 0x00, 0x40, 0x00]);
+    assert_eq!(
+      encode(VarInt::from(0x3fff_ffffu32)),
+      [0xbf, 0xff, 0xff, 0xff]
+    );
+    assert_eq!(
+      encode(VarInt::from(0x4000_0000u32)),
+      [0xc0, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(
+      encode(VarInt::MAX),
+      [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+    );
+  }
+
+  #[test]
+  fn varint_encode_round_trips() {
+    for v in [
+      0u64,
+      1,
+      63,
+      64,
+      16383,
+      16384,
+      151_288_809_941_952_652,
+      (1 << 62) - 1,
+    ] {
+      let v = VarInt::try_from(v).unwrap();
+      let buf = encode(v);
+      let mut bufref = &buf[..];
+      assert_eq!(VarInt::parse(&mut bufref).ok(), Some(v));
+      assert!(bufref.is_empty());
+    }
+  }
+
+  #[test]
+  fn varint_encode_into_short_slice_fails() {
+    let mut buf = [0u8; 3];
+    assert!(VarInt::from(0x4000u32).encode(&mut &mut buf[..]).is_err());
+  }
+
+  #[test]
+  fn varint_try_from_bounds() {
+    assert_eq!(VarInt::try_from((1u64 << 62) - 1), Ok(VarInt::MAX));
+    assert_eq!(VarInt::try_from(1u64 << 62), Err(VarIntOutOfRange));
+    assert_eq!(VarInt::try_from(u64::MAX), Err(VarIntOutOfRange));
+    assert_eq!(VarInt::try_from(5usize), Ok(VarInt::from(5u32)));
+    assert_eq!(u64::from(VarInt::MAX), (1 << 62) - 1);
+    assert_eq!(usize::try_from(VarInt::from(7u32)), Ok(7));
+  }
+
+  #[test]
+  fn varint_checked_arithmetic() {
+    let one = VarInt::from(1u32);
+    assert_eq!(one.checked_add(one), Some(VarInt::from(2u32)));
+    assert_eq!(VarInt::MAX.checked_add(VarInt::ZERO), Some(VarInt::MAX));
+    assert_eq!(VarInt::MAX.checked_add(one), None);
+    assert_eq!(VarInt::from(2u32).checked_sub(one), Some(one));
+    assert_eq!(one.checked_sub(one), Some(VarInt::ZERO));
+    assert_eq!(VarInt::ZERO.checked_sub(one), None);
+  }
+
+  #[test]
+  fn varint_ordering_and_display() {
+    assert!(VarInt::ZERO < VarInt::from(1u32));
+    assert!(VarInt::MAX > VarInt::from(u32::MAX));
+    assert_eq!(
+      VarInt::from(3u32).max(VarInt::from(2u32)),
+      VarInt::from(3u32)
+    );
+    assert_eq!(VarInt::from(1234u32).to_string(), "1234");
+    assert_eq!(VarInt::MAX.to_string(), "4611686018427387903");
+  }
+
+  fn pn(v: u64) -> PacketNumber {
+    PacketNumber::try_from(v).unwrap()
+  }
+
+  #[test]
+  fn packet_number_decode_rfc_example() {
+    // https://datatracker.ietf.org/doc/html/rfc9000#name-sample-packet-number-decodi
+    assert_eq!(
+      PacketNumber::decode(0x9b32, 2, Some(pn(0xa82f30ea))),
+      pn(0xa82f9b32)
+    );
+  }
+
+  #[test]
+  fn packet_number_decode_without_largest() {
+    assert_eq!(PacketNumber::decode(0, 1, None), pn(0));
+    assert_eq!(PacketNumber::decode(0x7f, 1, None), pn(0x7f));
+    assert_eq!(PacketNumber::decode(0x1234, 2, None), pn(0x1234));
+  }
+
+  #[test]
+  fn packet_number_decode_wraps_window() {
+    // Just past a 1 byte boundary
+    assert_eq!(PacketNumber::decode(0x01, 1, Some(pn(0xff))), pn(0x101));
+    // Reordered packet from just before a 1 byte boundary
+    assert_eq!(PacketNumber::decode(0xfe, 1, Some(pn(0x101))), pn(0xfe));
+    // Past the 4 byte boundary
+    assert_eq!(
+      PacketNumber::decode(0x0000_0005, 4, Some(pn(0xffff_fffe))),
+      pn(0x1_0000_0005)
+    );
+    // Never exceeds the maximum packet number
+    assert_eq!(
+      PacketNumber::decode(0x00, 1, Some(pn((1 << 62) - 2))),
+      pn((1 << 62) - 256)
+    );
+  }
+
+  #[test]
+  fn packet_number_encoded_len() {
+    // https://datatracker.ietf.org/doc/html/rfc9000#name-sample-packet-number-encodi
+    assert_eq!(pn(0xac5c02).encoded_len(Some(pn(0xabe8b3))), 2);
+    assert_eq!(pn(0xace8fe).encoded_len(Some(pn(0xabe8b3))), 3);
+    assert_eq!(pn(0).encoded_len(None), 1);
+    assert_eq!(pn(0x7f).encoded_len(None), 1);
+    assert_eq!(pn(0x80).encoded_len(None), 2);
+    assert_eq!(pn(1 << 40).encoded_len(Some(pn((1 << 40) - 1))), 1);
+    assert_eq!(pn(1 << 40).encoded_len(None), 4);
+  }
+
+  #[test]
+  fn packet_number_encode_round_trips() {
+    let largest_acked = Some(pn(0x1_2345_6700));
+    for full in [0x1_2345_6701, 0x1_2345_6800, 0x1_2346_0000, 0x1_3000_0000] {
+      let full = pn(full);
+      let len = full.encoded_len(largest_acked);
+      let mut buf = Vec::new();
+      full.encode(len, &mut buf).unwrap();
+      assert_eq!(buf.len(), len);
+
+      let mut bufref = &buf[..];
+      let parsed = PacketNumber::parse(&mut bufref, len, largest_acked).unwrap();
+      assert_eq!(parsed, full);
+    }
+  }
+
+  #[test]
+  fn stream_id_parts() {
+    // https://datatracker.ietf.org/doc/html/rfc9000#stream-id-types
+    let types = [
+      (0x00, Side::Client, StreamDir::Bidirectional),
+      (0x01, Side::Server, StreamDir::Bidirectional),
+      (0x02, Side::Client, StreamDir::Unidirectional),
+      (0x03, Side::Server, StreamDir::Unidirectional),
+    ];
+    for (bits, initiator, dir) in types {
+      let id = StreamId::new(initiator, dir, 5).unwrap();
+      assert_eq!(u64::from(id), 5 << 2 | bits);
+      assert_eq!(id.initiator(), initiator);
+      assert_eq!(id.dir(), dir);
+      assert_eq!(id.index(), 5);
+    }
+  }
+
+  #[test]
+  fn stream_id_index_bounds() {
+    let id = StreamId::new(Side::Server, StreamDir::Unidirectional, StreamId::MAX_INDEX).unwrap();
+    assert_eq!(id.into_inner(), VarInt::MAX);
+    assert_eq!(id.index(), StreamId::MAX_INDEX);
+    assert!(StreamId::new(Side::Client, StreamDir::Bidirectional, 1 << 60).is_err());
+  }
+
+  #[test]
+  fn stream_id_permissions() {
+    let bidi = StreamId::new(Side::Client, StreamDir::Bidirectional, 0).unwrap();
+    assert!(bidi.can_open(Side::Client));
+    assert!(!bidi.can_open(Side::Server));
+    for side in [Side::Client, Side::Server] {
+      assert!(bidi.can_send(side));
+      assert!(bidi.can_recv(side));
+    }
+
+    let uni = StreamId::new(Side::Server, StreamDir::Unidirectional, 0).unwrap();
+    assert!(uni.can_open(Side::Server));
+    assert!(uni.can_send(Side::Server));
+    assert!(!uni.can_recv(Side::Server));
+    assert!(!uni.can_open(Side::Client));
+    assert!(!uni.can_send(Side::Client));
+    assert!(uni.can_recv(Side::Client));
+  }
+
+  #[test]
+  fn connid_new_checks_length() {
+    let cid = ConnectionId::new(&[0xab; MAX_CID_LEN]).unwrap();
+    assert_eq!(cid.as_bytes(), [0xab; MAX_CID_LEN]);
+    assert!(ConnectionId::new(&[0; MAX_CID_LEN + 1]).is_err());
+    assert!(ConnectionId::new(&[]).unwrap().is_empty());
+    assert_eq!(ConnectionId::new(&[]).unwrap(), ConnectionId::EMPTY);
+  }
+
+  #[test]
+  fn connid_random_has_length() {
+    for len in 0..=MAX_CID_LEN {
+      assert_eq!(ConnectionId::random(len).unwrap().len(), len);
+    }
+    assert!(ConnectionId::random(MAX_CID_LEN + 1).is_err());
+    // 2^-64 chance of a collision
+    assert_ne!(
+      ConnectionId::random(8).unwrap(),
+      ConnectionId::random(8).unwrap()
+    );
+  }
+
+  #[test]
+  fn connid_eq_and_hash_ignore_unused_bytes() {
+    use std::collections::hash_map::DefaultHasher;
+
+    let a = ConnectionId::new(&[1, 2]).unwrap();
+    let mut b = a.clone();
+    b.buf[5] = 0xff;
+    assert_eq!(a, b);
+
+    let hash = |cid: &ConnectionId| {
+      let mut hasher = DefaultHasher::new();
+      cid.hash(&mut hasher);
+      hasher.finish()
+    };
+    assert_eq!(hash(&a), hash(&b));
+    assert_ne!(a, ConnectionId::new(&[1, 2, 0]).unwrap());
+  }
+
+  #[test]
+  fn connid_formats_as_hex() {
+    let cid = ConnectionId::new(&[0x83, 0x94, 0xc8, 0x0f]).unwrap();
+    assert_eq!(cid.to_string(), "8394c80f");
+    assert_eq!(format!("{cid:?}"), "ConnectionId(8394c80f)");
+    assert_eq!(ConnectionId::EMPTY.to_string(), "");
+  }
+
   #[test]
   fn connid_parse_no_bytes_fails() {
     let buf = Vec::<u8>::new();
     let mut bufref = &buf[..];
     let res = ConnectionId::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn connid_parse_size_only_fails() {
-    let buf = vec![19u8];
+    let buf = [19u8];
     let mut bufref = &buf[..];
     let res = ConnectionId::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn connid_parse_size_big_fails() {
-    let buf = vec![20u8];
+    let buf = [20u8];
     let mut bufref = &buf[..];
     let res = ConnectionId::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn connid_parse_size_very_big_fails() {
-    let buf = vec![0xffu8];
+    let buf = [0xffu8];
     let mut bufref = &buf[..];
     let res = ConnectionId::parse(&mut bufref);
-    let kind = res
-      .err()
-      .as_ref()
-      .and_then(|e| e.downcast_ref::<io::Error>())
-      .map(|e| e.kind());
+    let kind = match res {
+      Err(Error::Io(e)) => Some(e.kind()),
+      _ => None,
+    };
     assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof));
   }
 
   #[test]
   fn connid_parse_one_byte_passes() {
-    let buf = vec![1u8, 0x12, 0x34];
+    let buf = [1u8, 0x12, 0x34];
     let mut bufref = &buf[..];
     let res = ConnectionId::parse(&mut bufref);
     assert_eq!(
       res.ok(),
       Some(ConnectionId {
-        length: 1 as usize,
+        length: 1,
         buf: [0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
       })
     );
@@ -276,7 +986,7 @@ mod tests {
 
   #[test]
   fn connid_parse_10_bytes_passes() {
-    let buf = vec![
+    let buf = [
       10u8, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0x00, 0x12, 0x34,
     ];
     let mut bufref = &buf[..];
@@ -284,7 +994,7 @@ mod tests {
     assert_eq!(
       res.ok(),
       Some(ConnectionId {
-        length: 10 as usize,
+        length: 10,
         buf: [
           0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
         ]
@@ -295,7 +1005,7 @@ mod tests {
 
   #[test]
   fn connid_parse_max_byte_passes() {
-    let buf = vec![
+    let buf = [
       20u8, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0x00, 0x22, 0x11, 0x33, 0x55,
       0x44, 0x77, 0x66, 0x99, 0x11, 0x20, 0x12, 0x34,
     ];
@@ -304,7 +1014,7 @@ mod tests {
     assert_eq!(
       res.ok(),
       Some(ConnectionId {
-        length: 20 as usize,
+        length: 20,
         buf: [
           0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0x00, 0x22, 0x11, 0x33, 0x55, 0x44,
           0x77, 0x66, 0x99, 0x11, 0x20
@@ -0,0 +1,153 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use quik_util::*;
+
+// Frame types that `Frame` already knows about
+fn is_builtin(frame_type: u64) -> bool {
+  // QUIC v1
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-frame-types
+  let v1 = frame_type <= 0x1e;
+  // https://datatracker.ietf.org/doc/html/rfc9221#name-datagram-frame-types
+  let datagram = matches!(frame_type, 0x30 | 0x31);
+  // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-frames
+  let ack_frequency = matches!(frame_type, 0x1f | 0xaf);
+  v1 || datagram || ack_frequency
+}
+
+// Knows how to find the end of the frames of an extension, so that they can be
+// surfaced as `Frame::Extension` without quik-core understanding them
+pub trait ExtensionCodec: Send + Sync {
+  // Length of the frame body at the start of `data`, which follows the frame
+  // type
+  fn body_len(&self, frame_type: u64, data: &[u8]) -> Result<usize>;
+}
+
+#[derive(Clone)]
+struct Entry {
+  transport_parameter: u64,
+  codec: Arc<dyn ExtensionCodec>,
+  enabled: bool,
+}
+
+// Extension frame codecs keyed by frame type. An extension is only enabled
+// once both endpoints sent its transport parameter, and until then its frames
+// are treated like any other unknown frame.
+// https://datatracker.ietf.org/doc/html/rfc9000#section-19.21
+#[derive(Clone, Default)]
+pub struct ExtensionRegistry {
+  frames: HashMap<u64, Entry>,
+}
+
+impl fmt::Debug for ExtensionRegistry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut frames = f.debug_map();
+    for (frame_type, entry) in &self.frames {
+      frames.entry(frame_type, &(entry.transport_parameter, entry.enabled));
+    }
+    frames.finish()
+  }
+}
+
+impl ExtensionRegistry {
+  pub fn register(
+    &mut self,
+    frame_type: u64,
+    transport_parameter: u64,
+    codec: Arc<dyn ExtensionCodec>,
+  ) -> Result<()> {
+    if is_builtin(frame_type) {
+      Err("Extension frame type is already built in")?;
+    }
+    if self.frames.contains_key(&frame_type) {
+      Err("Extension frame type is already registered")?;
+    }
+    self.frames.insert(
+      frame_type,
+      Entry {
+        transport_parameter,
+        codec,
+        enabled: false,
+      },
+    );
+    Ok(())
+  }
+
+  // Transport parameters to send so that the peer knows what we support
+  pub fn transport_parameters(&self) -> BTreeSet<u64> {
+    self
+      .frames
+      .values()
+      .map(|entry| entry.transport_parameter)
+      .collect()
+  }
+
+  // Enables the extensions whose transport parameter the peer also sent
+  pub fn negotiate(&mut self, peer_parameters: impl IntoIterator<Item = u64>) {
+    let peer_parameters: Vec<u64> = peer_parameters.into_iter().collect();
+    for entry in self.frames.values_mut() {
+      entry.enabled = peer_parameters.contains(&entry.transport_parameter);
+    }
+  }
+
+  pub fn is_enabled(&self, frame_type: u64) -> bool {
+    self.codec(frame_type).is_some()
+  }
+
+  pub(crate) fn codec(&self, frame_type: u64) -> Option<&dyn ExtensionCodec> {
+    self
+      .frames
+      .get(&frame_type)
+      .filter(|entry| entry.enabled)
+      .map(|entry| entry.codec.as_ref())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Fixed(usize);
+
+  impl ExtensionCodec for Fixed {
+    fn body_len(&self, _frame_type: u64, _data: &[u8]) -> Result<usize> {
+      Ok(self.0)
+    }
+  }
+
+  #[test]
+  fn register_rejects_v1_and_duplicate_types() {
+    let mut registry = ExtensionRegistry::default();
+    assert!(registry.register(0x1e, 0x20, Arc::new(Fixed(0))).is_err());
+    assert!(registry.register(0x31, 0x20, Arc::new(Fixed(0))).is_err());
+    assert!(registry.register(0xaf, 0x20, Arc::new(Fixed(0))).is_err());
+    registry.register(0x40, 0x20, Arc::new(Fixed(0))).unwrap();
+    assert!(registry.register(0x40, 0x21, Arc::new(Fixed(0))).is_err());
+  }
+
+  #[test]
+  fn extensions_enabled_by_negotiation() {
+    let mut registry = ExtensionRegistry::default();
+    registry.register(0x40, 0x20, Arc::new(Fixed(0))).unwrap();
+    registry.register(0x41, 0x20, Arc::new(Fixed(0))).unwrap();
+    registry
+      .register(0x0f10, 0x0f10, Arc::new(Fixed(0)))
+      .unwrap();
+
+    assert_eq!(
+      registry
+        .transport_parameters()
+        .into_iter()
+        .collect::<Vec<_>>(),
+      [0x20, 0x0f10]
+    );
+    assert!(!registry.is_enabled(0x40));
+
+    registry.negotiate([0x20, 0x04]);
+    assert!(registry.is_enabled(0x40));
+    assert!(registry.is_enabled(0x41));
+    assert!(!registry.is_enabled(0x0f10));
+    assert!(!registry.is_enabled(0x42));
+  }
+}
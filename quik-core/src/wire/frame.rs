@@ -1,7 +1,15 @@
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
 use quik_util::*;
 
-use crate::wire::{ConnectionId, StreamId, VarInt};
+use crate::crypto::EncryptionLevel;
+use crate::wire::{
+  parse_field, ConnectionId, ExtensionRegistry, ParseLimits, Side, StreamDir, StreamId, VarInt,
+};
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Frame<'a> {
   Padding,
   Ping,
@@ -23,83 +31,192 @@ pub enum Frame<'a> {
   PathResponse(PathResponse),
   ConnectionClose(ConnectionClose<'a>),
   HandshakeDone,
+  Datagram(Datagram<'a>),
+  AckFrequency(AckFrequency),
+  ImmediateAck,
+  Extension(Extension<'a>),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Padding;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ping;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AckRange {
   pub gap: VarInt,
   pub range_length: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EcnCounts {
   pub ect0: VarInt,
   pub ect1: VarInt,
   pub ce: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ack {
   pub largest_acked: VarInt,
   pub ack_delay: VarInt,
-  pub ack_range_count: VarInt,
   pub first_ack_range: VarInt,
   pub ack_ranges: Vec<AckRange>,
   pub ecn_counts: Option<EcnCounts>,
 }
 
+fn negative_packet_number() -> Error {
+  Error::transport(
+    TransportErrorCode::FrameEncodingError,
+    "ACK range below packet number 0",
+  )
+}
+
+impl Ack {
+  // Acknowledges `packet_numbers`, reporting `ack_delay` scaled down by
+  // `ack_delay_exponent`
+  pub fn new(
+    packet_numbers: &BTreeSet<u64>,
+    ack_delay: Duration,
+    ack_delay_exponent: u64,
+  ) -> Result<Self> {
+    let mut ranges: Vec<RangeInclusive<u64>> = Vec::new();
+    for &pn in packet_numbers.iter().rev() {
+      match ranges.last_mut() {
+        Some(range) if pn + 1 == *range.start() => *range = pn..=*range.end(),
+        _ => ranges.push(pn..=pn),
+      }
+    }
+    let Some(first) = ranges.first() else {
+      Err("No packet numbers to acknowledge")?
+    };
+
+    let ack_ranges = ranges
+      .windows(2)
+      .map(|pair| {
+        // Gap and ACK Range Length are both one less than what they count
+        Ok(AckRange {
+          gap: VarInt::try_from(pair[0].start() - pair[1].end() - 2)?,
+          range_length: VarInt::try_from(pair[1].end() - pair[1].start())?,
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let ack_delay = ack_delay.as_micros() >> ack_delay_exponent.min(u128::BITS as u64 - 1);
+    Ok(Self {
+      largest_acked: VarInt::try_from(*first.end())?,
+      ack_delay: VarInt::try_from(u64::try_from(ack_delay)?)?,
+      first_ack_range: VarInt::try_from(first.end() - first.start())?,
+      ack_ranges,
+      ecn_counts: None,
+    })
+  }
+
+  // Acknowledged packet numbers, from the largest range down
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-ack-ranges
+  pub fn ranges(&self) -> impl Iterator<Item = Result<RangeInclusive<u64>>> + '_ {
+    // Computed in i128 so that a range going below 0 is caught rather than
+    // wrapping around. The first range acts as if it followed a gap of -1.
+    let mut smallest = i128::from(self.largest_acked.into_inner()) + 1;
+    let first = (-1, self.first_ack_range);
+    let rest = self
+      .ack_ranges
+      .iter()
+      .map(|range| (i128::from(range.gap.into_inner()), range.range_length));
+    std::iter::once(first)
+      .chain(rest)
+      .map_while(move |(gap, range_length)| {
+        // Nothing follows a negative range
+        if smallest < 0 {
+          return None;
+        }
+        let largest = smallest - gap - 2;
+        smallest = largest - i128::from(range_length.into_inner());
+        if smallest < 0 {
+          return Some(Err(negative_packet_number()));
+        }
+        Some(Ok(smallest as u64..=largest as u64))
+      })
+  }
+
+  // The encoded ack delay is in units of 2^ack_delay_exponent microseconds
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-19.3-4.4.1
+  pub fn ack_delay_duration(&self, ack_delay_exponent: u64) -> Duration {
+    let scale = u32::try_from(ack_delay_exponent).map_or(u64::MAX, |exp| 2u64.saturating_pow(exp));
+    Duration::from_micros(self.ack_delay.into_inner().saturating_mul(scale))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ResetStream {
   pub stream_id: StreamId,
   pub err_code: VarInt,
   pub final_size: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StopSending {
   pub stream_id: StreamId,
   pub err_code: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Crypto<'a> {
+  // Position of `data` in the crypto stream of the encryption level
+  pub offset: u64,
   pub data: &'a [u8],
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NewToken<'a> {
   pub token: &'a [u8],
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stream<'a> {
   pub stream_id: StreamId,
+  // Position of `data` in the stream
+  pub offset: u64,
+  // Without a Length field, the data extends to the end of the packet
+  pub has_len: bool,
   pub fin: bool,
   pub data: &'a [u8],
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MaxData {
   pub max_data: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MaxStreamData {
   pub stream_id: StreamId,
   pub max_stream_data: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MaxStreams {
+  pub dir: StreamDir,
   pub max_streams: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DataBlocked {
   pub max_data: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StreamDataBlocked {
   pub stream_id: StreamId,
   pub max_stream_data: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StreamsBlocked {
+  pub dir: StreamDir,
   pub max_streams: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NewConnectionId {
   pub seq_num: VarInt,
   pub retire_prior_to: VarInt,
@@ -107,18 +224,22 @@ pub struct NewConnectionId {
   pub stateless_reset_token: u128,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RetireConnectionId {
   pub seq_num: VarInt,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PathChallenge {
   pub data: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PathResponse {
   pub data: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ConnectionClose<'a> {
   pub err_code: VarInt,
   // Some when it's a QUIC err rather than a application error
@@ -126,24 +247,230 @@ pub struct ConnectionClose<'a> {
   pub reason_phrase: &'a [u8],
 }
 
+// Unknown codes may come from anywhere, so they're clamped to fit a VarInt
+fn error_code(code: TransportErrorCode) -> VarInt {
+  VarInt::try_from(code.code()).unwrap_or(VarInt::MAX)
+}
+
+// Closes the connection with the error, with a frame type of 0 when it isn't
+// attributed to a frame
+// https://datatracker.ietf.org/doc/html/rfc9000#section-19.19-6.4.1
+impl<'a> From<&'a Error> for ConnectionClose<'a> {
+  fn from(err: &'a Error) -> Self {
+    match err {
+      Error::Application { code, reason } => match VarInt::try_from(*code) {
+        Ok(err_code) => ConnectionClose {
+          err_code,
+          frame_type: None,
+          reason_phrase: reason.as_bytes(),
+        },
+        Err(_) => ConnectionClose {
+          err_code: error_code(TransportErrorCode::InternalError),
+          frame_type: Some(VarInt::ZERO),
+          reason_phrase: b"Application error code out of range",
+        },
+      },
+      Error::Transport {
+        code,
+        frame_type,
+        reason,
+        ..
+      } => ConnectionClose {
+        err_code: error_code(*code),
+        frame_type: Some(
+          frame_type
+            .and_then(|typ| VarInt::try_from(typ).ok())
+            .unwrap_or(VarInt::ZERO),
+        ),
+        reason_phrase: reason.as_bytes(),
+      },
+      // Internal failures aren't described to the peer
+      Error::Io(_) | Error::Other(_) => ConnectionClose {
+        err_code: error_code(TransportErrorCode::InternalError),
+        frame_type: Some(VarInt::ZERO),
+        reason_phrase: &[],
+      },
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandshakeDone;
 
+// https://datatracker.ietf.org/doc/html/rfc9221#name-datagram-frame-types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram<'a> {
+  pub data: &'a [u8],
+}
+
+// Asks the peer to change how often it sends acknowledgments
+// https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-ack_frequency-frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckFrequency {
+  pub seq_num: VarInt,
+  pub ack_eliciting_threshold: VarInt,
+  // In microseconds
+  pub request_max_ack_delay: VarInt,
+  pub reordering_threshold: VarInt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImmediateAck;
+
+// Frame of a negotiated extension, left encoded for its handler to interpret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension<'a> {
+  pub frame_type: VarInt,
+  pub body: &'a [u8],
+}
+
+// Encoded length of a length prefix for a slice of `len` bytes
+fn len_prefix_len(len: usize) -> usize {
+  VarInt::try_from(len).map_or(8, |len| len.encoded_len())
+}
+
+fn offset_len(offset: u64) -> usize {
+  VarInt::try_from(offset).map_or(8, |offset| offset.encoded_len())
+}
+
+// The end of the data in a stream can't be more than 2^62 - 1
+// https://datatracker.ietf.org/doc/html/rfc9000#section-19.8-11
+fn check_stream_end(offset: u64, len: usize) -> Result<()> {
+  offset
+    .checked_add(len as u64)
+    .filter(|end| *end <= VarInt::MAX.into_inner())
+    .ok_or(Error::transport(
+      TransportErrorCode::FrameEncodingError,
+      "Stream data beyond maximum offset",
+    ))?;
+  Ok(())
+}
+
+// `start` is the start of the frame
+fn field<'a, T>(
+  start: &[u8],
+  data: &mut &'a [u8],
+  name: &'static str,
+  parse: impl FnOnce(&mut &'a [u8]) -> Result<T>,
+) -> Result<T> {
+  let code = TransportErrorCode::FrameEncodingError;
+  parse_field(code, start, data, name, parse)
+}
+
+// Stream counts can't exceed 2^60, as the stream ID would then overflow
+// https://datatracker.ietf.org/doc/html/rfc9000#section-19.11-5.2.1
+const MAX_STREAMS: u64 = StreamId::MAX_INDEX + 1;
+
+fn parse_max_streams(data: &mut &[u8]) -> Result<VarInt> {
+  let max_streams = VarInt::parse(data)?;
+  if max_streams.into_inner() > MAX_STREAMS {
+    Err(Error::transport(
+      TransportErrorCode::FrameEncodingError,
+      "Maximum streams beyond 2^60",
+    ))?;
+  }
+  Ok(max_streams)
+}
+
+// The lowest bit of MAX_STREAMS and STREAMS_BLOCKED types is set for
+// unidirectional streams
+fn stream_dir(typ: u64) -> StreamDir {
+  if typ & 0x01 == 0 {
+    StreamDir::Bidirectional
+  } else {
+    StreamDir::Unidirectional
+  }
+}
+
+fn stream_dir_bit(dir: StreamDir) -> u32 {
+  match dir {
+    StreamDir::Bidirectional => 0x00,
+    StreamDir::Unidirectional => 0x01,
+  }
+}
+
+fn encode_len_prefixed(data: &[u8], dst: &mut impl WriteBytesExt) -> Result<()> {
+  VarInt::try_from(data.len())?.encode(dst)?;
+  dst.write_all(data)?;
+  Ok(())
+}
+
 impl<'a> Frame<'a> {
-  pub fn parse_multiple(mut data: &'a [u8]) -> impl Iterator<Item = Result<Frame<'a>>> {
+  // Frames borrow from `data`, not from `extensions` or `limits`. Iteration
+  // ends after the first error, as there's no telling where the next frame
+  // starts, and going over `limits.max_frames` is a FRAME_ENCODING_ERROR.
+  // Error offsets are from the start of `data`.
+  pub fn parse_multiple<'r>(
+    mut data: &'a [u8],
+    extensions: &'r ExtensionRegistry,
+    limits: &'r ParseLimits,
+  ) -> impl Iterator<Item = Result<Frame<'a>>> + 'r
+  where
+    'a: 'r,
+  {
+    let payload = data;
+    let mut frames = 0;
     std::iter::from_fn(move || {
-      if !data.is_empty() {
-        Some(Frame::parse(data).map(|(frame, rem_data)| {
+      if data.is_empty() {
+        return None;
+      }
+      let offset = payload.len() - data.len();
+      let frame = Frame::parse_with_limits(data, extensions, limits)
+        .map_err(|err| err.at_offset(offset))
+        .and_then(|(frame, rem_data)| {
           data = rem_data;
-          frame
-        }))
-      } else {
-        None
+          if frame != Frame::Padding {
+            frames += 1;
+            if frames > limits.max_frames {
+              return Err(Error::Transport {
+                code: TransportErrorCode::FrameEncodingError,
+                frame_type: Some(frame.frame_type().into_inner()),
+                reason: "Too many frames in packet".into(),
+                context: None,
+              });
+            }
+          }
+          Ok(frame)
+        });
+      if frame.is_err() {
+        data = &[];
       }
+      Some(frame)
     })
   }
 
-  pub fn parse(mut data: &'a [u8]) -> Result<(Frame<'a>, &'a [u8])> {
-    let typ: usize = VarInt::parse(&mut data)?.into();
+  // Frames that are malformed or of an unknown type are a FRAME_ENCODING_ERROR,
+  // blaming the field that failed by its offset from the start of the frame
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-12.4-8
+  pub fn parse(data: &'a [u8]) -> Result<(Frame<'a>, &'a [u8])> {
+    Self::parse_with_extensions(data, &ExtensionRegistry::default())
+  }
+
+  pub fn parse_with_extensions(
+    data: &'a [u8],
+    extensions: &ExtensionRegistry,
+  ) -> Result<(Frame<'a>, &'a [u8])> {
+    Self::parse_with_limits(data, extensions, &ParseLimits::default())
+  }
+
+  pub fn parse_with_limits(
+    data: &'a [u8],
+    extensions: &ExtensionRegistry,
+    limits: &ParseLimits,
+  ) -> Result<(Frame<'a>, &'a [u8])> {
+    Self::parse_unclassified(data, extensions, limits).map_err(|err| {
+      let typ = VarInt::parse(&mut &data[..]).ok().map(VarInt::into_inner);
+      err.classify(TransportErrorCode::FrameEncodingError, typ)
+    })
+  }
+
+  fn parse_unclassified(
+    mut data: &'a [u8],
+    extensions: &ExtensionRegistry,
+    limits: &ParseLimits,
+  ) -> Result<(Frame<'a>, &'a [u8])> {
+    let start = data;
+    let typ = field(start, &mut data, "Frame Type", VarInt::parse)?.into_inner();
     let frame = match typ {
       0x00 => {
         // Padding
@@ -158,43 +485,58 @@ impl<'a> Frame<'a> {
       0x02..=0x03 => {
         // Ack
         // https://datatracker.ietf.org/doc/html/rfc9000#name-ack-frames
-        let largest_acked = VarInt::parse(&mut data)?;
-        let ack_delay = VarInt::parse(&mut data)?;
-        let ack_range_count = VarInt::parse(&mut data)?;
-        let first_ack_range = VarInt::parse(&mut data)?;
+        let largest_acked = field(start, &mut data, "Largest Acknowledged", VarInt::parse)?;
+        let ack_delay = field(start, &mut data, "ACK Delay", VarInt::parse)?;
+        // The count is checked before anything is allocated for the ranges
+        let ack_range_count = field(start, &mut data, "ACK Range Count", |data| {
+          let count = VarInt::parse(data)?.into_inner();
+          if count > limits.max_ack_ranges as u64 {
+            Err("Too many ACK ranges")?;
+          }
+          Ok(count)
+        })?;
+        let first_ack_range = field(start, &mut data, "First ACK Range", VarInt::parse)?;
 
-        let ack_ranges = (0..ack_range_count.clone().into())
+        let ack_ranges = (0..ack_range_count)
           .map(|_| {
-            let gap = VarInt::parse(&mut data)?;
-            let range_length = VarInt::parse(&mut data)?;
+            let gap = field(start, &mut data, "Gap", VarInt::parse)?;
+            let range_length = field(start, &mut data, "ACK Range Length", VarInt::parse)?;
             Ok(AckRange { gap, range_length })
           })
           .collect::<Result<Vec<_>>>()?;
 
         let ecn_counts = if typ == 0x03 {
-          let ect0 = VarInt::parse(&mut data)?;
-          let ect1 = VarInt::parse(&mut data)?;
-          let ce = VarInt::parse(&mut data)?;
+          let ect0 = field(start, &mut data, "ECT0 Count", VarInt::parse)?;
+          let ect1 = field(start, &mut data, "ECT1 Count", VarInt::parse)?;
+          let ce = field(start, &mut data, "ECN-CE Count", VarInt::parse)?;
           Some(EcnCounts { ect0, ect1, ce })
         } else {
           None
         };
 
-        Frame::Ack(Ack {
+        let ack = Ack {
           largest_acked,
           ack_delay,
-          ack_range_count,
           first_ack_range,
           ack_ranges,
           ecn_counts,
-        })
+        };
+        for range in ack.ranges() {
+          range?;
+        }
+        Frame::Ack(ack)
       }
       0x04 => {
         // Reset Stream
         // https://datatracker.ietf.org/doc/html/rfc9000#name-reset_stream-frames
-        let stream_id = VarInt::parse(&mut data)?;
-        let err_code = VarInt::parse(&mut data)?;
-        let final_size = VarInt::parse(&mut data)?;
+        let stream_id = field(start, &mut data, "Stream ID", StreamId::parse)?;
+        let err_code = field(
+          start,
+          &mut data,
+          "Application Protocol Error Code",
+          VarInt::parse,
+        )?;
+        let final_size = field(start, &mut data, "Final Size", VarInt::parse)?;
 
         Frame::ResetStream(ResetStream {
           stream_id,
@@ -205,8 +547,13 @@ impl<'a> Frame<'a> {
       0x05 => {
         // Stop Sending
         // https://datatracker.ietf.org/doc/html/rfc9000#name-stop_sending-frames
-        let stream_id = VarInt::parse(&mut data)?;
-        let err_code = VarInt::parse(&mut data)?;
+        let stream_id = field(start, &mut data, "Stream ID", StreamId::parse)?;
+        let err_code = field(
+          start,
+          &mut data,
+          "Application Protocol Error Code",
+          VarInt::parse,
+        )?;
 
         Frame::StopSending(StopSending {
           stream_id,
@@ -216,17 +563,32 @@ impl<'a> Frame<'a> {
       0x06 => {
         // Crypto
         // https://datatracker.ietf.org/doc/html/rfc9000#name-crypto-frames
-        let offset = VarInt::parse(&mut data)?;
-        let length = VarInt::parse(&mut data)?;
-        let crypto_data = data.extract(Some(offset.into()), Some(length.into()))?;
+        let offset = field(start, &mut data, "Offset", VarInt::parse)?.into_inner();
+        let length = field(start, &mut data, "Length", VarInt::parse)?;
+        let crypto_data = field(start, &mut data, "Crypto Data", |data| {
+          let crypto_data = data.slice(length.try_into()?)?;
+          check_stream_end(offset, crypto_data.len())?;
+          Ok(crypto_data)
+        })?;
 
-        Frame::Crypto(Crypto { data: crypto_data })
+        Frame::Crypto(Crypto {
+          offset,
+          data: crypto_data,
+        })
       }
       0x07 => {
         // New Token
         // https://datatracker.ietf.org/doc/html/rfc9000#name-new_token-frames
-        let token_length = VarInt::parse(&mut data)?;
-        let token = data.slice(token_length.into())?;
+        let token_length = field(start, &mut data, "Token Length", |data| {
+          let length = VarInt::parse(data)?;
+          if length.into_inner() > limits.max_token_len as u64 {
+            Err("Token too long")?;
+          }
+          Ok(length)
+        })?;
+        let token = field(start, &mut data, "Token", |data| {
+          data.slice(token_length.try_into()?)
+        })?;
 
         Frame::NewToken(NewToken { token })
       }
@@ -237,21 +599,28 @@ impl<'a> Frame<'a> {
         let len_bit = typ & 0b010;
         let fin_bit = typ & 0b001;
 
-        let stream_id = VarInt::parse(&mut data)?;
+        let stream_id = field(start, &mut data, "Stream ID", StreamId::parse)?;
         let offset = if off_bit != 0 {
-          Some(VarInt::parse(&mut data)?.into())
+          field(start, &mut data, "Offset", VarInt::parse)?.into_inner()
         } else {
-          None
+          0
         };
         let length = if len_bit != 0 {
-          Some(VarInt::parse(&mut data)?.into())
+          Some(field(start, &mut data, "Length", VarInt::parse)?.try_into()?)
         } else {
           None
         };
-        let stream_data = data.extract(offset.into(), length.into())?;
+        // Without a length the data extends to the end of the packet
+        let stream_data = field(start, &mut data, "Stream Data", |data| {
+          let stream_data = data.extract(None, length)?;
+          check_stream_end(offset, stream_data.len())?;
+          Ok(stream_data)
+        })?;
 
         Frame::Stream(Stream {
           stream_id,
+          offset,
+          has_len: len_bit != 0,
           data: stream_data,
           fin: fin_bit != 0,
         })
@@ -259,15 +628,15 @@ impl<'a> Frame<'a> {
       0x10 => {
         // Max Data
         // https://datatracker.ietf.org/doc/html/rfc9000#name-max_data-frames
-        let max_data = VarInt::parse(&mut data)?;
+        let max_data = field(start, &mut data, "Maximum Data", VarInt::parse)?;
 
         Frame::MaxData(MaxData { max_data })
       }
       0x11 => {
         // Max Stream Data
         // https://datatracker.ietf.org/doc/html/rfc9000#name-max_stream_data-frames
-        let stream_id = VarInt::parse(&mut data)?;
-        let max_stream_data = VarInt::parse(&mut data)?;
+        let stream_id = field(start, &mut data, "Stream ID", StreamId::parse)?;
+        let max_stream_data = field(start, &mut data, "Maximum Stream Data", VarInt::parse)?;
 
         Frame::MaxStreamData(MaxStreamData {
           stream_id,
@@ -277,23 +646,25 @@ impl<'a> Frame<'a> {
       0x12..=0x13 => {
         // Max Streams
         // https://datatracker.ietf.org/doc/html/rfc9000#name-max_streams-frames
-        let max_streams = VarInt::parse(&mut data)?;
-        // TODO 0x12 is bidirectional, 0x13 is unidirectional. Does this matter?
+        let max_streams = field(start, &mut data, "Maximum Streams", parse_max_streams)?;
 
-        Frame::MaxStreams(MaxStreams { max_streams })
+        Frame::MaxStreams(MaxStreams {
+          dir: stream_dir(typ),
+          max_streams,
+        })
       }
       0x14 => {
         // Data Blocked
         // https://datatracker.ietf.org/doc/html/rfc9000#name-data_blocked-frames
-        let max_data = VarInt::parse(&mut data)?;
+        let max_data = field(start, &mut data, "Maximum Data", VarInt::parse)?;
 
         Frame::DataBlocked(DataBlocked { max_data })
       }
       0x15 => {
         // Stream Data Blocked
         // https://datatracker.ietf.org/doc/html/rfc9000#name-stream_data_blocked-frames
-        let stream_id = VarInt::parse(&mut data)?;
-        let max_stream_data = VarInt::parse(&mut data)?;
+        let stream_id = field(start, &mut data, "Stream ID", StreamId::parse)?;
+        let max_stream_data = field(start, &mut data, "Maximum Stream Data", VarInt::parse)?;
 
         Frame::StreamDataBlocked(StreamDataBlocked {
           stream_id,
@@ -303,18 +674,22 @@ impl<'a> Frame<'a> {
       0x16..=0x17 => {
         // Streams Blocked
         // https://datatracker.ietf.org/doc/html/rfc9000#name-streams_blocked-frames
-        let max_streams = VarInt::parse(&mut data)?;
-        // TODO 0x16 is bidirectional, 0x17 is unidirectional. Does this matter?
+        let max_streams = field(start, &mut data, "Maximum Streams", parse_max_streams)?;
 
-        Frame::StreamsBlocked(StreamsBlocked { max_streams })
+        Frame::StreamsBlocked(StreamsBlocked {
+          dir: stream_dir(typ),
+          max_streams,
+        })
       }
       0x18 => {
         // New Connection ID
         // https://datatracker.ietf.org/doc/html/rfc9000#name-new_connection_id-frames
-        let seq_num = VarInt::parse(&mut data)?;
-        let retire_prior_to = VarInt::parse(&mut data)?;
-        let cid = ConnectionId::parse(&mut data)?;
-        let stateless_reset_token = data.read_u128::<NetworkEndian>()?;
+        let seq_num = field(start, &mut data, "Sequence Number", VarInt::parse)?;
+        let retire_prior_to = field(start, &mut data, "Retire Prior To", VarInt::parse)?;
+        let cid = field(start, &mut data, "Connection ID", ConnectionId::parse)?;
+        let stateless_reset_token = field(start, &mut data, "Stateless Reset Token", |data| {
+          Ok(data.read_u128::<NetworkEndian>()?)
+        })?;
 
         Frame::NewConnectionId(NewConnectionId {
           seq_num,
@@ -326,35 +701,47 @@ impl<'a> Frame<'a> {
       0x19 => {
         // Retire Connection ID
         // https://datatracker.ietf.org/doc/html/rfc9000#name-retire_connection_id-frames
-        let seq_num = VarInt::parse(&mut data)?;
+        let seq_num = field(start, &mut data, "Sequence Number", VarInt::parse)?;
 
         Frame::RetireConnectionId(RetireConnectionId { seq_num })
       }
       0x1a => {
         // Path Challenge
         // https://datatracker.ietf.org/doc/html/rfc9000#name-path_challenge-frames
-        let data = data.read_u64::<NetworkEndian>()?;
+        let data = field(start, &mut data, "Data", |data| {
+          Ok(data.read_u64::<NetworkEndian>()?)
+        })?;
 
         Frame::PathChallenge(PathChallenge { data })
       }
       0x1b => {
         // Path Response
         // https://datatracker.ietf.org/doc/html/rfc9000#name-path_response-frames
-        let data = data.read_u64::<NetworkEndian>()?;
+        let data = field(start, &mut data, "Data", |data| {
+          Ok(data.read_u64::<NetworkEndian>()?)
+        })?;
 
         Frame::PathResponse(PathResponse { data })
       }
       0x1c..=0x1d => {
         // Connection Close
         // https://datatracker.ietf.org/doc/html/rfc9000#name-connection_close-frames
-        let err_code = VarInt::parse(&mut data)?;
+        let err_code = field(start, &mut data, "Error Code", VarInt::parse)?;
         let frame_type = if typ == 0x1c {
-          Some(VarInt::parse(&mut data)?)
+          Some(field(start, &mut data, "Frame Type", VarInt::parse)?)
         } else {
           None
         };
-        let reason_phrase_length = VarInt::parse(&mut data)?;
-        let reason_phrase = data.slice(reason_phrase_length.into())?;
+        let reason_phrase_length = field(start, &mut data, "Reason Phrase Length", |data| {
+          let length = VarInt::parse(data)?;
+          if length.into_inner() > limits.max_reason_phrase_len as u64 {
+            Err("Reason phrase too long")?;
+          }
+          Ok(length)
+        })?;
+        let reason_phrase = field(start, &mut data, "Reason Phrase", |data| {
+          data.slice(reason_phrase_length.try_into()?)
+        })?;
 
         Frame::ConnectionClose(ConnectionClose {
           err_code,
@@ -367,9 +754,1121 @@ impl<'a> Frame<'a> {
         // https://datatracker.ietf.org/doc/html/rfc9000#name-handshake_done-frames
         Frame::HandshakeDone
       }
-      _ => Err("Unknown frame type")?,
+      0x1f => {
+        // Immediate Ack
+        // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-immediate_ack-frame
+        Frame::ImmediateAck
+      }
+      0x30..=0x31 => {
+        // Datagram
+        // https://datatracker.ietf.org/doc/html/rfc9221#name-datagram-frame-types
+        let length = if typ & 0x01 != 0 {
+          Some(field(start, &mut data, "Length", VarInt::parse)?.try_into()?)
+        } else {
+          None
+        };
+        // Without a length the data extends to the end of the packet
+        let datagram_data = field(start, &mut data, "Datagram Data", |data| {
+          data.extract(None, length)
+        })?;
+
+        Frame::Datagram(Datagram {
+          data: datagram_data,
+        })
+      }
+      0xaf => {
+        // Ack Frequency
+        // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-ack_frequency-frame
+        let seq_num = field(start, &mut data, "Sequence Number", VarInt::parse)?;
+        let ack_eliciting_threshold =
+          field(start, &mut data, "Ack-Eliciting Threshold", VarInt::parse)?;
+        let request_max_ack_delay =
+          field(start, &mut data, "Request Max Ack Delay", VarInt::parse)?;
+        let reordering_threshold = field(start, &mut data, "Reordering Threshold", VarInt::parse)?;
+
+        Frame::AckFrequency(AckFrequency {
+          seq_num,
+          ack_eliciting_threshold,
+          request_max_ack_delay,
+          reordering_threshold,
+        })
+      }
+      _ => {
+        let Some(codec) = extensions.codec(typ) else {
+          Err(
+            Error::transport(TransportErrorCode::FrameEncodingError, "Unknown frame type")
+              .in_field("Frame Type", 0),
+          )?
+        };
+        let body = field(start, &mut data, "Frame Body", |data| {
+          data.slice(codec.body_len(typ, data)?)
+        })?;
+
+        Frame::Extension(Extension {
+          frame_type: VarInt::try_from(typ)?,
+          body,
+        })
+      }
     };
 
     Ok((frame, data))
   }
+
+  // Receiving any other frame obliges the receiver to acknowledge the packet
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-13.2.1
+  pub fn is_ack_eliciting(&self) -> bool {
+    !matches!(
+      self,
+      Frame::Padding | Frame::Ack(_) | Frame::ConnectionClose(_)
+    )
+  }
+
+  // Packet types that can carry this frame. 0-RTT packets can't carry frames
+  // that only make sense once the handshake is done, and extension frames are
+  // limited to 0-RTT and 1-RTT packets.
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-frames-and-frame-types
+  pub fn is_allowed_in(&self, level: EncryptionLevel) -> bool {
+    match self {
+      Frame::Padding | Frame::Ping => true,
+      Frame::ConnectionClose(close) if close.frame_type.is_some() => true,
+      Frame::Ack(_) | Frame::Crypto(_) => level != EncryptionLevel::ZeroRtt,
+      Frame::NewToken(_)
+      | Frame::RetireConnectionId(_)
+      | Frame::PathResponse(_)
+      | Frame::HandshakeDone => level == EncryptionLevel::OneRtt,
+      _ => matches!(level, EncryptionLevel::ZeroRtt | EncryptionLevel::OneRtt),
+    }
+  }
+
+  // Receiving a frame in a packet type that can't carry it, or from an
+  // endpoint that can't send it, is a PROTOCOL_VIOLATION
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-12.4-5
+  pub fn check_recv(&self, level: EncryptionLevel, sender: Side) -> Result<()> {
+    let reason = if !self.is_allowed_in(level) {
+      "Frame not allowed in packet type"
+    } else if sender == Side::Client && matches!(self, Frame::NewToken(_) | Frame::HandshakeDone) {
+      "Frame only sent by servers"
+    } else {
+      return Ok(());
+    };
+    Err(Error::Transport {
+      code: TransportErrorCode::ProtocolViolation,
+      frame_type: Some(self.frame_type().into_inner()),
+      reason: reason.into(),
+      context: None,
+    })
+  }
+
+  pub fn frame_type(&self) -> VarInt {
+    let typ: u32 = match self {
+      Frame::Padding => 0x00,
+      Frame::Ping => 0x01,
+      Frame::Ack(ack) => {
+        if ack.ecn_counts.is_some() {
+          0x03
+        } else {
+          0x02
+        }
+      }
+      Frame::ResetStream(_) => 0x04,
+      Frame::StopSending(_) => 0x05,
+      Frame::Crypto(_) => 0x06,
+      Frame::NewToken(_) => 0x07,
+      Frame::Stream(stream) => {
+        // The offset is left out at the start of the stream
+        let off_bit = if stream.offset != 0 { 0b100 } else { 0 };
+        let len_bit = if stream.has_len { 0b010 } else { 0 };
+        let fin_bit = if stream.fin { 0b001 } else { 0 };
+        0x08 | off_bit | len_bit | fin_bit
+      }
+      Frame::MaxData(_) => 0x10,
+      Frame::MaxStreamData(_) => 0x11,
+      Frame::MaxStreams(max) => 0x12 | stream_dir_bit(max.dir),
+      Frame::DataBlocked(_) => 0x14,
+      Frame::StreamDataBlocked(_) => 0x15,
+      Frame::StreamsBlocked(blocked) => 0x16 | stream_dir_bit(blocked.dir),
+      Frame::NewConnectionId(_) => 0x18,
+      Frame::RetireConnectionId(_) => 0x19,
+      Frame::PathChallenge(_) => 0x1a,
+      Frame::PathResponse(_) => 0x1b,
+      Frame::ConnectionClose(close) => {
+        if close.frame_type.is_some() {
+          0x1c
+        } else {
+          0x1d
+        }
+      }
+      Frame::HandshakeDone => 0x1e,
+      // The type with a Length field, so that frames can be packed in any order
+      Frame::Datagram(_) => 0x31,
+      Frame::AckFrequency(_) => 0xaf,
+      Frame::ImmediateAck => 0x1f,
+      // Extension frame types don't have to fit a u32
+      Frame::Extension(ext) => return ext.frame_type,
+    };
+    typ.into()
+  }
+
+  // Number of bytes `encode` will write for this frame
+  pub fn encoded_len(&self) -> usize {
+    let body = match self {
+      Frame::Padding | Frame::Ping | Frame::HandshakeDone | Frame::ImmediateAck => 0,
+      Frame::Ack(ack) => {
+        let ack_ranges: usize = ack
+          .ack_ranges
+          .iter()
+          .map(|r| r.gap.encoded_len() + r.range_length.encoded_len())
+          .sum();
+        let ecn_counts = ack.ecn_counts.as_ref().map_or(0, |ecn| {
+          ecn.ect0.encoded_len() + ecn.ect1.encoded_len() + ecn.ce.encoded_len()
+        });
+        ack.largest_acked.encoded_len()
+          + ack.ack_delay.encoded_len()
+          + len_prefix_len(ack.ack_ranges.len())
+          + ack.first_ack_range.encoded_len()
+          + ack_ranges
+          + ecn_counts
+      }
+      Frame::ResetStream(reset) => {
+        reset.stream_id.encoded_len()
+          + reset.err_code.encoded_len()
+          + reset.final_size.encoded_len()
+      }
+      Frame::StopSending(stop) => stop.stream_id.encoded_len() + stop.err_code.encoded_len(),
+      Frame::Crypto(crypto) => {
+        offset_len(crypto.offset) + len_prefix_len(crypto.data.len()) + crypto.data.len()
+      }
+      Frame::NewToken(new_token) => len_prefix_len(new_token.token.len()) + new_token.token.len(),
+      Frame::Stream(stream) => {
+        let offset_len = if stream.offset != 0 {
+          offset_len(stream.offset)
+        } else {
+          0
+        };
+        let len_len = if stream.has_len {
+          len_prefix_len(stream.data.len())
+        } else {
+          0
+        };
+        stream.stream_id.encoded_len() + offset_len + len_len + stream.data.len()
+      }
+      Frame::MaxData(max) => max.max_data.encoded_len(),
+      Frame::MaxStreamData(max) => max.stream_id.encoded_len() + max.max_stream_data.encoded_len(),
+      Frame::MaxStreams(max) => max.max_streams.encoded_len(),
+      Frame::DataBlocked(blocked) => blocked.max_data.encoded_len(),
+      Frame::StreamDataBlocked(blocked) => {
+        blocked.stream_id.encoded_len() + blocked.max_stream_data.encoded_len()
+      }
+      Frame::StreamsBlocked(blocked) => blocked.max_streams.encoded_len(),
+      Frame::NewConnectionId(new_cid) => {
+        new_cid.seq_num.encoded_len()
+          + new_cid.retire_prior_to.encoded_len()
+          + new_cid.cid.encoded_len()
+          + 16
+      }
+      Frame::RetireConnectionId(retire) => retire.seq_num.encoded_len(),
+      Frame::PathChallenge(_) | Frame::PathResponse(_) => 8,
+      Frame::ConnectionClose(close) => {
+        close.err_code.encoded_len()
+          + close.frame_type.as_ref().map_or(0, |typ| typ.encoded_len())
+          + len_prefix_len(close.reason_phrase.len())
+          + close.reason_phrase.len()
+      }
+      Frame::Datagram(datagram) => len_prefix_len(datagram.data.len()) + datagram.data.len(),
+      Frame::AckFrequency(freq) => {
+        freq.seq_num.encoded_len()
+          + freq.ack_eliciting_threshold.encoded_len()
+          + freq.request_max_ack_delay.encoded_len()
+          + freq.reordering_threshold.encoded_len()
+      }
+      Frame::Extension(ext) => ext.body.len(),
+    };
+    self.frame_type().encoded_len() + body
+  }
+
+  pub fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    self.frame_type().encode(dst)?;
+    match self {
+      Frame::Padding | Frame::Ping | Frame::HandshakeDone | Frame::ImmediateAck => {}
+      Frame::Ack(ack) => {
+        ack.largest_acked.encode(dst)?;
+        ack.ack_delay.encode(dst)?;
+        VarInt::try_from(ack.ack_ranges.len())?.encode(dst)?;
+        ack.first_ack_range.encode(dst)?;
+        for range in &ack.ack_ranges {
+          range.gap.encode(dst)?;
+          range.range_length.encode(dst)?;
+        }
+        if let Some(ecn) = &ack.ecn_counts {
+          ecn.ect0.encode(dst)?;
+          ecn.ect1.encode(dst)?;
+          ecn.ce.encode(dst)?;
+        }
+      }
+      Frame::ResetStream(reset) => {
+        reset.stream_id.encode(dst)?;
+        reset.err_code.encode(dst)?;
+        reset.final_size.encode(dst)?;
+      }
+      Frame::StopSending(stop) => {
+        stop.stream_id.encode(dst)?;
+        stop.err_code.encode(dst)?;
+      }
+      Frame::Crypto(crypto) => {
+        VarInt::try_from(crypto.offset)?.encode(dst)?;
+        encode_len_prefixed(crypto.data, dst)?;
+      }
+      Frame::NewToken(new_token) => encode_len_prefixed(new_token.token, dst)?,
+      Frame::Stream(stream) => {
+        stream.stream_id.encode(dst)?;
+        if stream.offset != 0 {
+          VarInt::try_from(stream.offset)?.encode(dst)?;
+        }
+        if stream.has_len {
+          encode_len_prefixed(stream.data, dst)?;
+        } else {
+          dst.write_all(stream.data)?;
+        }
+      }
+      Frame::MaxData(max) => max.max_data.encode(dst)?,
+      Frame::MaxStreamData(max) => {
+        max.stream_id.encode(dst)?;
+        max.max_stream_data.encode(dst)?;
+      }
+      Frame::MaxStreams(max) => max.max_streams.encode(dst)?,
+      Frame::DataBlocked(blocked) => blocked.max_data.encode(dst)?,
+      Frame::StreamDataBlocked(blocked) => {
+        blocked.stream_id.encode(dst)?;
+        blocked.max_stream_data.encode(dst)?;
+      }
+      Frame::StreamsBlocked(blocked) => blocked.max_streams.encode(dst)?,
+      Frame::NewConnectionId(new_cid) => {
+        new_cid.seq_num.encode(dst)?;
+        new_cid.retire_prior_to.encode(dst)?;
+        new_cid.cid.encode(dst)?;
+        dst.write_u128::<NetworkEndian>(new_cid.stateless_reset_token)?;
+      }
+      Frame::RetireConnectionId(retire) => retire.seq_num.encode(dst)?,
+      Frame::PathChallenge(challenge) => dst.write_u64::<NetworkEndian>(challenge.data)?,
+      Frame::PathResponse(response) => dst.write_u64::<NetworkEndian>(response.data)?,
+      Frame::ConnectionClose(close) => {
+        close.err_code.encode(dst)?;
+        if let Some(typ) = &close.frame_type {
+          typ.encode(dst)?;
+        }
+        encode_len_prefixed(close.reason_phrase, dst)?;
+      }
+      Frame::Datagram(datagram) => encode_len_prefixed(datagram.data, dst)?,
+      Frame::AckFrequency(freq) => {
+        freq.seq_num.encode(dst)?;
+        freq.ack_eliciting_threshold.encode(dst)?;
+        freq.request_max_ack_delay.encode(dst)?;
+        freq.reordering_threshold.encode(dst)?;
+      }
+      Frame::Extension(ext) => dst.write_all(ext.body)?,
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::wire::extension::ExtensionCodec;
+
+  fn varint(v: u32) -> VarInt {
+    v.into()
+  }
+
+  fn sid(v: u32) -> StreamId {
+    varint(v).into()
+  }
+
+  fn cid(bytes: &[u8]) -> ConnectionId {
+    ConnectionId::new(bytes).unwrap()
+  }
+
+  fn round_trip(frame: Frame) -> Vec<u8> {
+    let mut buf = Vec::new();
+    frame.encode(&mut buf).unwrap();
+    assert_eq!(buf.len(), frame.encoded_len());
+    let (parsed, rem) = Frame::parse(&buf).unwrap();
+    assert_eq!(parsed, frame);
+    assert!(rem.is_empty());
+    buf
+  }
+
+  #[test]
+  fn frame_padding_round_trips() {
+    assert_eq!(round_trip(Frame::Padding), [0x00]);
+  }
+
+  #[test]
+  fn frame_ping_round_trips() {
+    assert_eq!(round_trip(Frame::Ping), [0x01]);
+  }
+
+  #[test]
+  fn frame_ack_round_trips() {
+    let buf = round_trip(Frame::Ack(Ack {
+      largest_acked: varint(0x1234),
+      ack_delay: varint(10),
+      first_ack_range: varint(3),
+      ack_ranges: vec![
+        AckRange {
+          gap: varint(1),
+          range_length: varint(0),
+        },
+        AckRange {
+          gap: varint(100),
+          range_length: varint(5),
+        },
+      ],
+      ecn_counts: None,
+    }));
+    assert_eq!(
+      buf,
+      [0x02, 0x52, 0x34, 0x0a, 0x02, 0x03, 0x01, 0x00, 0x40, 0x64, 0x05]
+    );
+  }
+
+  #[test]
+  fn frame_ack_ranges_descend() {
+    let Frame::Ack(ack) = Frame::parse(&[
+      0x02, 0x52, 0x34, 0x0a, 0x02, 0x03, 0x01, 0x00, 0x40, 0x64, 0x05,
+    ])
+    .unwrap()
+    .0
+    else {
+      panic!("Not an ACK frame");
+    };
+    let ranges = ack.ranges().collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(ranges, [0x1231..=0x1234, 0x122e..=0x122e, 0x11c3..=0x11c8]);
+    assert_eq!(ack.ack_delay_duration(3), Duration::from_micros(80));
+    assert_eq!(ack.ack_delay_duration(0), Duration::from_micros(10));
+  }
+
+  #[test]
+  fn frame_ack_negative_range_is_frame_encoding_error() {
+    let mut ack = Ack {
+      largest_acked: varint(5),
+      ack_delay: varint(0),
+      first_ack_range: varint(2),
+      ack_ranges: vec![AckRange {
+        gap: varint(1),
+        range_length: varint(0),
+      }],
+      ecn_counts: None,
+    };
+    assert_eq!(
+      ack.ranges().collect::<Result<Vec<_>>>().unwrap(),
+      [3..=5, 0..=0]
+    );
+
+    ack.ack_ranges[0].gap = varint(2);
+    let ranges = ack.ranges().collect::<Vec<_>>();
+    assert_eq!(ranges.len(), 2);
+    let err = ranges.into_iter().last().unwrap().unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+
+    // Rejected while parsing as well
+    let mut buf = Vec::new();
+    Frame::Ack(ack).encode(&mut buf).unwrap();
+    let err = Frame::parse(&buf).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+
+    let ack = Ack {
+      largest_acked: varint(5),
+      ack_delay: varint(0),
+      first_ack_range: varint(6),
+      ack_ranges: vec![],
+      ecn_counts: None,
+    };
+    assert!(ack.ranges().next().unwrap().is_err());
+  }
+
+  #[test]
+  fn frame_ack_from_packet_numbers() {
+    let packet_numbers = BTreeSet::from([0, 1, 2, 5, 9, 10]);
+    let ack = Ack::new(&packet_numbers, Duration::from_micros(1000), 3).unwrap();
+    assert_eq!(ack.largest_acked, varint(10));
+    assert_eq!(ack.ack_delay, varint(125));
+    assert_eq!(ack.ack_ranges.len(), 2);
+    assert_eq!(ack.first_ack_range, varint(1));
+    assert_eq!(
+      ack.ranges().collect::<Result<Vec<_>>>().unwrap(),
+      [9..=10, 5..=5, 0..=2]
+    );
+    round_trip(Frame::Ack(ack));
+
+    assert!(Ack::new(&BTreeSet::new(), Duration::ZERO, 3).is_err());
+  }
+
+  #[test]
+  fn frame_ack_ecn_round_trips() {
+    let buf = round_trip(Frame::Ack(Ack {
+      largest_acked: varint(7),
+      ack_delay: varint(0),
+      first_ack_range: varint(7),
+      ack_ranges: vec![],
+      ecn_counts: Some(EcnCounts {
+        ect0: varint(1),
+        ect1: varint(2),
+        ce: varint(70_000),
+      }),
+    }));
+    assert_eq!(
+      buf,
+      [0x03, 0x07, 0x00, 0x00, 0x07, 0x01, 0x02, 0x80, 0x01, 0x11, 0x70]
+    );
+  }
+
+  #[test]
+  fn frame_reset_stream_round_trips() {
+    round_trip(Frame::ResetStream(ResetStream {
+      stream_id: sid(4),
+      err_code: varint(0x100),
+      final_size: varint(1 << 20),
+    }));
+  }
+
+  #[test]
+  fn frame_stop_sending_round_trips() {
+    round_trip(Frame::StopSending(StopSending {
+      stream_id: sid(3),
+      err_code: varint(42),
+    }));
+  }
+
+  #[test]
+  fn frame_crypto_round_trips() {
+    let buf = round_trip(Frame::Crypto(Crypto {
+      offset: 0,
+      data: &[0xde, 0xad],
+    }));
+    assert_eq!(buf, [0x06, 0x00, 0x02, 0xde, 0xad]);
+
+    let buf = round_trip(Frame::Crypto(Crypto {
+      offset: 300,
+      data: &[0xde, 0xad],
+    }));
+    assert_eq!(buf, [0x06, 0x41, 0x2c, 0x02, 0xde, 0xad]);
+  }
+
+  #[test]
+  fn frame_crypto_data_is_not_skipped_by_offset() {
+    let buf = [0x06, 0x03, 0x02, 0xaa, 0xbb, 0xcc];
+    let (frame, rem) = Frame::parse(&buf).unwrap();
+    assert_eq!(
+      frame,
+      Frame::Crypto(Crypto {
+        offset: 3,
+        data: &[0xaa, 0xbb],
+      })
+    );
+    assert_eq!(rem, [0xcc]);
+  }
+
+  #[test]
+  fn frame_crypto_beyond_max_offset_fails() {
+    // Offset 2^62 - 1 with a single byte of data
+    let buf = [
+      0x06, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0xaa,
+    ];
+    assert!(Frame::parse(&buf).is_err());
+  }
+
+  #[test]
+  fn frame_new_token_round_trips() {
+    round_trip(Frame::NewToken(NewToken {
+      token: &[1, 2, 3, 4, 5],
+    }));
+  }
+
+  #[test]
+  fn frame_stream_round_trips() {
+    let buf = round_trip(Frame::Stream(Stream {
+      stream_id: sid(8),
+      offset: 0,
+      has_len: true,
+      fin: false,
+      data: b"hello",
+    }));
+    assert_eq!(buf, [0x0a, 0x08, 0x05, b'h', b'e', b'l', b'l', b'o']);
+
+    let buf = round_trip(Frame::Stream(Stream {
+      stream_id: sid(8),
+      offset: 0,
+      has_len: true,
+      fin: true,
+      data: &[],
+    }));
+    assert_eq!(buf, [0x0b, 0x08, 0x00]);
+
+    let buf = round_trip(Frame::Stream(Stream {
+      stream_id: sid(8),
+      offset: 64,
+      has_len: true,
+      fin: true,
+      data: &[0x01],
+    }));
+    assert_eq!(buf, [0x0f, 0x08, 0x40, 0x40, 0x01, 0x01]);
+
+    // The last frame in a packet can leave out its length
+    let buf = round_trip(Frame::Stream(Stream {
+      stream_id: sid(8),
+      offset: 0,
+      has_len: false,
+      fin: false,
+      data: b"hi",
+    }));
+    assert_eq!(buf, [0x08, 0x08, b'h', b'i']);
+  }
+
+  #[test]
+  fn frame_stream_beyond_max_offset_fails() {
+    let buf = [
+      0x0e, 0x04, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0xaa,
+    ];
+    assert!(Frame::parse(&buf).is_err());
+
+    // An empty frame at the maximum offset is fine
+    let buf = [
+      0x0e, 0x04, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+    ];
+    assert!(Frame::parse(&buf).is_ok());
+  }
+
+  #[test]
+  fn frame_stream_type_bits_parse() {
+    for typ in 0x08..=0x0fu8 {
+      let mut buf = vec![typ, 0x04];
+      if typ & 0b100 != 0 {
+        buf.push(0x05);
+      }
+      if typ & 0b010 != 0 {
+        buf.push(0x02);
+      }
+      buf.extend_from_slice(&[0xaa, 0xbb]);
+
+      let (frame, rem) = Frame::parse(&buf).unwrap();
+      assert_eq!(
+        frame,
+        Frame::Stream(Stream {
+          stream_id: sid(4),
+          offset: if typ & 0b100 != 0 { 5 } else { 0 },
+          has_len: typ & 0b010 != 0,
+          fin: typ & 0b001 != 0,
+          data: &[0xaa, 0xbb],
+        })
+      );
+      assert!(rem.is_empty());
+
+      // Every combination of bits is encoded back the same way
+      let mut encoded = Vec::new();
+      frame.encode(&mut encoded).unwrap();
+      assert_eq!(encoded, buf);
+      assert_eq!(encoded.len(), frame.encoded_len());
+    }
+  }
+
+  #[test]
+  fn frame_max_data_round_trips() {
+    round_trip(Frame::MaxData(MaxData {
+      max_data: varint(u32::MAX),
+    }));
+  }
+
+  #[test]
+  fn frame_max_stream_data_round_trips() {
+    round_trip(Frame::MaxStreamData(MaxStreamData {
+      stream_id: sid(1),
+      max_stream_data: varint(65536),
+    }));
+  }
+
+  #[test]
+  fn frame_max_streams_round_trips() {
+    let buf = round_trip(Frame::MaxStreams(MaxStreams {
+      dir: StreamDir::Bidirectional,
+      max_streams: varint(100),
+    }));
+    assert_eq!(buf[0], 0x12);
+
+    let buf = round_trip(Frame::MaxStreams(MaxStreams {
+      dir: StreamDir::Unidirectional,
+      max_streams: varint(100),
+    }));
+    assert_eq!(buf[0], 0x13);
+  }
+
+  #[test]
+  fn frame_max_streams_beyond_limit_fails() {
+    // 2^60 is the largest allowed value
+    let limit = [0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    for typ in [0x12, 0x13, 0x16, 0x17] {
+      let buf = [&[typ][..], &limit].concat();
+      assert!(Frame::parse(&buf).is_ok());
+
+      let buf = [typ, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+      let err = Frame::parse(&buf).unwrap_err();
+      assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    }
+  }
+
+  #[test]
+  fn frame_data_blocked_round_trips() {
+    round_trip(Frame::DataBlocked(DataBlocked {
+      max_data: varint(16383),
+    }));
+  }
+
+  #[test]
+  fn frame_stream_data_blocked_round_trips() {
+    round_trip(Frame::StreamDataBlocked(StreamDataBlocked {
+      stream_id: sid(2),
+      max_stream_data: varint(16384),
+    }));
+  }
+
+  #[test]
+  fn frame_streams_blocked_round_trips() {
+    let buf = round_trip(Frame::StreamsBlocked(StreamsBlocked {
+      dir: StreamDir::Bidirectional,
+      max_streams: varint(63),
+    }));
+    assert_eq!(buf, [0x16, 0x3f]);
+
+    let buf = round_trip(Frame::StreamsBlocked(StreamsBlocked {
+      dir: StreamDir::Unidirectional,
+      max_streams: varint(63),
+    }));
+    assert_eq!(buf, [0x17, 0x3f]);
+  }
+
+  #[test]
+  fn frame_new_connection_id_round_trips() {
+    round_trip(Frame::NewConnectionId(NewConnectionId {
+      seq_num: varint(1),
+      retire_prior_to: varint(0),
+      cid: cid(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]),
+      stateless_reset_token: 0x0011_2233_4455_6677_8899_aabb_ccdd_eeff,
+    }));
+  }
+
+  #[test]
+  fn frame_retire_connection_id_round_trips() {
+    round_trip(Frame::RetireConnectionId(RetireConnectionId {
+      seq_num: varint(9),
+    }));
+  }
+
+  #[test]
+  fn frame_path_challenge_round_trips() {
+    let buf = round_trip(Frame::PathChallenge(PathChallenge {
+      data: 0x0102_0304_0506_0708,
+    }));
+    assert_eq!(buf, [0x1a, 1, 2, 3, 4, 5, 6, 7, 8]);
+  }
+
+  #[test]
+  fn frame_path_response_round_trips() {
+    round_trip(Frame::PathResponse(PathResponse {
+      data: 0x0102_0304_0506_0708,
+    }));
+  }
+
+  #[test]
+  fn frame_connection_close_round_trips() {
+    let buf = round_trip(Frame::ConnectionClose(ConnectionClose {
+      err_code: varint(0x0a),
+      frame_type: Some(varint(0x08)),
+      reason_phrase: b"bad",
+    }));
+    assert_eq!(buf, [0x1c, 0x0a, 0x08, 0x03, b'b', b'a', b'd']);
+
+    let buf = round_trip(Frame::ConnectionClose(ConnectionClose {
+      err_code: varint(0x1234),
+      frame_type: None,
+      reason_phrase: &[],
+    }));
+    assert_eq!(buf, [0x1d, 0x52, 0x34, 0x00]);
+  }
+
+  #[test]
+  fn frame_handshake_done_round_trips() {
+    assert_eq!(round_trip(Frame::HandshakeDone), [0x1e]);
+  }
+
+  #[test]
+  fn frame_datagram_round_trips() {
+    let buf = round_trip(Frame::Datagram(Datagram { data: b"state" }));
+    assert_eq!(buf, [0x31, 0x05, b's', b't', b'a', b't', b'e']);
+  }
+
+  #[test]
+  fn frame_datagram_without_length_takes_rest() {
+    let buf = [0x30, 0xaa, 0xbb, 0x01];
+    let (frame, rem) = Frame::parse(&buf).unwrap();
+    assert_eq!(
+      frame,
+      Frame::Datagram(Datagram {
+        data: &[0xaa, 0xbb, 0x01],
+      })
+    );
+    assert!(rem.is_empty());
+
+    let buf = [0x31, 0x01, 0xaa, 0x01];
+    let (frame, rem) = Frame::parse(&buf).unwrap();
+    assert_eq!(frame, Frame::Datagram(Datagram { data: &[0xaa] }));
+    assert_eq!(rem, [0x01]);
+  }
+
+  #[test]
+  fn frame_ack_frequency_round_trips() {
+    let buf = round_trip(Frame::AckFrequency(AckFrequency {
+      seq_num: varint(3),
+      ack_eliciting_threshold: varint(9),
+      request_max_ack_delay: varint(25_000),
+      reordering_threshold: varint(0),
+    }));
+    assert_eq!(buf, [0x40, 0xaf, 0x03, 0x09, 0x80, 0x00, 0x61, 0xa8, 0x00]);
+  }
+
+  #[test]
+  fn frame_immediate_ack_round_trips() {
+    assert_eq!(round_trip(Frame::ImmediateAck), [0x1f]);
+  }
+
+  #[test]
+  fn frame_ack_eliciting() {
+    assert!(Frame::Ping.is_ack_eliciting());
+    assert!(Frame::ImmediateAck.is_ack_eliciting());
+    assert!(!Frame::Padding.is_ack_eliciting());
+    let close = ConnectionClose {
+      err_code: varint(0),
+      frame_type: None,
+      reason_phrase: &[],
+    };
+    assert!(!Frame::ConnectionClose(close).is_ack_eliciting());
+  }
+
+  #[test]
+  fn frame_packet_type_permissions() {
+    use EncryptionLevel::*;
+
+    let levels = [Initial, ZeroRtt, Handshake, OneRtt];
+    let allowed = |frame: &Frame| levels.map(|level| frame.is_allowed_in(level));
+    assert_eq!(allowed(&Frame::Ping), [true; 4]);
+    let crypto = Frame::Crypto(Crypto {
+      offset: 0,
+      data: &[],
+    });
+    assert_eq!(allowed(&crypto), [true, false, true, true]);
+    let stream = Frame::Stream(Stream {
+      stream_id: StreamId::new(Side::Client, StreamDir::Bidirectional, 0).unwrap(),
+      offset: 0,
+      has_len: true,
+      fin: false,
+      data: &[],
+    });
+    assert_eq!(allowed(&stream), [false, true, false, true]);
+    assert_eq!(allowed(&Frame::HandshakeDone), [false, false, false, true]);
+
+    let close = |frame_type| {
+      Frame::ConnectionClose(ConnectionClose {
+        err_code: varint(0),
+        frame_type,
+        reason_phrase: &[],
+      })
+    };
+    assert_eq!(allowed(&close(Some(varint(0)))), [true; 4]);
+    assert_eq!(allowed(&close(None)), [false, true, false, true]);
+  }
+
+  #[test]
+  fn frame_check_recv_is_protocol_violation() {
+    let err = Frame::ImmediateAck
+      .check_recv(EncryptionLevel::Handshake, Side::Server)
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert!(matches!(
+      err,
+      Error::Transport {
+        frame_type: Some(0x1f),
+        ..
+      }
+    ));
+
+    let done = Frame::HandshakeDone;
+    assert!(done
+      .check_recv(EncryptionLevel::OneRtt, Side::Server)
+      .is_ok());
+    let err = done
+      .check_recv(EncryptionLevel::OneRtt, Side::Client)
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+  }
+
+  #[test]
+  fn frame_unknown_type_is_frame_encoding_error() {
+    let err = Frame::parse(&[0x40, 0x21]).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    assert!(matches!(
+      err,
+      Error::Transport {
+        frame_type: Some(0x21),
+        ..
+      }
+    ));
+  }
+
+  #[test]
+  fn frame_truncated_is_frame_encoding_error() {
+    // Reset Stream missing its final size
+    let err = Frame::parse(&[0x04, 0x01, 0x02]).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    assert!(matches!(
+      err,
+      Error::Transport {
+        frame_type: Some(0x04),
+        ..
+      }
+    ));
+
+    let err = Frame::parse(&[0x06, 0x00, 0x05, 0xaa]).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+  }
+
+  #[test]
+  fn frame_connection_close_from_error() {
+    let err = Frame::parse(&[0x40, 0x21]).unwrap_err();
+    assert_eq!(
+      ConnectionClose::from(&err),
+      ConnectionClose {
+        err_code: varint(0x07),
+        frame_type: Some(varint(0x21)),
+        reason_phrase: b"Unknown frame type",
+      }
+    );
+
+    let err = Error::transport(TransportErrorCode::CryptoError(0x28), "handshake failure");
+    assert_eq!(
+      ConnectionClose::from(&err),
+      ConnectionClose {
+        err_code: varint(0x0128),
+        frame_type: Some(varint(0)),
+        reason_phrase: b"handshake failure",
+      }
+    );
+
+    let err = Error::application(0x42, "bye");
+    assert_eq!(
+      ConnectionClose::from(&err),
+      ConnectionClose {
+        err_code: varint(0x42),
+        frame_type: None,
+        reason_phrase: b"bye",
+      }
+    );
+
+    let err = Error::from(std::io::Error::other("socket closed"));
+    assert_eq!(
+      ConnectionClose::from(&err),
+      ConnectionClose {
+        err_code: varint(0x01),
+        frame_type: Some(varint(0)),
+        reason_phrase: &[],
+      }
+    );
+  }
+
+  #[test]
+  fn frame_encode_into_short_slice_fails() {
+    let mut buf = [0u8; 4];
+    let frame = Frame::PathChallenge(PathChallenge { data: 1 });
+    assert!(frame.encode(&mut &mut buf[..]).is_err());
+  }
+
+  #[test]
+  fn frame_parse_error_names_field() {
+    // Length claims 5 bytes of crypto data but only 1 follows
+    let err = Frame::parse(&[0x06, 0x00, 0x05, 0xaa]).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    assert!(matches!(
+      err,
+      Error::Transport {
+        frame_type: Some(0x06),
+        ..
+      }
+    ));
+    assert_eq!(
+      err.context(),
+      Some(&ParseContext {
+        packet_type: None,
+        field: Some("Crypto Data"),
+        offset: Some(3),
+      })
+    );
+
+    // Offsets are from the start of the payload when parsing many frames
+    let buf = [0x01, 0x00, 0x1c, 0x0a, 0x06];
+    let limits = ParseLimits::default();
+    let err = Frame::parse_multiple(&buf, &ExtensionRegistry::default(), &limits)
+      .find_map(Result::err)
+      .unwrap();
+    assert_eq!(
+      err.to_string(),
+      "FRAME_ENCODING_ERROR in frame 0x1c field Reason Phrase Length at offset 5: failed to \
+       fill whole buffer"
+    );
+  }
+
+  #[test]
+  fn frame_parse_multiple_round_trips() {
+    let frames = [
+      Frame::Ping,
+      Frame::MaxData(MaxData {
+        max_data: varint(1000),
+      }),
+      Frame::Padding,
+      Frame::HandshakeDone,
+    ];
+    let mut buf = Vec::new();
+    for frame in &frames {
+      frame.encode(&mut buf).unwrap();
+    }
+    let limits = ParseLimits::default();
+    let parsed = Frame::parse_multiple(&buf, &ExtensionRegistry::default(), &limits)
+      .collect::<Result<Vec<_>>>()
+      .unwrap();
+    assert_eq!(parsed, frames);
+  }
+
+  fn limits(max: usize) -> ParseLimits {
+    ParseLimits {
+      max_ack_ranges: max,
+      max_token_len: max,
+      max_reason_phrase_len: max,
+      max_frames: max,
+    }
+  }
+
+  #[test]
+  fn frame_ack_ranges_over_limit_fail_fast() {
+    let extensions = ExtensionRegistry::default();
+    // Claims 2^62 - 1 ranges without carrying any
+    let buf = [
+      0x02, 0x05, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+    ];
+    let err = Frame::parse(&buf).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+
+    let buf = [0x02, 0x05, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00];
+    assert!(Frame::parse_with_limits(&buf, &extensions, &limits(2)).is_ok());
+    let err = Frame::parse_with_limits(&buf, &extensions, &limits(1)).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+  }
+
+  #[test]
+  fn frame_lengths_over_limit_fail() {
+    let extensions = ExtensionRegistry::default();
+    let new_token = [0x07, 0x03, 0x01, 0x02, 0x03];
+    let close = [0x1d, 0x00, 0x03, b'b', b'y', b'e'];
+    for buf in [&new_token[..], &close[..]] {
+      assert!(Frame::parse_with_limits(buf, &extensions, &limits(3)).is_ok());
+      let err = Frame::parse_with_limits(buf, &extensions, &limits(2)).unwrap_err();
+      assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    }
+  }
+
+  #[test]
+  fn frame_count_over_limit_ends_parsing() {
+    let extensions = ExtensionRegistry::default();
+    // Padding doesn't count towards the limit
+    let buf = [0x01, 0x00, 0x00, 0x01, 0x01];
+    let parsed = Frame::parse_multiple(&buf, &extensions, &limits(2)).collect::<Vec<_>>();
+    assert_eq!(parsed.len(), 5);
+    let err = parsed.into_iter().last().unwrap().unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+
+    let limits = limits(3);
+    let parsed = Frame::parse_multiple(&buf, &extensions, &limits);
+    assert!(parsed.collect::<Result<Vec<_>>>().is_ok());
+  }
+
+  #[test]
+  fn frame_parse_multiple_ends_after_error() {
+    let extensions = ExtensionRegistry::default();
+    // PING, then a frame of an unknown type followed by more PINGs
+    let buf = [0x01, 0x40, 0x21, 0x01, 0x01];
+    let limits = ParseLimits::default();
+    let mut parsed = Frame::parse_multiple(&buf, &extensions, &limits);
+    assert_eq!(parsed.next().unwrap().unwrap(), Frame::Ping);
+    let err = parsed.next().unwrap().unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    assert!(parsed.next().is_none());
+
+    // Skipping errors doesn't keep returning the same one
+    let frames = Frame::parse_multiple(&buf, &extensions, &limits).filter_map(Result::ok);
+    assert_eq!(frames.collect::<Vec<_>>(), [Frame::Ping]);
+  }
+
+  // Body is a single length-prefixed field
+  struct LengthPrefixed;
+
+  impl ExtensionCodec for LengthPrefixed {
+    fn body_len(&self, _frame_type: u64, mut data: &[u8]) -> Result<usize> {
+      let prefix = VarInt::parse(&mut data)?;
+      Ok(prefix.encoded_len() + usize::try_from(prefix)?)
+    }
+  }
+
+  fn extensions(enabled: bool) -> ExtensionRegistry {
+    let mut extensions = ExtensionRegistry::default();
+    extensions
+      .register(0x4242, 0x99, Arc::new(LengthPrefixed))
+      .unwrap();
+    if enabled {
+      extensions.negotiate([0x99]);
+    }
+    extensions
+  }
+
+  #[test]
+  fn frame_extension_round_trips() {
+    let buf = [0x80, 0x00, 0x42, 0x42, 0x02, 0xaa, 0xbb, 0x01];
+    let extensions = extensions(true);
+    let (frame, rem) = Frame::parse_with_extensions(&buf, &extensions).unwrap();
+    assert_eq!(
+      frame,
+      Frame::Extension(Extension {
+        frame_type: varint(0x4242),
+        body: &[0x02, 0xaa, 0xbb],
+      })
+    );
+    assert_eq!(rem, [0x01]);
+
+    let mut encoded = Vec::new();
+    frame.encode(&mut encoded).unwrap();
+    assert_eq!(encoded, buf[..7]);
+    assert_eq!(frame.encoded_len(), 7);
+  }
+
+  #[test]
+  fn frame_extension_not_negotiated_fails() {
+    let buf = [0x80, 0x00, 0x42, 0x42, 0x00];
+    let err = Frame::parse_with_extensions(&buf, &extensions(false)).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    assert!(Frame::parse(&buf).is_err());
+  }
+
+  #[test]
+  fn frame_extension_truncated_fails() {
+    let buf = [0x80, 0x00, 0x42, 0x42, 0x05, 0xaa];
+    let err = Frame::parse_with_extensions(&buf, &extensions(true)).unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+  }
 }
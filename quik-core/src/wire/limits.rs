@@ -0,0 +1,25 @@
+// Caps on what a single packet can make the parser hold on to. Lengths and
+// counts on the wire are chosen by the peer, so anything sized by them is
+// checked against these before it is allocated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLimits {
+  // ACK Range fields after the First ACK Range of an ACK frame
+  pub max_ack_ranges: usize,
+  // Tokens of NEW_TOKEN frames and Initial packets
+  pub max_token_len: usize,
+  // Reason Phrase of CONNECTION_CLOSE frames
+  pub max_reason_phrase_len: usize,
+  // Frames other than PADDING in a single packet
+  pub max_frames: usize,
+}
+
+impl Default for ParseLimits {
+  fn default() -> Self {
+    Self {
+      max_ack_ranges: 256,
+      max_token_len: 1024,
+      max_reason_phrase_len: 1024,
+      max_frames: 1024,
+    }
+  }
+}
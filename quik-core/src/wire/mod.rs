@@ -1,7 +1,13 @@
 mod common;
+pub mod extension;
 pub mod frame;
+pub mod limits;
 pub mod packet;
+pub mod transport_parameters;
 
 pub use common::*;
+pub use extension::ExtensionRegistry;
 pub use frame::Frame;
+pub use limits::ParseLimits;
 pub use packet::Packet;
+pub use transport_parameters::TransportParameters;
@@ -1,13 +1,112 @@
-use std::io;
-
 use quik_util::*;
+use rand::RngCore;
 
-use crate::crypto::Crypto;
-use crate::wire::{ConnectionId, PacketNumber, VarInt};
+use crate::crypto::{Crypto, EncryptionLevel, AEAD_TAG_LEN, HP_SAMPLE_LEN};
+use crate::wire::{
+  offset_in, parse_field, ConnectionId, PacketNumber, PacketNumberSpace, PacketNumberSpaces,
+  ParseLimits, Side, VarInt, VERSION_2,
+};
 // Packets handled by the middle layer
 
+const FIXED_BIT: u8 = 0b0100_0000;
+
+// Transport parameter allowing the peer to send packets with the fixed bit
+// cleared, so that middleboxes can't come to depend on it
+// https://datatracker.ietf.org/doc/html/rfc9287#name-the-grease_quic_bit-transpo
+pub const GREASE_QUIC_BIT: u64 = 0x2ab2;
+
+// Packets with the fixed bit cleared aren't QUIC packets, unless we told the
+// peer it could grease it
+// https://datatracker.ietf.org/doc/html/rfc9000#section-17.2-8.4.1
+fn check_fixed_bit(first_byte: u8, grease_quic_bit: bool) -> Result<()> {
+  if first_byte & FIXED_BIT == 0 && !grease_quic_bit {
+    Err(header_error("Fixed bit not set").in_field("Fixed Bit", 0))?;
+  }
+  Ok(())
+}
+
+// Malformed headers are a PROTOCOL_VIOLATION, though the packets are usually
+// dropped rather than closing the connection
+fn header_error(reason: &'static str) -> Error {
+  Error::transport(TransportErrorCode::ProtocolViolation, reason)
+}
+
+// `start` is the start of the packet
+fn field<'a, T>(
+  start: &[u8],
+  data: &mut &'a [u8],
+  name: &'static str,
+  parse: impl FnOnce(&mut &'a [u8]) -> Result<T>,
+) -> Result<T> {
+  let code = TransportErrorCode::ProtocolViolation;
+  parse_field(code, start, data, name, parse)
+}
+
+// Packet type `data` starts with, as far as its header can be read
+fn type_name(data: &[u8]) -> Option<&'static str> {
+  let first_byte = *data.first()?;
+  if first_byte >> 7 == 0 {
+    return Some("1-RTT");
+  }
+  let version = u32::from_be_bytes(*data.get(1..)?.first_chunk()?);
+  if version == 0 {
+    return Some("Version Negotiation");
+  }
+  Some(LongPacketType::from_bits((first_byte >> 4) & 0b11, version).name())
+}
+
+// Packet types of the long header, whose codepoints depend on the version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LongPacketType {
+  Initial,
+  ZeroRtt,
+  Handshake,
+  Retry,
+}
+
+impl LongPacketType {
+  // Versions other than v2 are read as v1, as that's all they have in common
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-long-header-packet-types
+  // https://datatracker.ietf.org/doc/html/rfc9369#name-long-header-packet-types
+  fn from_bits(bits: u8, version: u32) -> Self {
+    let v1_bits = match version {
+      VERSION_2 => bits.wrapping_sub(1),
+      _ => bits,
+    };
+    match v1_bits & 0b11 {
+      0b00 => LongPacketType::Initial,
+      0b01 => LongPacketType::ZeroRtt,
+      0b10 => LongPacketType::Handshake,
+      _ => LongPacketType::Retry,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      LongPacketType::Initial => "Initial",
+      LongPacketType::ZeroRtt => "0-RTT",
+      LongPacketType::Handshake => "Handshake",
+      LongPacketType::Retry => "Retry",
+    }
+  }
+
+  fn bits(self, version: u32) -> u8 {
+    let v1_bits = match self {
+      LongPacketType::Initial => 0b00,
+      LongPacketType::ZeroRtt => 0b01,
+      LongPacketType::Handshake => 0b10,
+      LongPacketType::Retry => 0b11,
+    };
+    match version {
+      VERSION_2 => (v1_bits + 1) & 0b11,
+      _ => v1_bits,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Packet<'a> {
-  VersionNegotiation(VersionNegotiation),
+  VersionNegotiation(VersionNegotiation<'a>),
   Initial(Initial<'a>),
   ZeroRTT(ZeroRTT),
   Handshake(Handshake),
@@ -16,25 +115,93 @@ pub enum Packet<'a> {
 }
 
 impl Packet<'_> {
-  pub fn dst_cid(&self) -> &ConnectionId {
+  // Packet type as named in the RFC
+  pub fn type_name(&self) -> &'static str {
     match self {
-      Packet::VersionNegotiation(vn) => &vn.dst_cid,
-      Packet::Initial(i) => &i.dst_cid,
-      Packet::ZeroRTT(z) => &z.dst_cid,
-      Packet::Handshake(h) => &h.dst_cid,
-      Packet::Retry(r) => &r.dst_cid,
-      Packet::OneRtt(o) => &o.dst_cid,
+      Packet::VersionNegotiation(_) => "Version Negotiation",
+      Packet::Initial(_) => LongPacketType::Initial.name(),
+      Packet::ZeroRTT(_) => LongPacketType::ZeroRtt.name(),
+      Packet::Handshake(_) => LongPacketType::Handshake.name(),
+      Packet::Retry(_) => LongPacketType::Retry.name(),
+      Packet::OneRtt(_) => "1-RTT",
+    }
+  }
+
+  pub fn dst_cid(&self) -> &[u8] {
+    match self {
+      Packet::VersionNegotiation(vn) => vn.dst_cid,
+      Packet::Initial(i) => i.dst_cid.as_bytes(),
+      Packet::ZeroRTT(z) => z.dst_cid.as_bytes(),
+      Packet::Handshake(h) => h.dst_cid.as_bytes(),
+      Packet::Retry(r) => r.dst_cid.as_bytes(),
+      Packet::OneRtt(o) => o.dst_cid.as_bytes(),
+    }
+  }
+
+  // None for packets that aren't protected
+  pub fn encryption_level(&self) -> Option<EncryptionLevel> {
+    match self {
+      Packet::Initial(_) => Some(EncryptionLevel::Initial),
+      Packet::ZeroRTT(_) => Some(EncryptionLevel::ZeroRtt),
+      Packet::Handshake(_) => Some(EncryptionLevel::Handshake),
+      Packet::OneRtt(_) => Some(EncryptionLevel::OneRtt),
+      Packet::VersionNegotiation(_) | Packet::Retry(_) => None,
+    }
+  }
+
+  // Short headers don't carry a version
+  pub fn version(&self) -> Option<u32> {
+    match self {
+      Packet::VersionNegotiation(_) => Some(0),
+      Packet::Initial(i) => Some(i.version),
+      Packet::ZeroRTT(z) => Some(z.version),
+      Packet::Handshake(h) => Some(h.version),
+      Packet::Retry(r) => Some(r.version),
+      Packet::OneRtt(_) => None,
+    }
+  }
+
+  // None for packets that aren't numbered
+  pub fn space(&self) -> Option<PacketNumberSpace> {
+    match self {
+      Packet::Initial(_) => Some(PacketNumberSpace::Initial),
+      Packet::Handshake(_) => Some(PacketNumberSpace::Handshake),
+      Packet::ZeroRTT(_) | Packet::OneRtt(_) => Some(PacketNumberSpace::ApplicationData),
+      Packet::VersionNegotiation(_) | Packet::Retry(_) => None,
+    }
+  }
+
+  pub fn packet_number(&self) -> Option<PacketNumber> {
+    match self {
+      Packet::Initial(i) => Some(i.packet_number),
+      Packet::ZeroRTT(z) => Some(z.packet_number),
+      Packet::Handshake(h) => Some(h.packet_number),
+      Packet::OneRtt(o) => Some(o.packet_number),
+      Packet::VersionNegotiation(_) | Packet::Retry(_) => None,
     }
   }
 }
 
-pub struct VersionNegotiation {
-  pub src_cid: ConnectionId,
-  pub dst_cid: ConnectionId,
-  // TODO ew this isn't zero copy
-  pub supported_versions: Vec<u32>,
+// Connection IDs are kept as is since they can be longer than 20 bytes when
+// the version isn't one we support
+// https://datatracker.ietf.org/doc/html/rfc8999#section-5.1-5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionNegotiation<'a> {
+  pub src_cid: &'a [u8],
+  pub dst_cid: &'a [u8],
+  pub supported_versions: &'a [[u8; 4]],
 }
 
+impl VersionNegotiation<'_> {
+  pub fn versions(&self) -> impl Iterator<Item = u32> + '_ {
+    self
+      .supported_versions
+      .iter()
+      .map(|version| u32::from_be_bytes(*version))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Initial<'a> {
   pub src_cid: ConnectionId,
   pub dst_cid: ConnectionId,
@@ -42,102 +209,609 @@ pub struct Initial<'a> {
 
   pub token: &'a [u8],
   // variable length
-  pub packet_number: u32,
+  pub packet_number: PacketNumber,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ZeroRTT {
   pub src_cid: ConnectionId,
   pub dst_cid: ConnectionId,
   pub version: u32,
 
   // variable length
-  pub packet_number: u32,
+  pub packet_number: PacketNumber,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
   pub src_cid: ConnectionId,
   pub dst_cid: ConnectionId,
   pub version: u32,
 
   // variable length
-  pub packet_number: u32,
+  pub packet_number: PacketNumber,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Retry<'a> {
   pub src_cid: ConnectionId,
   pub dst_cid: ConnectionId,
   pub version: u32,
 
   pub retry_token: &'a [u8],
-  pub retry_integrity_tag: u128,
+  pub retry_integrity_tag: [u8; AEAD_TAG_LEN],
+}
+
+impl<'a> Retry<'a> {
+  // Builds a Retry in response to an Initial sent to `original_dst_cid`, with
+  // a valid integrity tag
+  pub fn new(
+    crypto: &impl Crypto,
+    original_dst_cid: &ConnectionId,
+    src_cid: ConnectionId,
+    dst_cid: ConnectionId,
+    version: u32,
+    retry_token: &'a [u8],
+  ) -> Result<Self> {
+    let mut retry = Retry {
+      src_cid,
+      dst_cid,
+      version,
+      retry_token,
+      retry_integrity_tag: [0; AEAD_TAG_LEN],
+    };
+    let mut buf = Vec::new();
+    Packet::Retry(retry.clone()).encode_header(0, None, &mut buf)?;
+    buf.truncate(buf.len() - AEAD_TAG_LEN);
+    retry.retry_integrity_tag =
+      crypto.retry_integrity_tag(version, &retry_pseudo_packet(original_dst_cid, &buf))?;
+    Ok(retry)
+  }
+}
+
+// The Retry integrity tag also covers the Destination Connection ID of the
+// Initial that the Retry responds to
+// https://datatracker.ietf.org/doc/html/rfc9001#name-retry-packet-integrity
+fn retry_pseudo_packet(original_dst_cid: &ConnectionId, retry_without_tag: &[u8]) -> Vec<u8> {
+  let mut pseudo_packet =
+    Vec::with_capacity(original_dst_cid.encoded_len() + retry_without_tag.len());
+  pseudo_packet.push(original_dst_cid.len() as u8);
+  pseudo_packet.extend_from_slice(original_dst_cid.as_bytes());
+  pseudo_packet.extend_from_slice(retry_without_tag);
+  pseudo_packet
 }
 
 // This one actually uses the short header
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OneRtt {
   pub dst_cid: ConnectionId,
   pub spin: u8,
   pub key_phase: u8,
 
   // variable length
-  pub packet_number: u32,
+  pub packet_number: PacketNumber,
+}
+
+// What an endpoint needs to know about a connection to parse and protect its
+// packets
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketContext {
+  pub is_server: bool,
+  // Length of the Connection IDs we issued, needed to parse short headers
+  pub local_cid_len: usize,
+  // Version in use, which short headers don't carry
+  pub version: u32,
+  // Destination Connection ID of the first Initial a client sent, which Retry
+  // packets are authenticated with
+  pub original_dst_cid: Option<ConnectionId>,
+  // Destination Connection ID Initial keys are derived from, which both
+  // endpoints keep using once the server picked its own Connection ID. This is
+  // the Source Connection ID of a Retry if the client received one, and
+  // `original_dst_cid` otherwise.
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-initial-secrets
+  pub initial_dst_cid: Option<ConnectionId>,
+  // Whether we sent grease_quic_bit, allowing the fixed bit to be cleared
+  pub grease_quic_bit: bool,
+  pub limits: ParseLimits,
+}
+
+impl PacketContext {
+  pub fn side(&self) -> Side {
+    if self.is_server {
+      Side::Server
+    } else {
+      Side::Client
+    }
+  }
+
+  // Connection ID to derive the keys of a packet sent to `dst_cid` from. Only
+  // Initial keys depend on it, and until the initial Connection ID is known
+  // it's the one the packet was sent to, as in the first Initial a server
+  // receives.
+  pub fn key_cid<'c>(
+    &'c self,
+    level: EncryptionLevel,
+    dst_cid: &'c ConnectionId,
+  ) -> &'c ConnectionId {
+    let initial_dst_cid = self.initial_dst_cid.as_ref();
+    match initial_dst_cid.or(self.original_dst_cid.as_ref()) {
+      Some(cid) if level == EncryptionLevel::Initial => cid,
+      _ => dst_cid,
+    }
+  }
+}
+
+// The header protection sample starts 4 bytes after the start of the packet
+// number, as if it were always 4 bytes long
+fn hp_sample(pn_and_payload: &[u8]) -> Result<&[u8; HP_SAMPLE_LEN]> {
+  let sample = pn_and_payload
+    .get(4..4 + HP_SAMPLE_LEN)
+    .ok_or("Packet too short for header protection sample")?;
+  Ok(sample.try_into()?)
 }
 
+// Mask over the bits of the first byte that header protection covers
+fn first_byte_hp_bits(first_byte: u8) -> u8 {
+  if first_byte >> 7 != 0 {
+    // Long Header: Reserved (2), Packet Number Length (2)
+    0b0000_1111
+  } else {
+    // Short Header: Reserved (2), Key Phase (1), Packet Number Length (2)
+    0b0001_1111
+  }
+}
+
+// Removes header protection from the first byte and the packet number that
+// `data` starts with, returning the unprotected first byte and packet number
+// https://datatracker.ietf.org/doc/html/rfc9001#name-header-protection-applicati
+fn unprotect_header(
+  mask: [u8; 5],
+  first_byte: u8,
+  data: &mut impl Buffer,
+  largest_received: Option<PacketNumber>,
+) -> Result<(u8, PacketNumber)> {
+  let first_byte = first_byte ^ (mask[0] & first_byte_hp_bits(first_byte));
+  let packet_number_length = 1 + (first_byte & 0b11) as usize;
+
+  let mut buf = [0u8; 4];
+  data.read_exact(&mut buf[4 - packet_number_length..])?;
+  for (b, m) in buf[4 - packet_number_length..].iter_mut().zip(&mask[1..]) {
+    *b ^= m;
+  }
+  let truncated = NetworkEndian::read_u32(&buf);
+  let packet_number = PacketNumber::decode(truncated, packet_number_length, largest_received);
+  Ok((first_byte, packet_number))
+}
+
+// Reserved bits are only checked once header protection is removed and the
+// payload decrypted, as they can't be trusted before that
+// https://datatracker.ietf.org/doc/html/rfc9000#section-17.2-8.10.1
+// https://datatracker.ietf.org/doc/html/rfc9000#section-17.3.1-4.8.1
+fn check_reserved_bits(first_byte: u8) -> Result<()> {
+  let reserved_bits = if first_byte >> 7 != 0 {
+    // Long Header: Reserved (2)
+    0b0000_1100
+  } else {
+    // Short Header: Reserved (2)
+    0b0001_1000
+  };
+  if first_byte & reserved_bits != 0 {
+    Err(header_error("Reserved bits set").in_field("Reserved Bits", 0))?;
+  }
+  Ok(())
+}
+
+// Layout of an encoded header, needed to apply header protection afterwards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedHeader {
+  // Total length of the header, including the packet number
+  pub len: usize,
+  // Length of the truncated packet number, 0 for Retry & VersionNegotiation
+  pub packet_number_len: usize,
+}
+
+impl EncodedHeader {
+  pub fn packet_number_offset(&self) -> usize {
+    self.len - self.packet_number_len
+  }
+
+  // Sample of the protected payload of `packet`, which starts with this header
+  pub fn hp_sample<'p>(&self, packet: &'p [u8]) -> Result<&'p [u8; HP_SAMPLE_LEN]> {
+    hp_sample(&packet[self.packet_number_offset()..])
+  }
+
+  // Applies header protection to `packet`, which starts with this header
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-header-protection-applicati
+  pub fn protect(&self, packet: &mut [u8], mask: [u8; 5]) {
+    packet[0] ^= mask[0] & first_byte_hp_bits(packet[0]);
+    let pn_offset = self.packet_number_offset();
+    let packet_number = &mut packet[pn_offset..pn_offset + self.packet_number_len];
+    for (b, m) in packet_number.iter_mut().zip(&mask[1..]) {
+      *b ^= m;
+    }
+  }
+
+  // Sets the fixed bit of `packet` to an unpredictable value, which is only
+  // allowed if the peer sent grease_quic_bit. The bit isn't covered by header
+  // protection.
+  // https://datatracker.ietf.org/doc/html/rfc9287#name-clearing-the-quic-bit
+  pub fn grease_fixed_bit(&self, packet: &mut [u8], rng: &mut impl RngCore) {
+    if rng.next_u32() & 1 == 0 {
+      packet[0] &= !FIXED_BIT;
+    }
+  }
+}
+
+// Writes everything up to and including the Source Connection ID, returning
+// the number of bytes written
+fn encode_long_header(
+  dst: &mut impl WriteBytesExt,
+  typ: u8,
+  packet_number_len: usize,
+  version: u32,
+  dst_cid: &[u8],
+  src_cid: &[u8],
+) -> Result<usize> {
+  // Header Form (1) = 1, Fixed Bit (1) = 1, Reserved (2) = 0
+  let pn_len_bits = packet_number_len.saturating_sub(1) as u8;
+  dst.write_u8(0b1100_0000 | typ << 4 | pn_len_bits)?;
+  dst.write_u32::<NetworkEndian>(version)?;
+  for cid in [dst_cid, src_cid] {
+    dst.write_u8(u8::try_from(cid.len())?)?;
+    dst.write_all(cid)?;
+  }
+  Ok(7 + dst_cid.len() + src_cid.len())
+}
+
+// Splits a UDP datagram into the packets coalesced in it, using the Length
+// field of long header packets. Short header packets always extend to the end
+// of the datagram.
+// https://datatracker.ietf.org/doc/html/rfc9000#name-coalescing-packets
+pub struct Coalesced<'a> {
+  data: &'a [u8],
+  local_cid_len: usize,
+  grease_quic_bit: bool,
+  first_dst_cid: Option<&'a [u8]>,
+}
+
+impl<'a> Coalesced<'a> {
+  pub fn new(datagram: &'a [u8], ctx: &PacketContext) -> Self {
+    Self {
+      data: datagram,
+      local_cid_len: ctx.local_cid_len,
+      grease_quic_bit: ctx.grease_quic_bit,
+      first_dst_cid: None,
+    }
+  }
+}
+
+// Destination Connection ID of the first packet in a datagram, which is all an
+// endpoint reads to find the connection the datagram belongs to
+pub fn first_dst_cid(datagram: &[u8], local_cid_len: usize) -> Result<&[u8]> {
+  // Only the connection knows whether the fixed bit may be greased
+  let (_, dst_cid) = split_packet(datagram, local_cid_len, true)?;
+  Ok(dst_cid)
+}
+
+impl<'a> Iterator for Coalesced<'a> {
+  type Item = &'a [u8];
+
+  fn next(&mut self) -> Option<&'a [u8]> {
+    while !self.data.is_empty() {
+      let Ok((packet, dst_cid)) = split_packet(self.data, self.local_cid_len, self.grease_quic_bit)
+      else {
+        // Padding or garbage that doesn't form a packet ends the datagram
+        self.data = &[];
+        return None;
+      };
+      self.data = &self.data[packet.len()..];
+
+      // Packets for a different connection than the first one are ignored
+      match self.first_dst_cid {
+        Some(first_dst_cid) if first_dst_cid != dst_cid => continue,
+        _ => self.first_dst_cid = Some(dst_cid),
+      }
+      return Some(packet);
+    }
+    None
+  }
+}
+
+// Returns the first packet in `data` and its Destination Connection ID,
+// reading only as much of the header as needed to find where it ends
+fn split_packet(
+  data: &[u8],
+  local_cid_len: usize,
+  grease_quic_bit: bool,
+) -> Result<(&[u8], &[u8])> {
+  let mut rem = data;
+  let first_byte = rem.read_u8()?;
+  if first_byte >> 7 == 0 {
+    check_fixed_bit(first_byte, grease_quic_bit)?;
+    let dst_cid = rem.slice(local_cid_len)?;
+    return Ok((data, dst_cid));
+  }
+
+  let version = rem.read_u32::<NetworkEndian>()?;
+  let dst_cid_len = rem.read_u8()? as usize;
+  let dst_cid = rem.slice(dst_cid_len)?;
+  let src_cid_len = rem.read_u8()? as usize;
+  rem.slice(src_cid_len)?;
+  // VersionNegotiation doesn't have a fixed bit and takes up the rest
+  if version == 0 {
+    return Ok((data, dst_cid));
+  }
+  check_fixed_bit(first_byte, grease_quic_bit)?;
+
+  match LongPacketType::from_bits(first_byte >> 4, version) {
+    LongPacketType::Retry => return Ok((data, dst_cid)),
+    LongPacketType::Initial => {
+      let token_length = VarInt::parse(&mut rem)?;
+      rem.slice(token_length.try_into()?)?;
+    }
+    _ => {}
+  }
+  let length = VarInt::parse(&mut rem)?;
+  rem.slice(length.try_into()?)?;
+
+  let len = data.len() - rem.len();
+  Ok((&data[..len], dst_cid))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RemainingBuf {
-  Decrypted(Vec<u8>),
+  // `offset` is where the payload starts in the packet
+  Decrypted { payload: Vec<u8>, offset: usize },
   // Raw(&'a [u8]),
   None,
 }
 
 impl<'a> Packet<'a> {
+  // Writes the header of this packet. `payload_len` is the length of the
+  // (encrypted) payload following the packet number, including the AEAD tag,
+  // and is used for the Length field of long header packets. The packet number
+  // is truncated based on the largest packet number acknowledged by the peer
+  // in the same packet number space.
+  pub fn encode_header(
+    &self,
+    payload_len: usize,
+    largest_acked: Option<PacketNumber>,
+    dst: &mut impl WriteBytesExt,
+  ) -> Result<EncodedHeader> {
+    match self {
+      Packet::VersionNegotiation(vn) => {
+        // Unused (7) bits are arbitrary, the fixed bit is set for compatibility
+        let mut len = encode_long_header(dst, 0, 0, 0, vn.dst_cid, vn.src_cid)?;
+        for version in vn.supported_versions {
+          dst.write_all(version)?;
+          len += 4;
+        }
+        Ok(EncodedHeader {
+          len,
+          packet_number_len: 0,
+        })
+      }
+      Packet::Initial(initial) => {
+        let pn_len = initial.packet_number.encoded_len(largest_acked);
+        let token_length = VarInt::try_from(initial.token.len())?;
+        let length = VarInt::try_from(pn_len + payload_len)?;
+
+        let mut len = encode_long_header(
+          dst,
+          LongPacketType::Initial.bits(initial.version),
+          pn_len,
+          initial.version,
+          initial.dst_cid.as_bytes(),
+          initial.src_cid.as_bytes(),
+        )?;
+        token_length.encode(dst)?;
+        dst.write_all(initial.token)?;
+        length.encode(dst)?;
+        initial.packet_number.encode(pn_len, dst)?;
+        len += token_length.encoded_len() + initial.token.len() + length.encoded_len() + pn_len;
+
+        Ok(EncodedHeader {
+          len,
+          packet_number_len: pn_len,
+        })
+      }
+      Packet::ZeroRTT(zero_rtt) => {
+        let pn_len = zero_rtt.packet_number.encoded_len(largest_acked);
+        let length = VarInt::try_from(pn_len + payload_len)?;
+
+        let mut len = encode_long_header(
+          dst,
+          LongPacketType::ZeroRtt.bits(zero_rtt.version),
+          pn_len,
+          zero_rtt.version,
+          zero_rtt.dst_cid.as_bytes(),
+          zero_rtt.src_cid.as_bytes(),
+        )?;
+        length.encode(dst)?;
+        zero_rtt.packet_number.encode(pn_len, dst)?;
+        len += length.encoded_len() + pn_len;
+
+        Ok(EncodedHeader {
+          len,
+          packet_number_len: pn_len,
+        })
+      }
+      Packet::Handshake(handshake) => {
+        let pn_len = handshake.packet_number.encoded_len(largest_acked);
+        let length = VarInt::try_from(pn_len + payload_len)?;
+
+        let mut len = encode_long_header(
+          dst,
+          LongPacketType::Handshake.bits(handshake.version),
+          pn_len,
+          handshake.version,
+          handshake.dst_cid.as_bytes(),
+          handshake.src_cid.as_bytes(),
+        )?;
+        length.encode(dst)?;
+        handshake.packet_number.encode(pn_len, dst)?;
+        len += length.encoded_len() + pn_len;
+
+        Ok(EncodedHeader {
+          len,
+          packet_number_len: pn_len,
+        })
+      }
+      Packet::Retry(retry) => {
+        let mut len = encode_long_header(
+          dst,
+          LongPacketType::Retry.bits(retry.version),
+          0,
+          retry.version,
+          retry.dst_cid.as_bytes(),
+          retry.src_cid.as_bytes(),
+        )?;
+        dst.write_all(retry.retry_token)?;
+        dst.write_all(&retry.retry_integrity_tag)?;
+        len += retry.retry_token.len() + AEAD_TAG_LEN;
+
+        Ok(EncodedHeader {
+          len,
+          packet_number_len: 0,
+        })
+      }
+      Packet::OneRtt(one_rtt) => {
+        let pn_len = one_rtt.packet_number.encoded_len(largest_acked);
+
+        // Header Form (1) = 0, Fixed Bit (1) = 1, Reserved (2) = 0
+        dst.write_u8(
+          0b0100_0000 | (one_rtt.spin & 1) << 5 | (one_rtt.key_phase & 1) << 2 | (pn_len - 1) as u8,
+        )?;
+        // The length of the Destination Connection ID is not encoded
+        dst.write_all(one_rtt.dst_cid.as_bytes())?;
+        one_rtt.packet_number.encode(pn_len, dst)?;
+
+        Ok(EncodedHeader {
+          len: 1 + one_rtt.dst_cid.len() + pn_len,
+          packet_number_len: pn_len,
+        })
+      }
+    }
+  }
+
+  // Parses a single packet, see `Coalesced` for splitting up a datagram.
+  // Packet numbers are recovered using the largest packet number received so
+  // far in the packet number space of the packet. Errors name the packet type
+  // and the field that failed, with its offset from the start of the packet.
   pub async fn parse(
+    crypto: &impl Crypto,
+    data: &'a [u8],
+    ctx: &PacketContext,
+    largest_received: &PacketNumberSpaces<Option<PacketNumber>>,
+  ) -> Result<(Packet<'a>, RemainingBuf)> {
+    let packet = Self::parse_unclassified(crypto, data, ctx, largest_received).await;
+    packet.map_err(|err| match type_name(data) {
+      Some(packet_type) => err.in_packet(packet_type),
+      None => err,
+    })
+  }
+
+  async fn parse_unclassified(
     crypto: &impl Crypto,
     mut data: &'a [u8],
+    ctx: &PacketContext,
+    largest_received: &PacketNumberSpaces<Option<PacketNumber>>,
   ) -> Result<(Packet<'a>, RemainingBuf)> {
-    let first_byte = data.read_u8()?;
+    // Packets we receive are sent by our peer
+    let sent_by_server = !ctx.is_server;
+    let raw = data;
+
+    let first_byte = field(raw, &mut data, "Header Form", |data| Ok(data.read_u8()?))?;
     // Header Form (1) bit
     if first_byte >> 7 != 0 {
       // Long Header
       // https://datatracker.ietf.org/doc/html/rfc9000#long-header
 
-      // Fixed Bit (1) = 1 - ignored
-      // Long Packet Type (2) - not used in VersionNegotiation
+      // Fixed Bit (1) = 1, unless greased
+      // Long Packet Type (2) - depends on the version, not used in
+      // VersionNegotiation
       let packet_type = (first_byte >> 4) & 0b11;
-      // Reserved (2) - ignored
-      // Packet Number Length (2) - not used in Retry & VersionNegotiation
-      let packet_number_length_encoded = first_byte & 0b11;
-      let packet_number_length = 1 + packet_number_length_encoded as usize;
+      // Reserved (2) - protected, checked once decrypted
+      // Packet Number Length (2) - protected, not used in Retry & VersionNegotiation
 
-      let version = data.read_u32::<NetworkEndian>()?;
-      let dst_cid = ConnectionId::parse(&mut data)?;
-      let src_cid = ConnectionId::parse(&mut data)?;
+      let version = field(raw, &mut data, "Version", |data| {
+        Ok(data.read_u32::<NetworkEndian>()?)
+      })?;
 
       if version == 0 {
         // VersionNegotiation packet
         // https://datatracker.ietf.org/doc/html/rfc9000#name-version-negotiation-packet
 
-        let (versions_bytes, _remainder) = data.as_chunks::<4>();
-        let versions = versions_bytes
-          .iter()
-          .map(|b| (&b[..]).read_u32::<NetworkEndian>())
-          .collect::<io::Result<Vec<u32>>>()?;
-        // TODO check remainder?
+        let dst_cid = field(raw, &mut data, "Destination Connection ID", |data| {
+          let dst_cid_len = data.read_u8()?;
+          data.slice(dst_cid_len.into())
+        })?;
+        let src_cid = field(raw, &mut data, "Source Connection ID", |data| {
+          let src_cid_len = data.read_u8()?;
+          data.slice(src_cid_len.into())
+        })?;
+
+        let (supported_versions, remainder) = data.as_chunks::<4>();
+        if !remainder.is_empty() {
+          let offset = offset_in(raw, remainder);
+          Err(
+            header_error("Version Negotiation has a partial version")
+              .in_field("Supported Version", offset),
+          )?;
+        }
 
         let packet = Packet::VersionNegotiation(VersionNegotiation {
           src_cid,
-          dst_cid: dst_cid.clone(),
-          supported_versions: versions,
+          dst_cid,
+          supported_versions,
         });
         return Ok((packet, RemainingBuf::None));
       }
-      match packet_type {
-        0b00 => {
+
+      check_fixed_bit(first_byte, ctx.grease_quic_bit)?;
+      let dst_cid = field(
+        raw,
+        &mut data,
+        "Destination Connection ID",
+        ConnectionId::parse,
+      )?;
+      let src_cid = field(raw, &mut data, "Source Connection ID", ConnectionId::parse)?;
+      match LongPacketType::from_bits(packet_type, version) {
+        LongPacketType::Initial => {
           // Initial packet
           // https://datatracker.ietf.org/doc/html/rfc9000#name-initial-packet
 
-          let token_length = VarInt::parse(&mut data)?;
-          let token = data.slice(token_length.into())?;
-          let length = VarInt::parse(&mut data)?; // TODO use this
-          let packet_number = PacketNumber::parse(&mut data, packet_number_length)?;
+          let token_length = field(raw, &mut data, "Token Length", |data| {
+            let length = VarInt::parse(data)?;
+            if length.into_inner() > ctx.limits.max_token_len as u64 {
+              Err("Token too long")?;
+            }
+            Ok(length)
+          })?;
+          let token = field(raw, &mut data, "Token", |data| {
+            data.slice(token_length.try_into()?)
+          })?;
+          // Anything after Length belongs to the next coalesced packet
+          data = field(raw, &mut data, "Length", |data| {
+            let length = VarInt::parse(data)?;
+            data.slice(length.try_into()?)
+          })?;
+
+          let mask = crypto.header_protection_mask(
+            EncryptionLevel::Initial,
+            ctx.key_cid(EncryptionLevel::Initial, &dst_cid),
+            version,
+            sent_by_server,
+            field(raw, &mut data, "Packet Payload", |data| hp_sample(data))?,
+          )?;
+          let (first_byte, packet_number) = field(raw, &mut data, "Packet Number", |data| {
+            unprotect_header(
+              mask,
+              first_byte,
+              data,
+              largest_received[PacketNumberSpace::Initial],
+            )
+          })?;
 
           let packet = Packet::Initial(Initial {
             src_cid,
@@ -146,18 +820,42 @@ impl<'a> Packet<'a> {
             token,
             packet_number,
           });
+          let offset = offset_in(raw, data);
+          let key_cid = ctx.key_cid(EncryptionLevel::Initial, &dst_cid).clone();
           let payload = crypto
-            .decrypt_initial_data(dst_cid, version, false, &mut data)
+            .decrypt_initial_data(key_cid, version, sent_by_server, &mut data)
             .await?;
-          Ok((packet, RemainingBuf::Decrypted(payload)))
+          check_reserved_bits(first_byte)?;
+          Ok((packet, RemainingBuf::Decrypted { payload, offset }))
         }
-        0b01 => {
+        LongPacketType::ZeroRtt => {
           // 0-RTT packet
           // https://datatracker.ietf.org/doc/html/rfc9000#name-0-rtt
 
-          let length = VarInt::parse(&mut data)?; // TODO use this
-          let packet_number = PacketNumber::parse(&mut data, packet_number_length)?;
+          // Anything after Length belongs to the next coalesced packet
+          data = field(raw, &mut data, "Length", |data| {
+            let length = VarInt::parse(data)?;
+            data.slice(length.try_into()?)
+          })?;
+
+          let mask = crypto.header_protection_mask(
+            EncryptionLevel::ZeroRtt,
+            ctx.key_cid(EncryptionLevel::ZeroRtt, &dst_cid),
+            version,
+            sent_by_server,
+            field(raw, &mut data, "Packet Payload", |data| hp_sample(data))?,
+          )?;
+          let (first_byte, packet_number) = field(raw, &mut data, "Packet Number", |data| {
+            unprotect_header(
+              mask,
+              first_byte,
+              data,
+              largest_received[PacketNumberSpace::ApplicationData],
+            )
+          })?;
+          let offset = offset_in(raw, data);
           let payload = data.to_vec(); // TODO: decrypt
+          check_reserved_bits(first_byte)?;
 
           let packet = Packet::ZeroRTT(ZeroRTT {
             src_cid,
@@ -165,15 +863,36 @@ impl<'a> Packet<'a> {
             version,
             packet_number,
           });
-          Ok((packet, RemainingBuf::Decrypted(payload)))
+          Ok((packet, RemainingBuf::Decrypted { payload, offset }))
         }
-        0b10 => {
+        LongPacketType::Handshake => {
           // Handshake packet
           // https://datatracker.ietf.org/doc/html/rfc9000#packet-handshake
 
-          let length = VarInt::parse(&mut data)?; // TODO use this
-          let packet_number = PacketNumber::parse(&mut data, packet_number_length)?;
+          // Anything after Length belongs to the next coalesced packet
+          data = field(raw, &mut data, "Length", |data| {
+            let length = VarInt::parse(data)?;
+            data.slice(length.try_into()?)
+          })?;
+
+          let mask = crypto.header_protection_mask(
+            EncryptionLevel::Handshake,
+            ctx.key_cid(EncryptionLevel::Handshake, &dst_cid),
+            version,
+            sent_by_server,
+            field(raw, &mut data, "Packet Payload", |data| hp_sample(data))?,
+          )?;
+          let (first_byte, packet_number) = field(raw, &mut data, "Packet Number", |data| {
+            unprotect_header(
+              mask,
+              first_byte,
+              data,
+              largest_received[PacketNumberSpace::Handshake],
+            )
+          })?;
+          let offset = offset_in(raw, data);
           let payload = data.to_vec(); // TODO: decrypt
+          check_reserved_bits(first_byte)?;
 
           let packet = Packet::Handshake(Handshake {
             src_cid,
@@ -181,48 +900,88 @@ impl<'a> Packet<'a> {
             version,
             packet_number,
           });
-          Ok((packet, RemainingBuf::Decrypted(payload)))
+          Ok((packet, RemainingBuf::Decrypted { payload, offset }))
         }
-        0b11 => {
+        LongPacketType::Retry => {
           // Retry packet
           // https://datatracker.ietf.org/doc/html/rfc9000#name-retry-packet
 
-          // TODO is this encoding even correct? wtf is a retry token?
-          let (retry_token, retry_integrity_tag) = data
-            .split_last_chunk::<16>() // 128bits/8 = 16 bytes
-            .ok_or_else(|| "Packet too short for Retry Token")?;
-          let retry_integrity_tag = (&retry_integrity_tag[..]).read_u128::<NetworkEndian>()?;
+          // The token is opaque to the client, and is echoed in its next Initial
+          let tag_offset = raw.len().saturating_sub(AEAD_TAG_LEN);
+          let (retry_token, retry_integrity_tag) =
+            data.split_last_chunk::<AEAD_TAG_LEN>().ok_or_else(|| {
+              header_error("Packet too short for Retry Integrity Tag")
+                .in_field("Retry Integrity Tag", tag_offset)
+            })?;
+          // https://datatracker.ietf.org/doc/html/rfc9000#section-17.2.5.2-3
+          if retry_token.is_empty() {
+            Err(header_error("Retry without a token").in_field("Retry Token", tag_offset))?;
+          }
+
+          // Only clients accept Retry packets, and only if they're authentic
+          // https://datatracker.ietf.org/doc/html/rfc9000#section-17.2.5.2-2
+          let original_dst_cid = match (&ctx.original_dst_cid, ctx.is_server) {
+            (Some(original_dst_cid), false) => original_dst_cid,
+            _ => Err("Unexpected Retry")?,
+          };
+          let retry_without_tag = &raw[..raw.len() - AEAD_TAG_LEN];
+          let expected_tag = crypto.retry_integrity_tag(
+            version,
+            &retry_pseudo_packet(original_dst_cid, retry_without_tag),
+          )?;
+          if expected_tag != *retry_integrity_tag {
+            Err(
+              header_error("Invalid Retry Integrity Tag")
+                .in_field("Retry Integrity Tag", tag_offset),
+            )?;
+          }
 
           let packet = Packet::Retry(Retry {
             src_cid,
             dst_cid: dst_cid.clone(),
             version,
             retry_token,
-            retry_integrity_tag,
+            retry_integrity_tag: *retry_integrity_tag,
           });
           Ok((packet, RemainingBuf::None))
         }
-        _ => unreachable!(),
       }
     } else {
       // Short Header
       // https://datatracker.ietf.org/doc/html/rfc9000#name-short-header-packets
 
-      // Fixed Bit (1) = 1 - ignored
+      // Fixed Bit (1) = 1, unless greased
+      check_fixed_bit(first_byte, ctx.grease_quic_bit)?;
       // Spin Bit (1)
       let spin = (first_byte >> 5) & 1;
-      // Reserved (2) - ignored
-      // Key Phase (1)
-      let key_phase = (first_byte >> 2) & 1;
-      // Packet Number Length (2)
-      let packet_number_length_encoded = first_byte & 0b11;
-      let packet_number_length = 1 + packet_number_length_encoded as usize;
-      let dst_cid = ConnectionId::parse(&mut data)?;
+      // Reserved (2) - protected, checked once decrypted
+      // Key Phase (1) - protected
+      // Packet Number Length (2) - protected
+      let dst_cid = field(raw, &mut data, "Destination Connection ID", |data| {
+        ConnectionId::parse_with_len(data, ctx.local_cid_len)
+      })?;
 
       // Currently 1-RTT packets are the only Short Header packets
       // https://datatracker.ietf.org/doc/html/rfc9000#name-1-rtt-packet
-      let packet_number = PacketNumber::parse(&mut data, packet_number_length)?;
+      let mask = crypto.header_protection_mask(
+        EncryptionLevel::OneRtt,
+        ctx.key_cid(EncryptionLevel::OneRtt, &dst_cid),
+        ctx.version,
+        sent_by_server,
+        field(raw, &mut data, "Packet Payload", |data| hp_sample(data))?,
+      )?;
+      let (first_byte, packet_number) = field(raw, &mut data, "Packet Number", |data| {
+        unprotect_header(
+          mask,
+          first_byte,
+          data,
+          largest_received[PacketNumberSpace::ApplicationData],
+        )
+      })?;
+      let key_phase = (first_byte >> 2) & 1;
+      let offset = offset_in(raw, data);
       let payload = data.to_vec(); // TODO: decrypt
+      check_reserved_bits(first_byte)?;
 
       let packet = Packet::OneRtt(OneRtt {
         dst_cid: dst_cid.clone(),
@@ -230,7 +989,775 @@ impl<'a> Packet<'a> {
         spin,
         key_phase,
       });
-      Ok((packet, RemainingBuf::Decrypted(payload)))
+      Ok((packet, RemainingBuf::Decrypted { payload, offset }))
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wire::VERSION_1;
+
+  // Leaves payloads as is, header protection is done with a fixed mask and
+  // Retry tags are the pseudo-packet folded into 16 bytes
+  struct PlaintextCrypto {
+    mask: [u8; 5],
+  }
+
+  const PLAINTEXT: PlaintextCrypto = PlaintextCrypto { mask: [0; 5] };
+
+  impl Crypto for PlaintextCrypto {
+    async fn decrypt_initial_data(
+      &self,
+      _cid: ConnectionId,
+      _version: u32,
+      _is_server: bool,
+      data: &mut impl Buffer,
+    ) -> Result<Vec<u8>> {
+      let mut payload = Vec::new();
+      data.read_to_end(&mut payload)?;
+      Ok(payload)
+    }
+
+    fn header_protection_mask(
+      &self,
+      _level: EncryptionLevel,
+      _cid: &ConnectionId,
+      _version: u32,
+      _is_server: bool,
+      _sample: &[u8; HP_SAMPLE_LEN],
+    ) -> Result<[u8; 5]> {
+      Ok(self.mask)
+    }
+
+    fn retry_integrity_tag(
+      &self,
+      _version: u32,
+      pseudo_packet: &[u8],
+    ) -> Result<[u8; AEAD_TAG_LEN]> {
+      let mut tag = [0; AEAD_TAG_LEN];
+      for (i, byte) in pseudo_packet.iter().enumerate() {
+        tag[i % AEAD_TAG_LEN] ^= byte.rotate_left(i as u32);
+      }
+      Ok(tag)
+    }
+  }
+
+  fn ctx() -> PacketContext {
+    PacketContext {
+      is_server: true,
+      local_cid_len: 3,
+      version: 1,
+      original_dst_cid: None,
+      initial_dst_cid: None,
+      grease_quic_bit: false,
+      limits: ParseLimits::default(),
+    }
+  }
+
+  fn cid_len_ctx(local_cid_len: usize) -> PacketContext {
+    PacketContext {
+      local_cid_len,
+      ..ctx()
+    }
+  }
+
+  fn client_ctx(original_dst_cid: &[u8]) -> PacketContext {
+    PacketContext {
+      is_server: false,
+      original_dst_cid: Some(cid(original_dst_cid)),
+      ..ctx()
+    }
+  }
+
+  fn cid(bytes: &[u8]) -> ConnectionId {
+    ConnectionId::new(bytes).unwrap()
+  }
+
+  async fn round_trip(packet: Packet<'_>, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let header = packet.encode_header(payload.len(), None, &mut buf).unwrap();
+    assert_eq!(header.len, buf.len());
+    buf.extend_from_slice(payload);
+
+    let (parsed, remaining) =
+      Packet::parse(&PLAINTEXT, &buf, &ctx(), &PacketNumberSpaces::default())
+        .await
+        .unwrap();
+    assert_eq!(parsed, packet);
+    match remaining {
+      RemainingBuf::Decrypted {
+        payload: data,
+        offset,
+      } => {
+        assert_eq!(data, payload);
+        assert_eq!(offset, header.len);
+      }
+      RemainingBuf::None => assert!(payload.is_empty()),
+    }
+    buf
+  }
+
+  #[tokio::test]
+  async fn initial_header_round_trips() {
+    let buf = round_trip(
+      Packet::Initial(Initial {
+        src_cid: cid(&[0xaa]),
+        dst_cid: cid(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]),
+        version: 1,
+        token: &[0x01, 0x02],
+        packet_number: PacketNumber::from(0x1234u32),
+      }),
+      &[0xff; 20],
+    )
+    .await;
+    assert_eq!(
+      buf[..25],
+      [
+        0xc1, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0x01,
+        0xaa, 0x02, 0x01, 0x02, 0x16, 0x12, 0x34, 0xff, 0xff, 0xff
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn initial_token_over_limit_fails() {
+    let buf = round_trip(
+      Packet::Initial(Initial {
+        src_cid: cid(&[]),
+        dst_cid: cid(&[0x01]),
+        version: 1,
+        token: &[0x01; 8],
+        packet_number: PacketNumber::from(0u32),
+      }),
+      &[0xff; 20],
+    )
+    .await;
+    let limited_ctx = PacketContext {
+      limits: ParseLimits {
+        max_token_len: 7,
+        ..ParseLimits::default()
+      },
+      ..ctx()
+    };
+    let spaces = PacketNumberSpaces::default();
+    assert!(Packet::parse(&PLAINTEXT, &buf, &limited_ctx, &spaces)
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn truncated_header_names_field() {
+    let buf = round_trip(
+      Packet::Initial(Initial {
+        src_cid: cid(&[0xaa]),
+        dst_cid: cid(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]),
+        version: 1,
+        token: &[0x01, 0x02],
+        packet_number: PacketNumber::from(0x1234u32),
+      }),
+      &[0xff; 20],
+    )
+    .await;
+    let spaces = PacketNumberSpaces::default();
+    // Cut off in the middle of the token
+    let err = Packet::parse(&PLAINTEXT, &buf[..18], &ctx(), &spaces)
+      .await
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert_eq!(
+      err.context(),
+      Some(&ParseContext {
+        packet_type: Some("Initial"),
+        field: Some("Token"),
+        offset: Some(17),
+      })
+    );
+    assert_eq!(
+      err.to_string(),
+      "PROTOCOL_VIOLATION field Token at offset 17 of Initial packet: Buffer too short"
+    );
+
+    // Length claims more than the packet has
+    let err = Packet::parse(&PLAINTEXT, &buf[..30], &ctx(), &spaces)
+      .await
+      .unwrap_err();
+    assert_eq!(err.context().unwrap().field, Some("Length"));
+    assert_eq!(err.context().unwrap().offset, Some(19));
+  }
+
+  #[tokio::test]
+  async fn zero_rtt_header_round_trips() {
+    let buf = round_trip(
+      Packet::ZeroRTT(ZeroRTT {
+        src_cid: cid(&[]),
+        dst_cid: cid(&[0x01, 0x02, 0x03, 0x04]),
+        version: 1,
+        packet_number: PacketNumber::from(3u32),
+      }),
+      &[0x01; 100],
+    )
+    .await;
+    assert_eq!(buf[0], 0xd0);
+    // Length covers the packet number and the payload
+    assert_eq!(buf[11..13], [0x40, 101]);
+  }
+
+  #[tokio::test]
+  async fn handshake_header_round_trips() {
+    let buf = round_trip(
+      Packet::Handshake(Handshake {
+        src_cid: cid(&[0x05; 20]),
+        dst_cid: cid(&[0x06; 20]),
+        version: 1,
+        packet_number: PacketNumber::from(0x0102_0304u32),
+      }),
+      &[0x00; 30],
+    )
+    .await;
+    assert_eq!(buf[0], 0xe3);
+  }
+
+  #[tokio::test]
+  async fn v2_long_header_types() {
+    let initial = round_trip(
+      Packet::Initial(Initial {
+        src_cid: cid(&[]),
+        dst_cid: cid(&[0x01]),
+        version: VERSION_2,
+        token: &[],
+        packet_number: PacketNumber::from(0u32),
+      }),
+      &[0xff; 20],
+    )
+    .await;
+    assert_eq!(initial[..5], [0xd0, 0x6b, 0x33, 0x43, 0xcf]);
+
+    let zero_rtt = round_trip(
+      Packet::ZeroRTT(ZeroRTT {
+        src_cid: cid(&[]),
+        dst_cid: cid(&[0x01]),
+        version: VERSION_2,
+        packet_number: PacketNumber::from(0u32),
+      }),
+      &[0xff; 20],
+    )
+    .await;
+    assert_eq!(zero_rtt[0], 0xe0);
+
+    let handshake = round_trip(
+      Packet::Handshake(Handshake {
+        src_cid: cid(&[]),
+        dst_cid: cid(&[0x01]),
+        version: VERSION_2,
+        packet_number: PacketNumber::from(0u32),
+      }),
+      &[0xff; 20],
+    )
+    .await;
+    assert_eq!(handshake[0], 0xf0);
+
+    let retry = Retry::new(
+      &PLAINTEXT,
+      &cid(&[0xaa]),
+      cid(&[0x01, 0x02]),
+      cid(&[]),
+      VERSION_2,
+      b"token",
+    )
+    .unwrap();
+    let mut buf = Vec::new();
+    Packet::Retry(retry.clone())
+      .encode_header(0, None, &mut buf)
+      .unwrap();
+    assert_eq!(buf[0] & 0xf0, 0xc0);
+    let parsed = parse_retry(&buf, &client_ctx(&[0xaa])).await.unwrap();
+    assert_eq!(parsed, Packet::Retry(retry));
+
+    // The same bits mean something else in v1
+    assert_eq!(
+      LongPacketType::from_bits(buf[0] >> 4, VERSION_1),
+      LongPacketType::Initial
+    );
+  }
+
+  fn retry(original_dst_cid: &[u8]) -> Retry<'static> {
+    Retry::new(
+      &PLAINTEXT,
+      &cid(original_dst_cid),
+      cid(&[0x01, 0x02]),
+      cid(&[]),
+      1,
+      b"token",
+    )
+    .unwrap()
+  }
+
+  async fn parse_retry<'a>(buf: &'a [u8], ctx: &PacketContext) -> Result<Packet<'a>> {
+    let (packet, remaining) =
+      Packet::parse(&PLAINTEXT, buf, ctx, &PacketNumberSpaces::default()).await?;
+    assert_eq!(remaining, RemainingBuf::None);
+    Ok(packet)
+  }
+
+  #[tokio::test]
+  async fn retry_round_trips() {
+    let retry = retry(&[0xaa, 0xbb]);
+    let mut buf = Vec::new();
+    Packet::Retry(retry.clone())
+      .encode_header(0, None, &mut buf)
+      .unwrap();
+    assert_eq!(buf[0] & 0xf0, 0xf0);
+    assert_eq!(buf.len(), 7 + 2 + 5 + AEAD_TAG_LEN);
+
+    let parsed = parse_retry(&buf, &client_ctx(&[0xaa, 0xbb])).await.unwrap();
+    assert_eq!(parsed, Packet::Retry(retry));
+  }
+
+  #[tokio::test]
+  async fn retry_with_invalid_tag_fails() {
+    let mut buf = Vec::new();
+    Packet::Retry(retry(&[0xaa, 0xbb]))
+      .encode_header(0, None, &mut buf)
+      .unwrap();
+
+    // Tag for a different original Destination Connection ID
+    assert!(parse_retry(&buf, &client_ctx(&[0xaa])).await.is_err());
+
+    // Modified token
+    let mut modified = buf.clone();
+    modified[9] ^= 1;
+    assert!(parse_retry(&modified, &client_ctx(&[0xaa, 0xbb]))
+      .await
+      .is_err());
+
+    // Too short for a tag
+    assert!(parse_retry(&buf[..20], &client_ctx(&[0xaa, 0xbb]))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn retry_without_token_fails() {
+    let retry = Retry::new(
+      &PLAINTEXT,
+      &cid(&[0xaa, 0xbb]),
+      cid(&[0x01, 0x02]),
+      cid(&[]),
+      1,
+      b"",
+    )
+    .unwrap();
+    let mut buf = Vec::new();
+    Packet::Retry(retry)
+      .encode_header(0, None, &mut buf)
+      .unwrap();
+
+    let err = parse_retry(&buf, &client_ctx(&[0xaa, 0xbb]))
+      .await
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert_eq!(err.context().unwrap().field, Some("Retry Token"));
+  }
+
+  #[tokio::test]
+  async fn retry_only_accepted_by_clients() {
+    let mut buf = Vec::new();
+    Packet::Retry(retry(&[0xaa, 0xbb]))
+      .encode_header(0, None, &mut buf)
+      .unwrap();
+
+    let server_ctx = PacketContext {
+      original_dst_cid: Some(cid(&[0xaa, 0xbb])),
+      ..ctx()
+    };
+    assert!(parse_retry(&buf, &server_ctx).await.is_err());
+    assert!(parse_retry(&buf, &ctx()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn version_negotiation_round_trips() {
+    let buf = round_trip(
+      Packet::VersionNegotiation(VersionNegotiation {
+        src_cid: &[0x01],
+        dst_cid: &[0x02],
+        supported_versions: &[1u32.to_be_bytes(), 0x6b33_43cfu32.to_be_bytes()],
+      }),
+      &[],
+    )
+    .await;
+    assert_eq!(buf[1..5], [0, 0, 0, 0]);
+    assert_eq!(buf[9..], [0, 0, 0, 1, 0x6b, 0x33, 0x43, 0xcf]);
+  }
+
+  async fn parse_version_negotiation(buf: &[u8]) -> Result<Packet<'_>> {
+    let (packet, _) =
+      Packet::parse(&PLAINTEXT, buf, &ctx(), &PacketNumberSpaces::default()).await?;
+    Ok(packet)
+  }
+
+  #[tokio::test]
+  async fn version_negotiation_versions_are_borrowed() {
+    let buf = [
+      0x80, 0, 0, 0, 0, 1, 0xaa, 0, 0xff, 0, 0, 1, 0x0a, 0x0a, 0x0a, 0x0a,
+    ];
+    let Packet::VersionNegotiation(vn) = parse_version_negotiation(&buf).await.unwrap() else {
+      panic!("not a Version Negotiation packet");
+    };
+    assert_eq!(vn.dst_cid, [0xaa]);
+    assert!(vn.src_cid.is_empty());
+    assert_eq!(
+      vn.versions().collect::<Vec<_>>(),
+      [0xff00_0001, 0x0a0a_0a0a]
+    );
+    assert!(std::ptr::eq(vn.supported_versions[0].as_ptr(), &buf[8]));
+  }
+
+  #[tokio::test]
+  async fn version_negotiation_partial_version_fails() {
+    let buf = [0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff];
+    assert!(parse_version_negotiation(&buf).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn version_negotiation_allows_long_cids() {
+    let long_cid = [0x5a; 255];
+    let mut buf = Vec::new();
+    Packet::VersionNegotiation(VersionNegotiation {
+      src_cid: &long_cid[..21],
+      dst_cid: &long_cid,
+      supported_versions: &[1u32.to_be_bytes()],
+    })
+    .encode_header(0, None, &mut buf)
+    .unwrap();
+
+    let Packet::VersionNegotiation(vn) = parse_version_negotiation(&buf).await.unwrap() else {
+      panic!("not a Version Negotiation packet");
+    };
+    assert_eq!(vn.dst_cid, long_cid);
+    assert_eq!(vn.src_cid, &long_cid[..21]);
+    assert_eq!(vn.versions().collect::<Vec<_>>(), [1]);
+  }
+
+  #[tokio::test]
+  async fn one_rtt_header_round_trips() {
+    round_trip(
+      Packet::OneRtt(OneRtt {
+        dst_cid: cid(&[0x01, 0x02, 0x03]),
+        spin: 0,
+        key_phase: 1,
+        packet_number: PacketNumber::from(7u32),
+      }),
+      &[0xab; 19],
+    )
+    .await;
+  }
+
+  fn encode(packet: &Packet, payload: &[u8], dst: &mut Vec<u8>) {
+    packet.encode_header(payload.len(), None, dst).unwrap();
+    dst.extend_from_slice(payload);
+  }
+
+  fn handshake(dst_cid: &[u8]) -> Packet<'static> {
+    Packet::Handshake(Handshake {
+      src_cid: cid(&[0x09]),
+      dst_cid: cid(dst_cid),
+      version: 1,
+      packet_number: PacketNumber::from(1u32),
+    })
+  }
+
+  #[tokio::test]
+  async fn coalesced_packets_split_by_length() {
+    let initial = Packet::Initial(Initial {
+      src_cid: cid(&[0x09]),
+      dst_cid: cid(&[0x01, 0x02, 0x03]),
+      version: 1,
+      token: &[],
+      packet_number: PacketNumber::from(0u32),
+    });
+    let one_rtt = Packet::OneRtt(OneRtt {
+      dst_cid: cid(&[0x01, 0x02, 0x03]),
+      spin: 0,
+      key_phase: 0,
+      packet_number: PacketNumber::from(2u32),
+    });
+    let mut datagram = Vec::new();
+    encode(&initial, &[0x11; 30], &mut datagram);
+    let first_len = datagram.len();
+    encode(&handshake(&[0x01, 0x02, 0x03]), &[0x22; 40], &mut datagram);
+    let second_len = datagram.len() - first_len;
+    encode(&one_rtt, &[0x33; 50], &mut datagram);
+    let third_len = datagram.len() - first_len - second_len;
+
+    let packets = Coalesced::new(&datagram, &ctx()).collect::<Vec<_>>();
+    assert_eq!(
+      packets.iter().map(|p| p.len()).collect::<Vec<_>>(),
+      [first_len, second_len, third_len]
+    );
+
+    // Each packet only decrypts its own payload
+    let spaces = PacketNumberSpaces::default();
+    let payloads = [&[0x11; 30][..], &[0x22; 40], &[0x33; 50]];
+    for ((data, expected), payload) in packets
+      .into_iter()
+      .zip([initial, handshake(&[0x01, 0x02, 0x03]), one_rtt])
+      .zip(payloads)
+    {
+      let (parsed, remaining) = Packet::parse(&PLAINTEXT, data, &ctx(), &spaces)
+        .await
+        .unwrap();
+      assert_eq!(parsed, expected);
+      let offset = data.len() - payload.len();
+      let payload = payload.to_vec();
+      assert_eq!(remaining, RemainingBuf::Decrypted { payload, offset });
+    }
+  }
+
+  #[test]
+  fn coalesced_trailing_padding_dropped() {
+    let mut datagram = Vec::new();
+    encode(&handshake(&[0x01]), &[0x22; 20], &mut datagram);
+    let len = datagram.len();
+    datagram.extend_from_slice(&[0; 100]);
+
+    let packets = Coalesced::new(&datagram, &cid_len_ctx(1)).collect::<Vec<_>>();
+    assert_eq!(packets, [&datagram[..len]]);
+  }
+
+  #[test]
+  fn coalesced_truncated_packet_dropped() {
+    let mut datagram = Vec::new();
+    encode(&handshake(&[0x01]), &[0x22; 20], &mut datagram);
+    let len = datagram.len();
+    encode(&handshake(&[0x01]), &[0x22; 20], &mut datagram);
+    datagram.truncate(datagram.len() - 1);
+
+    let packets = Coalesced::new(&datagram, &cid_len_ctx(1)).collect::<Vec<_>>();
+    assert_eq!(packets, [&datagram[..len]]);
+  }
+
+  #[test]
+  fn coalesced_different_dst_cid_ignored() {
+    let mut datagram = Vec::new();
+    encode(&handshake(&[0x01]), &[0x22; 20], &mut datagram);
+    let first_len = datagram.len();
+    encode(&handshake(&[0x02]), &[0x22; 20], &mut datagram);
+    let second_len = datagram.len();
+    encode(&handshake(&[0x01]), &[0x22; 20], &mut datagram);
+
+    let packets = Coalesced::new(&datagram, &cid_len_ctx(1)).collect::<Vec<_>>();
+    assert_eq!(packets, [&datagram[..first_len], &datagram[second_len..]]);
+  }
+
+  #[tokio::test]
+  async fn cleared_fixed_bit_needs_grease_quic_bit() {
+    let one_rtt = Packet::OneRtt(OneRtt {
+      dst_cid: cid(&[0x01, 0x02, 0x03]),
+      spin: 0,
+      key_phase: 0,
+      packet_number: PacketNumber::from(2u32),
+    });
+    let greased_ctx = PacketContext {
+      grease_quic_bit: true,
+      ..ctx()
+    };
+    let spaces = PacketNumberSpaces::default();
+    for packet in [handshake(&[0x01, 0x02, 0x03]), one_rtt] {
+      let mut datagram = Vec::new();
+      encode(&packet, &[0x22; 20], &mut datagram);
+      datagram[0] &= !FIXED_BIT;
+
+      assert_eq!(Coalesced::new(&datagram, &ctx()).count(), 0);
+      assert!(Packet::parse(&PLAINTEXT, &datagram, &ctx(), &spaces)
+        .await
+        .is_err());
+
+      assert_eq!(Coalesced::new(&datagram, &greased_ctx).count(), 1);
+      let (parsed, _) = Packet::parse(&PLAINTEXT, &datagram, &greased_ctx, &spaces)
+        .await
+        .unwrap();
+      assert_eq!(parsed, packet);
+    }
+  }
+
+  #[test]
+  fn grease_fixed_bit_is_random() {
+    let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+    let mut buf = Vec::new();
+    let header = handshake(&[0x01])
+      .encode_header(20, None, &mut buf)
+      .unwrap();
+    let first_byte = buf[0];
+
+    header.grease_fixed_bit(&mut buf, &mut rng);
+    assert_eq!(buf[0], first_byte & !FIXED_BIT);
+    buf[0] = first_byte;
+    header.grease_fixed_bit(&mut buf, &mut rng);
+    assert_eq!(buf[0], first_byte);
+  }
+
+  #[tokio::test]
+  async fn protected_headers_round_trip() {
+    let crypto = PlaintextCrypto {
+      mask: [0xff, 0xaa, 0x55, 0x0f, 0xf0],
+    };
+    let packets = [
+      handshake(&[0x01, 0x02, 0x03]),
+      Packet::OneRtt(OneRtt {
+        dst_cid: cid(&[0x01, 0x02, 0x03]),
+        spin: 1,
+        key_phase: 1,
+        packet_number: PacketNumber::from(0x1234u32),
+      }),
+    ];
+    for packet in packets {
+      let mut buf = Vec::new();
+      let header = packet.encode_header(24, None, &mut buf).unwrap();
+      buf.extend_from_slice(&[0x44; 24]);
+      let unprotected = buf.clone();
+      header.protect(&mut buf, crypto.mask);
+      assert_ne!(buf, unprotected);
+      // Only the first byte and the packet number are protected
+      assert_eq!(
+        buf[1..header.packet_number_offset()],
+        unprotected[1..header.packet_number_offset()]
+      );
+      assert_eq!(buf[header.len..], unprotected[header.len..]);
+
+      let (parsed, _) = Packet::parse(&crypto, &buf, &ctx(), &PacketNumberSpaces::default())
+        .await
+        .unwrap();
+      assert_eq!(parsed, packet);
+    }
+  }
+
+  #[tokio::test]
+  async fn reserved_bits_fail_once_unprotected() {
+    let crypto = PlaintextCrypto {
+      mask: [0xff, 0xaa, 0x55, 0x0f, 0xf0],
+    };
+    let one_rtt = Packet::OneRtt(OneRtt {
+      dst_cid: cid(&[0x01, 0x02, 0x03]),
+      spin: 0,
+      key_phase: 0,
+      packet_number: PacketNumber::from(0x1234u32),
+    });
+    let initial = Packet::Initial(Initial {
+      src_cid: cid(&[0x09]),
+      dst_cid: cid(&[0x01, 0x02, 0x03]),
+      version: 1,
+      token: &[],
+      packet_number: PacketNumber::from(0u32),
+    });
+    let packets = [
+      (initial, 0b0000_1000),
+      (handshake(&[0x01, 0x02, 0x03]), 0b0000_0100),
+      (one_rtt, 0b0001_0000),
+    ];
+    for (packet, reserved_bit) in packets {
+      let mut buf = Vec::new();
+      let header = packet.encode_header(24, None, &mut buf).unwrap();
+      buf.extend_from_slice(&[0x44; 24]);
+      buf[0] |= reserved_bit;
+      header.protect(&mut buf, crypto.mask);
+
+      let err = Packet::parse(&crypto, &buf, &ctx(), &PacketNumberSpaces::default())
+        .await
+        .unwrap_err();
+      assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+      assert_eq!(err.context().unwrap().field, Some("Reserved Bits"));
+    }
+  }
+
+  #[test]
+  fn protect_rfc_client_initial_header() {
+    // https://datatracker.ietf.org/doc/html/rfc9001#name-client-initial
+    // The example uses a 4 byte packet number, which we would never pick
+    let header = EncodedHeader {
+      len: 22,
+      packet_number_len: 4,
+    };
+    let mut buf = vec![
+      0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0x00,
+      0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
+    ];
+    header.protect(&mut buf, [0x43, 0x7b, 0x9a, 0xec, 0x36]);
+    assert_eq!(
+      buf,
+      [
+        0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0x00,
+        0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34
+      ]
+    );
+  }
+
+  #[test]
+  fn short_packet_has_no_hp_sample() {
+    let packet = handshake(&[0x01]);
+    let mut buf = Vec::new();
+    let header = packet.encode_header(18, None, &mut buf).unwrap();
+    buf.extend_from_slice(&[0; 18]);
+    assert!(header.hp_sample(&buf).is_err());
+    buf.push(0);
+    assert!(header.hp_sample(&buf).is_ok());
+  }
+
+  #[test]
+  fn one_rtt_header_encodes() {
+    let packet = Packet::OneRtt(OneRtt {
+      dst_cid: cid(&[0x11, 0x22, 0x33]),
+      spin: 1,
+      key_phase: 1,
+      packet_number: PacketNumber::from(0x0203u32),
+    });
+    let mut buf = Vec::new();
+    let header = packet.encode_header(10, None, &mut buf).unwrap();
+    assert_eq!(buf, [0x65, 0x11, 0x22, 0x33, 0x02, 0x03]);
+    assert_eq!(header.len, 6);
+    assert_eq!(header.packet_number_len, 2);
+    assert_eq!(header.packet_number_offset(), 4);
+  }
+
+  #[tokio::test]
+  async fn packet_number_truncated_against_largest_acked() {
+    let packet = Packet::Handshake(Handshake {
+      src_cid: cid(&[]),
+      dst_cid: cid(&[]),
+      version: 1,
+      packet_number: PacketNumber::from(0xac5c02u32),
+    });
+    let largest_acked = Some(PacketNumber::from(0xabe8b3u32));
+    let mut buf = Vec::new();
+    let header = packet.encode_header(20, largest_acked, &mut buf).unwrap();
+    assert_eq!(header.packet_number_len, 2);
+    assert_eq!(buf[header.packet_number_offset()..], [0x5c, 0x02]);
+    buf.extend_from_slice(&[0; 20]);
+
+    let mut largest_received = PacketNumberSpaces::default();
+    largest_received[PacketNumberSpace::Handshake] = Some(PacketNumber::from(0xac5c01u32));
+    let (parsed, _) = Packet::parse(&PLAINTEXT, &buf, &ctx(), &largest_received)
+      .await
+      .unwrap();
+    assert_eq!(parsed, packet);
+
+    // Without the largest packet number the truncated value is all we have
+    let (parsed, _) = Packet::parse(&PLAINTEXT, &buf, &ctx(), &PacketNumberSpaces::default())
+      .await
+      .unwrap();
+    assert_eq!(parsed.packet_number(), Some(PacketNumber::from(0x5c02u32)));
+  }
+
+  #[test]
+  fn encode_header_into_short_slice_fails() {
+    let packet = Packet::Handshake(Handshake {
+      src_cid: cid(&[0x01; 8]),
+      dst_cid: cid(&[0x02; 8]),
+      version: 1,
+      packet_number: PacketNumber::from(0u32),
+    });
+    let mut buf = [0u8; 16];
+    assert!(packet.encode_header(10, None, &mut &mut buf[..]).is_err());
+  }
+}
@@ -0,0 +1,666 @@
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use quik_util::*;
+
+use crate::ack::MIN_ACK_DELAY;
+use crate::datagram::MAX_DATAGRAM_FRAME_SIZE;
+use crate::version::{VersionInformation, VERSION_INFORMATION};
+use crate::wire::packet::GREASE_QUIC_BIT;
+use crate::wire::{ConnectionId, Side, StreamId, VarInt};
+
+// https://datatracker.ietf.org/doc/html/rfc9000#name-transport-parameter-definit
+const ORIGINAL_DESTINATION_CONNECTION_ID: u64 = 0x00;
+const MAX_IDLE_TIMEOUT: u64 = 0x01;
+const STATELESS_RESET_TOKEN: u64 = 0x02;
+const MAX_UDP_PAYLOAD_SIZE: u64 = 0x03;
+const INITIAL_MAX_DATA: u64 = 0x04;
+const INITIAL_MAX_STREAM_DATA_BIDI_LOCAL: u64 = 0x05;
+const INITIAL_MAX_STREAM_DATA_BIDI_REMOTE: u64 = 0x06;
+const INITIAL_MAX_STREAM_DATA_UNI: u64 = 0x07;
+const INITIAL_MAX_STREAMS_BIDI: u64 = 0x08;
+const INITIAL_MAX_STREAMS_UNI: u64 = 0x09;
+const ACK_DELAY_EXPONENT: u64 = 0x0a;
+const MAX_ACK_DELAY: u64 = 0x0b;
+const DISABLE_ACTIVE_MIGRATION: u64 = 0x0c;
+const PREFERRED_ADDRESS: u64 = 0x0d;
+const ACTIVE_CONNECTION_ID_LIMIT: u64 = 0x0e;
+const INITIAL_SOURCE_CONNECTION_ID: u64 = 0x0f;
+const RETRY_SOURCE_CONNECTION_ID: u64 = 0x10;
+
+// Parameters that only a server can send
+// https://datatracker.ietf.org/doc/html/rfc9000#section-18.2-4.8
+const SERVER_ONLY: [u64; 4] = [
+  ORIGINAL_DESTINATION_CONNECTION_ID,
+  STATELESS_RESET_TOKEN,
+  PREFERRED_ADDRESS,
+  RETRY_SOURCE_CONNECTION_ID,
+];
+
+const DEFAULT_MAX_UDP_PAYLOAD_SIZE: u32 = 65527;
+const MIN_MAX_UDP_PAYLOAD_SIZE: u64 = 1200;
+const DEFAULT_ACK_DELAY_EXPONENT: u32 = 3;
+const MAX_ACK_DELAY_EXPONENT: u64 = 20;
+const DEFAULT_MAX_ACK_DELAY: u32 = 25;
+const MAX_MAX_ACK_DELAY: u64 = 1 << 14;
+const DEFAULT_ACTIVE_CONNECTION_ID_LIMIT: u32 = 2;
+
+fn transport_parameter_error(reason: &'static str) -> Error {
+  Error::transport(TransportErrorCode::TransportParameterError, reason)
+}
+
+// Address a server would rather have the client migrate to after the handshake
+// https://datatracker.ietf.org/doc/html/rfc9000#name-preferred-address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreferredAddress {
+  // Unspecified with port 0 when the server has no address of the family
+  pub ipv4: SocketAddrV4,
+  pub ipv6: SocketAddrV6,
+  pub cid: ConnectionId,
+  pub stateless_reset_token: u128,
+}
+
+impl PreferredAddress {
+  fn parse(mut data: &[u8]) -> Result<Self> {
+    let ipv4 = Ipv4Addr::from(data.read_u32::<NetworkEndian>()?);
+    let ipv4_port = data.read_u16::<NetworkEndian>()?;
+    let ipv6 = Ipv6Addr::from(data.read_u128::<NetworkEndian>()?);
+    let ipv6_port = data.read_u16::<NetworkEndian>()?;
+    let cid = ConnectionId::parse(&mut data)?;
+    // The client would have no way to use the address otherwise
+    if cid.is_empty() {
+      Err(transport_parameter_error(
+        "Preferred address with an empty Connection ID",
+      ))?;
+    }
+    let stateless_reset_token = data.read_u128::<NetworkEndian>()?;
+    if !data.is_empty() {
+      Err(transport_parameter_error("Preferred address too long"))?;
+    }
+    Ok(Self {
+      ipv4: SocketAddrV4::new(ipv4, ipv4_port),
+      ipv6: SocketAddrV6::new(ipv6, ipv6_port, 0, 0),
+      cid,
+      stateless_reset_token,
+    })
+  }
+
+  fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    dst.write_all(&self.ipv4.ip().octets())?;
+    dst.write_u16::<NetworkEndian>(self.ipv4.port())?;
+    dst.write_all(&self.ipv6.ip().octets())?;
+    dst.write_u16::<NetworkEndian>(self.ipv6.port())?;
+    self.cid.encode(dst)?;
+    dst.write_u128::<NetworkEndian>(self.stateless_reset_token)?;
+    Ok(())
+  }
+}
+
+// Contents of the quic_transport_parameters TLS extension. Durations are in
+// milliseconds unless noted otherwise, and absent parameters take their
+// default values.
+// https://datatracker.ietf.org/doc/html/rfc9000#name-transport-parameter-encodin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportParameters {
+  pub original_destination_connection_id: Option<ConnectionId>,
+  // 0 disables the idle timeout
+  pub max_idle_timeout: VarInt,
+  pub stateless_reset_token: Option<u128>,
+  pub max_udp_payload_size: VarInt,
+  pub initial_max_data: VarInt,
+  pub initial_max_stream_data_bidi_local: VarInt,
+  pub initial_max_stream_data_bidi_remote: VarInt,
+  pub initial_max_stream_data_uni: VarInt,
+  pub initial_max_streams_bidi: VarInt,
+  pub initial_max_streams_uni: VarInt,
+  pub ack_delay_exponent: VarInt,
+  pub max_ack_delay: VarInt,
+  pub disable_active_migration: bool,
+  pub preferred_address: Option<PreferredAddress>,
+  pub active_connection_id_limit: VarInt,
+  pub initial_source_connection_id: Option<ConnectionId>,
+  pub retry_source_connection_id: Option<ConnectionId>,
+  pub version_information: Option<VersionInformation>,
+  pub max_datagram_frame_size: Option<VarInt>,
+  pub grease_quic_bit: bool,
+  // In microseconds
+  pub min_ack_delay: Option<VarInt>,
+  // Parameters we don't know about, kept in the order they were received
+  pub unknown: Vec<(VarInt, Vec<u8>)>,
+}
+
+impl Default for TransportParameters {
+  fn default() -> Self {
+    Self {
+      original_destination_connection_id: None,
+      max_idle_timeout: VarInt::ZERO,
+      stateless_reset_token: None,
+      max_udp_payload_size: DEFAULT_MAX_UDP_PAYLOAD_SIZE.into(),
+      initial_max_data: VarInt::ZERO,
+      initial_max_stream_data_bidi_local: VarInt::ZERO,
+      initial_max_stream_data_bidi_remote: VarInt::ZERO,
+      initial_max_stream_data_uni: VarInt::ZERO,
+      initial_max_streams_bidi: VarInt::ZERO,
+      initial_max_streams_uni: VarInt::ZERO,
+      ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT.into(),
+      max_ack_delay: DEFAULT_MAX_ACK_DELAY.into(),
+      disable_active_migration: false,
+      preferred_address: None,
+      active_connection_id_limit: DEFAULT_ACTIVE_CONNECTION_ID_LIMIT.into(),
+      initial_source_connection_id: None,
+      retry_source_connection_id: None,
+      version_information: None,
+      max_datagram_frame_size: None,
+      grease_quic_bit: false,
+      min_ack_delay: None,
+      unknown: Vec::new(),
+    }
+  }
+}
+
+// Integer parameters are a single variable-length integer filling the value
+fn parse_varint(mut value: &[u8]) -> Result<VarInt> {
+  let varint = VarInt::parse(&mut value)?;
+  if !value.is_empty() {
+    Err(transport_parameter_error(
+      "Integer transport parameter too long",
+    ))?;
+  }
+  Ok(varint)
+}
+
+fn parse_token(mut value: &[u8]) -> Result<u128> {
+  let token = value.read_u128::<NetworkEndian>()?;
+  if !value.is_empty() {
+    Err(transport_parameter_error("Stateless reset token too long"))?;
+  }
+  Ok(token)
+}
+
+fn parse_flag(value: &[u8]) -> Result<bool> {
+  if !value.is_empty() {
+    Err(transport_parameter_error(
+      "Flag transport parameter has a value",
+    ))?;
+  }
+  Ok(true)
+}
+
+fn encode_param(id: u64, value: &[u8], dst: &mut impl WriteBytesExt) -> Result<()> {
+  VarInt::try_from(id)?.encode(dst)?;
+  VarInt::try_from(value.len())?.encode(dst)?;
+  dst.write_all(value)?;
+  Ok(())
+}
+
+fn encode_varint_param(id: u64, value: VarInt, dst: &mut impl WriteBytesExt) -> Result<()> {
+  let mut buf = Vec::with_capacity(8);
+  value.encode(&mut buf)?;
+  encode_param(id, &buf, dst)
+}
+
+impl TransportParameters {
+  // Parses the parameters sent by `sender`. Any violation is a
+  // TRANSPORT_PARAMETER_ERROR.
+  // https://datatracker.ietf.org/doc/html/rfc9000#section-7.4-2
+  pub fn parse(data: &[u8], sender: Side) -> Result<Self> {
+    Self::parse_unclassified(data, sender)
+      .map_err(|err| err.classify(TransportErrorCode::TransportParameterError, None))
+  }
+
+  fn parse_unclassified(mut data: &[u8], sender: Side) -> Result<Self> {
+    let mut params = Self::default();
+    let mut seen = HashSet::new();
+    while !data.is_empty() {
+      let id = VarInt::parse(&mut data)?;
+      let length = VarInt::parse(&mut data)?;
+      let value = data.slice(length.try_into()?)?;
+
+      let id = id.into_inner();
+      if !seen.insert(id) {
+        Err(transport_parameter_error("Duplicate transport parameter"))?;
+      }
+      if sender == Side::Client && SERVER_ONLY.contains(&id) {
+        Err(transport_parameter_error(
+          "Server-only transport parameter sent by a client",
+        ))?;
+      }
+
+      match id {
+        ORIGINAL_DESTINATION_CONNECTION_ID => {
+          params.original_destination_connection_id = Some(ConnectionId::new(value)?);
+        }
+        MAX_IDLE_TIMEOUT => params.max_idle_timeout = parse_varint(value)?,
+        STATELESS_RESET_TOKEN => params.stateless_reset_token = Some(parse_token(value)?),
+        MAX_UDP_PAYLOAD_SIZE => params.max_udp_payload_size = parse_varint(value)?,
+        INITIAL_MAX_DATA => params.initial_max_data = parse_varint(value)?,
+        INITIAL_MAX_STREAM_DATA_BIDI_LOCAL => {
+          params.initial_max_stream_data_bidi_local = parse_varint(value)?;
+        }
+        INITIAL_MAX_STREAM_DATA_BIDI_REMOTE => {
+          params.initial_max_stream_data_bidi_remote = parse_varint(value)?;
+        }
+        INITIAL_MAX_STREAM_DATA_UNI => params.initial_max_stream_data_uni = parse_varint(value)?,
+        INITIAL_MAX_STREAMS_BIDI => params.initial_max_streams_bidi = parse_varint(value)?,
+        INITIAL_MAX_STREAMS_UNI => params.initial_max_streams_uni = parse_varint(value)?,
+        ACK_DELAY_EXPONENT => params.ack_delay_exponent = parse_varint(value)?,
+        MAX_ACK_DELAY => params.max_ack_delay = parse_varint(value)?,
+        DISABLE_ACTIVE_MIGRATION => params.disable_active_migration = parse_flag(value)?,
+        PREFERRED_ADDRESS => params.preferred_address = Some(PreferredAddress::parse(value)?),
+        ACTIVE_CONNECTION_ID_LIMIT => params.active_connection_id_limit = parse_varint(value)?,
+        INITIAL_SOURCE_CONNECTION_ID => {
+          params.initial_source_connection_id = Some(ConnectionId::new(value)?);
+        }
+        RETRY_SOURCE_CONNECTION_ID => {
+          params.retry_source_connection_id = Some(ConnectionId::new(value)?);
+        }
+        VERSION_INFORMATION => {
+          params.version_information = Some(VersionInformation::parse(value)?);
+        }
+        MAX_DATAGRAM_FRAME_SIZE => params.max_datagram_frame_size = Some(parse_varint(value)?),
+        GREASE_QUIC_BIT => params.grease_quic_bit = parse_flag(value)?,
+        MIN_ACK_DELAY => params.min_ack_delay = Some(parse_varint(value)?),
+        _ => params.unknown.push((id.try_into()?, value.to_vec())),
+      }
+    }
+    params.validate()?;
+    Ok(params)
+  }
+
+  // Checks the values against the limits of their parameters
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-transport-parameter-definit
+  pub fn validate(&self) -> Result<()> {
+    if self.max_udp_payload_size.into_inner() < MIN_MAX_UDP_PAYLOAD_SIZE {
+      Err(transport_parameter_error("max_udp_payload_size below 1200"))?;
+    }
+    if self.ack_delay_exponent.into_inner() > MAX_ACK_DELAY_EXPONENT {
+      Err(transport_parameter_error("ack_delay_exponent above 20"))?;
+    }
+    if self.max_ack_delay.into_inner() >= MAX_MAX_ACK_DELAY {
+      Err(transport_parameter_error("max_ack_delay of 2^14 or more"))?;
+    }
+    // https://datatracker.ietf.org/doc/html/rfc9000#section-4.6-2
+    let max_streams = StreamId::MAX_INDEX + 1;
+    if self.initial_max_streams_bidi.into_inner() > max_streams
+      || self.initial_max_streams_uni.into_inner() > max_streams
+    {
+      Err(transport_parameter_error("initial_max_streams above 2^60"))?;
+    }
+    if self.active_connection_id_limit.into_inner() < 2 {
+      Err(transport_parameter_error(
+        "active_connection_id_limit below 2",
+      ))?;
+    }
+    // https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency#name-negotiating-extension-use
+    let max_ack_delay_us = self.max_ack_delay.into_inner() * 1000;
+    if self
+      .min_ack_delay
+      .is_some_and(|min_ack_delay| min_ack_delay.into_inner() > max_ack_delay_us)
+    {
+      Err(transport_parameter_error(
+        "min_ack_delay above max_ack_delay",
+      ))?;
+    }
+    Ok(())
+  }
+
+  // Checks the Connection IDs the peer used during the handshake against the
+  // ones it sent, so that they can't have been tampered with. The original
+  // Destination Connection ID and the Retry Source Connection ID are only
+  // checked on the client.
+  // https://datatracker.ietf.org/doc/html/rfc9000#name-authenticating-connection-i
+  pub fn authenticate_cids(
+    &self,
+    initial_src_cid: &ConnectionId,
+    original_dst_cid: Option<&ConnectionId>,
+    retry_src_cid: Option<&ConnectionId>,
+  ) -> Result<()> {
+    if self.initial_source_connection_id.as_ref() != Some(initial_src_cid) {
+      Err(transport_parameter_error(
+        "initial_source_connection_id mismatch",
+      ))?;
+    }
+    if original_dst_cid.is_some()
+      && self.original_destination_connection_id.as_ref() != original_dst_cid
+    {
+      Err(transport_parameter_error(
+        "original_destination_connection_id mismatch",
+      ))?;
+    }
+    if self.retry_source_connection_id.as_ref() != retry_src_cid {
+      Err(transport_parameter_error(
+        "retry_source_connection_id mismatch",
+      ))?;
+    }
+    Ok(())
+  }
+
+  // Parameters with their default value are left out
+  pub fn encode(&self, dst: &mut impl WriteBytesExt) -> Result<()> {
+    let defaults = Self::default();
+    let cids = [
+      (
+        ORIGINAL_DESTINATION_CONNECTION_ID,
+        &self.original_destination_connection_id,
+      ),
+      (
+        INITIAL_SOURCE_CONNECTION_ID,
+        &self.initial_source_connection_id,
+      ),
+      (RETRY_SOURCE_CONNECTION_ID, &self.retry_source_connection_id),
+    ];
+    for (id, cid) in cids {
+      if let Some(cid) = cid {
+        encode_param(id, cid.as_bytes(), dst)?;
+      }
+    }
+    if let Some(token) = self.stateless_reset_token {
+      encode_param(STATELESS_RESET_TOKEN, &token.to_be_bytes(), dst)?;
+    }
+
+    let varints = [
+      (
+        MAX_IDLE_TIMEOUT,
+        self.max_idle_timeout,
+        defaults.max_idle_timeout,
+      ),
+      (
+        MAX_UDP_PAYLOAD_SIZE,
+        self.max_udp_payload_size,
+        defaults.max_udp_payload_size,
+      ),
+      (
+        INITIAL_MAX_DATA,
+        self.initial_max_data,
+        defaults.initial_max_data,
+      ),
+      (
+        INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+        self.initial_max_stream_data_bidi_local,
+        defaults.initial_max_stream_data_bidi_local,
+      ),
+      (
+        INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+        self.initial_max_stream_data_bidi_remote,
+        defaults.initial_max_stream_data_bidi_remote,
+      ),
+      (
+        INITIAL_MAX_STREAM_DATA_UNI,
+        self.initial_max_stream_data_uni,
+        defaults.initial_max_stream_data_uni,
+      ),
+      (
+        INITIAL_MAX_STREAMS_BIDI,
+        self.initial_max_streams_bidi,
+        defaults.initial_max_streams_bidi,
+      ),
+      (
+        INITIAL_MAX_STREAMS_UNI,
+        self.initial_max_streams_uni,
+        defaults.initial_max_streams_uni,
+      ),
+      (
+        ACK_DELAY_EXPONENT,
+        self.ack_delay_exponent,
+        defaults.ack_delay_exponent,
+      ),
+      (MAX_ACK_DELAY, self.max_ack_delay, defaults.max_ack_delay),
+      (
+        ACTIVE_CONNECTION_ID_LIMIT,
+        self.active_connection_id_limit,
+        defaults.active_connection_id_limit,
+      ),
+    ];
+    for (id, value, default) in varints {
+      if value != default {
+        encode_varint_param(id, value, dst)?;
+      }
+    }
+    for (id, value) in [
+      (MAX_DATAGRAM_FRAME_SIZE, self.max_datagram_frame_size),
+      (MIN_ACK_DELAY, self.min_ack_delay),
+    ] {
+      if let Some(value) = value {
+        encode_varint_param(id, value, dst)?;
+      }
+    }
+
+    for (id, set) in [
+      (DISABLE_ACTIVE_MIGRATION, self.disable_active_migration),
+      (GREASE_QUIC_BIT, self.grease_quic_bit),
+    ] {
+      if set {
+        encode_param(id, &[], dst)?;
+      }
+    }
+    if let Some(preferred_address) = &self.preferred_address {
+      let mut value = Vec::new();
+      preferred_address.encode(&mut value)?;
+      encode_param(PREFERRED_ADDRESS, &value, dst)?;
+    }
+    if let Some(version_information) = &self.version_information {
+      let mut value = Vec::with_capacity(version_information.encoded_len());
+      version_information.encode(&mut value)?;
+      encode_param(VERSION_INFORMATION, &value, dst)?;
+    }
+    for (id, value) in &self.unknown {
+      encode_param(id.into_inner(), value, dst)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wire::{VERSION_1, VERSION_2};
+
+  fn cid(bytes: &[u8]) -> ConnectionId {
+    ConnectionId::new(bytes).unwrap()
+  }
+
+  fn encode(params: &TransportParameters) -> Vec<u8> {
+    let mut buf = Vec::new();
+    params.encode(&mut buf).unwrap();
+    buf
+  }
+
+  fn server_params() -> TransportParameters {
+    TransportParameters {
+      original_destination_connection_id: Some(cid(&[0x83, 0x94])),
+      max_idle_timeout: 30_000u32.into(),
+      stateless_reset_token: Some(0x0011_2233_4455_6677_8899_aabb_ccdd_eeff),
+      max_udp_payload_size: 1472u32.into(),
+      initial_max_data: 1_000_000u32.into(),
+      initial_max_stream_data_bidi_local: 1u32.into(),
+      initial_max_stream_data_bidi_remote: 2u32.into(),
+      initial_max_stream_data_uni: 3u32.into(),
+      initial_max_streams_bidi: 100u32.into(),
+      initial_max_streams_uni: 10u32.into(),
+      ack_delay_exponent: 8u32.into(),
+      max_ack_delay: 50u32.into(),
+      disable_active_migration: true,
+      preferred_address: Some(PreferredAddress {
+        ipv4: "192.0.2.1:443".parse().unwrap(),
+        ipv6: "[2001:db8::1]:443".parse().unwrap(),
+        cid: cid(&[0x01, 0x02, 0x03, 0x04]),
+        stateless_reset_token: 0xffff,
+      }),
+      active_connection_id_limit: 8u32.into(),
+      initial_source_connection_id: Some(cid(&[0xaa])),
+      retry_source_connection_id: Some(cid(&[0xbb, 0xcc])),
+      version_information: Some(VersionInformation {
+        chosen_version: VERSION_2,
+        available_versions: vec![VERSION_2, VERSION_1],
+      }),
+      max_datagram_frame_size: Some(1200u32.into()),
+      grease_quic_bit: true,
+      min_ack_delay: Some(1000u32.into()),
+      unknown: vec![(0x1bu32.into(), vec![0x01, 0x02]), (0x42u32.into(), vec![])],
+    }
+  }
+
+  #[test]
+  fn transport_parameters_round_trip() {
+    let params = server_params();
+    let buf = encode(&params);
+    assert_eq!(
+      TransportParameters::parse(&buf, Side::Server).unwrap(),
+      params
+    );
+
+    let params = TransportParameters::default();
+    assert!(encode(&params).is_empty());
+    assert_eq!(
+      TransportParameters::parse(&[], Side::Client).unwrap(),
+      params
+    );
+  }
+
+  #[test]
+  fn transport_parameters_encoding() {
+    let params = TransportParameters {
+      max_idle_timeout: 30_000u32.into(),
+      initial_source_connection_id: Some(cid(&[0xaa])),
+      grease_quic_bit: true,
+      unknown: vec![(0x1bu32.into(), vec![0x07])],
+      ..Default::default()
+    };
+    assert_eq!(
+      encode(&params),
+      [0x0f, 0x01, 0xaa, 0x01, 0x04, 0x80, 0x00, 0x75, 0x30, 0x6a, 0xb2, 0x00, 0x1b, 0x01, 0x07]
+    );
+  }
+
+  fn parse_err(buf: &[u8], sender: Side) -> TransportErrorCode {
+    TransportParameters::parse(buf, sender).unwrap_err().code()
+  }
+
+  #[test]
+  fn transport_parameters_malformed_fails() {
+    let cases: &[&[u8]] = &[
+      // Truncated value
+      &[0x01, 0x02, 0x05],
+      // Integer with trailing bytes
+      &[0x01, 0x02, 0x05, 0x00],
+      // Flag with a value
+      &[0x0c, 0x01, 0x00],
+      // Short stateless reset token
+      &[0x02, 0x01, 0x00],
+      // Connection ID longer than 20 bytes
+      &[
+        0x0f, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+      ],
+      // Duplicate
+      &[0x01, 0x01, 0x05, 0x01, 0x01, 0x05],
+    ];
+    for buf in cases {
+      assert_eq!(
+        parse_err(buf, Side::Server),
+        TransportErrorCode::TransportParameterError
+      );
+    }
+  }
+
+  #[test]
+  fn transport_parameters_limits() {
+    let cases = [
+      TransportParameters {
+        max_udp_payload_size: 1199u32.into(),
+        ..Default::default()
+      },
+      TransportParameters {
+        ack_delay_exponent: 21u32.into(),
+        ..Default::default()
+      },
+      TransportParameters {
+        max_ack_delay: (1u32 << 14).into(),
+        ..Default::default()
+      },
+      TransportParameters {
+        initial_max_streams_uni: VarInt::try_from((1u64 << 60) + 1).unwrap(),
+        ..Default::default()
+      },
+      TransportParameters {
+        active_connection_id_limit: 1u32.into(),
+        ..Default::default()
+      },
+      TransportParameters {
+        min_ack_delay: Some(25_001u32.into()),
+        ..Default::default()
+      },
+    ];
+    for params in cases {
+      assert!(params.validate().is_err());
+      assert_eq!(
+        parse_err(&encode(&params), Side::Server),
+        TransportErrorCode::TransportParameterError
+      );
+    }
+  }
+
+  #[test]
+  fn server_only_parameters_from_client_fail() {
+    let params = server_params();
+    let buf = encode(&params);
+    assert_eq!(
+      parse_err(&buf, Side::Client),
+      TransportErrorCode::TransportParameterError
+    );
+
+    let client_params = TransportParameters {
+      original_destination_connection_id: None,
+      stateless_reset_token: None,
+      preferred_address: None,
+      retry_source_connection_id: None,
+      ..params
+    };
+    let buf = encode(&client_params);
+    assert_eq!(
+      TransportParameters::parse(&buf, Side::Client).unwrap(),
+      client_params
+    );
+  }
+
+  #[test]
+  fn preferred_address_needs_cid() {
+    let mut params = server_params();
+    if let Some(preferred_address) = &mut params.preferred_address {
+      preferred_address.cid = ConnectionId::EMPTY;
+    }
+    assert_eq!(
+      parse_err(&encode(&params), Side::Server),
+      TransportErrorCode::TransportParameterError
+    );
+  }
+
+  #[test]
+  fn authenticate_cids() {
+    let params = server_params();
+    let (initial, original, retry) = (cid(&[0xaa]), cid(&[0x83, 0x94]), cid(&[0xbb, 0xcc]));
+    assert!(params
+      .authenticate_cids(&initial, Some(&original), Some(&retry))
+      .is_ok());
+
+    assert!(params
+      .authenticate_cids(&cid(&[0xab]), Some(&original), Some(&retry))
+      .is_err());
+    assert!(params
+      .authenticate_cids(&initial, Some(&cid(&[0x83])), Some(&retry))
+      .is_err());
+    let err = params
+      .authenticate_cids(&initial, Some(&original), None)
+      .unwrap_err();
+    assert_eq!(err.code(), TransportErrorCode::TransportParameterError);
+
+    let client_params = TransportParameters {
+      initial_source_connection_id: Some(initial.clone()),
+      ..Default::default()
+    };
+    assert!(client_params
+      .authenticate_cids(&initial, None, None)
+      .is_ok());
+    let missing = TransportParameters::default();
+    assert!(missing.authenticate_cids(&initial, None, None).is_err());
+  }
+}
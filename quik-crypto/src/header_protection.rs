@@ -0,0 +1,88 @@
+use quik_core::crypto::HP_SAMPLE_LEN;
+use quik_util::*;
+use ring::aead::quic;
+
+// Header protection key for one of the algorithms of the negotiated AEAD
+// https://datatracker.ietf.org/doc/html/rfc9001#name-header-protection
+pub struct HeaderProtectionKey(quic::HeaderProtectionKey);
+
+impl HeaderProtectionKey {
+  fn new(algorithm: &'static quic::Algorithm, key: &[u8]) -> Result<Self> {
+    let key = quic::HeaderProtectionKey::new(algorithm, key)
+      .map_err(|_| "Invalid header protection key")?;
+    Ok(Self(key))
+  }
+
+  // Used with AEAD_AES_128_GCM and AEAD_AES_128_CCM
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-aes-based-header-protection
+  pub fn aes_128(key: &[u8]) -> Result<Self> {
+    Self::new(&quic::AES_128, key)
+  }
+
+  // Used with AEAD_AES_256_GCM
+  pub fn aes_256(key: &[u8]) -> Result<Self> {
+    Self::new(&quic::AES_256, key)
+  }
+
+  // Used with AEAD_CHACHA20_POLY1305
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-chacha20-based-header-prote
+  pub fn chacha20(key: &[u8]) -> Result<Self> {
+    Self::new(&quic::CHACHA20, key)
+  }
+
+  pub fn mask(&self, sample: &[u8; HP_SAMPLE_LEN]) -> Result<[u8; 5]> {
+    Ok(
+      self
+        .0
+        .new_mask(sample)
+        .map_err(|_| "Invalid header protection sample")?,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-client-initial
+  #[test]
+  fn aes_128_mask() {
+    let key = [
+      0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad, 0xed,
+      0xd2,
+    ];
+    let sample = [
+      0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68, 0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc,
+      0x9b,
+    ];
+    let mask = HeaderProtectionKey::aes_128(&key)
+      .unwrap()
+      .mask(&sample)
+      .unwrap();
+    assert_eq!(mask, [0x43, 0x7b, 0x9a, 0xec, 0x36]);
+  }
+
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-chacha20-poly1305-short-hea
+  #[test]
+  fn chacha20_mask() {
+    let key = [
+      0x25, 0xa2, 0x82, 0xb9, 0xe8, 0x2f, 0x06, 0xf2, 0x1f, 0x48, 0x89, 0x17, 0xa4, 0xfc, 0x8f,
+      0x1b, 0x73, 0x57, 0x36, 0x85, 0x60, 0x85, 0x97, 0xd0, 0xef, 0xcb, 0x07, 0x6b, 0x0a, 0xb7,
+      0xa7, 0xa4,
+    ];
+    let sample = [
+      0x5e, 0x5c, 0xd5, 0x5c, 0x41, 0xf6, 0x90, 0x80, 0x57, 0x5d, 0x79, 0x99, 0xc2, 0x5a, 0x5b,
+      0xfb,
+    ];
+    let mask = HeaderProtectionKey::chacha20(&key)
+      .unwrap()
+      .mask(&sample)
+      .unwrap();
+    assert_eq!(mask, [0xae, 0xfe, 0xfe, 0x7d, 0x03]);
+  }
+
+  #[test]
+  fn wrong_key_length_fails() {
+    assert!(HeaderProtectionKey::aes_256(&[0; 16]).is_err());
+  }
+}
@@ -0,0 +1,229 @@
+use quik_core::wire::{ConnectionId, VERSION_1, VERSION_2};
+use quik_util::*;
+use ring::hkdf;
+
+// https://datatracker.ietf.org/doc/html/rfc9001#name-initial-secrets
+const INITIAL_SALT_V1: [u8; 20] = [
+  0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+  0xcc, 0xbb, 0x7f, 0x0a,
+];
+// https://datatracker.ietf.org/doc/html/rfc9369#name-initial-salt
+const INITIAL_SALT_V2: [u8; 20] = [
+  0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+  0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+// HKDF labels for deriving packet protection keys from a secret
+// https://datatracker.ietf.org/doc/html/rfc9001#name-packet-protection-keys
+// https://datatracker.ietf.org/doc/html/rfc9369#name-hmac-based-key-derivation-f
+struct Labels {
+  key: &'static [u8],
+  iv: &'static [u8],
+  hp: &'static [u8],
+  ku: &'static [u8],
+}
+
+const LABELS_V1: Labels = Labels {
+  key: b"quic key",
+  iv: b"quic iv",
+  hp: b"quic hp",
+  ku: b"quic ku",
+};
+const LABELS_V2: Labels = Labels {
+  key: b"quicv2 key",
+  iv: b"quicv2 iv",
+  hp: b"quicv2 hp",
+  ku: b"quicv2 ku",
+};
+
+fn labels(version: u32) -> Result<&'static Labels> {
+  match version {
+    VERSION_1 => Ok(&LABELS_V1),
+    VERSION_2 => Ok(&LABELS_V2),
+    _ => Err("Unsupported version")?,
+  }
+}
+
+// Keys for one direction of one encryption level
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketKeys {
+  pub key: [u8; 16],
+  pub iv: [u8; 12],
+  pub hp: [u8; 16],
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+  fn len(&self) -> usize {
+    self.0
+  }
+}
+
+// HKDF-Expand-Label from TLS 1.3, always with an empty context
+// https://datatracker.ietf.org/doc/html/rfc8446#section-7.1
+fn hkdf_expand_label(secret: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Result<()> {
+  const PREFIX: &[u8] = b"tls13 ";
+  let out_len = (out.len() as u16).to_be_bytes();
+  let label_len = [(PREFIX.len() + label.len()) as u8];
+  let info = [&out_len[..], &label_len, PREFIX, label, &[0]];
+  secret
+    .expand(&info, Len(out.len()))
+    .and_then(|okm| okm.fill(out))
+    .map_err(|_| "HKDF-Expand-Label failed")?;
+  Ok(())
+}
+
+fn packet_keys(secret: &[u8], version: u32) -> Result<PacketKeys> {
+  let labels = labels(version)?;
+  let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret);
+  let mut keys = PacketKeys {
+    key: [0; 16],
+    iv: [0; 12],
+    hp: [0; 16],
+  };
+  hkdf_expand_label(&secret, labels.key, &mut keys.key)?;
+  hkdf_expand_label(&secret, labels.iv, &mut keys.iv)?;
+  hkdf_expand_label(&secret, labels.hp, &mut keys.hp)?;
+  Ok(keys)
+}
+
+// Secret for the next key phase, for cipher suites using SHA-256
+// https://datatracker.ietf.org/doc/html/rfc9001#name-key-update
+pub fn next_secret(secret: &[u8; 32], version: u32) -> Result<[u8; 32]> {
+  let labels = labels(version)?;
+  let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret);
+  let mut next = [0; 32];
+  hkdf_expand_label(&secret, labels.ku, &mut next)?;
+  Ok(next)
+}
+
+// Initial keys are derived from the Destination Connection ID of the first
+// Initial packet sent by the client
+pub fn initial_keys(cid: &ConnectionId, version: u32, is_server: bool) -> Result<PacketKeys> {
+  let salt = match version {
+    VERSION_1 => &INITIAL_SALT_V1,
+    VERSION_2 => &INITIAL_SALT_V2,
+    _ => Err("Unsupported version")?,
+  };
+  let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(cid.as_bytes());
+  let label: &[u8] = if is_server {
+    b"server in"
+  } else {
+    b"client in"
+  };
+  let mut secret = [0; 32];
+  hkdf_expand_label(&initial_secret, label, &mut secret)?;
+  packet_keys(&secret, version)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cid(bytes: &[u8]) -> ConnectionId {
+    ConnectionId::new(bytes).unwrap()
+  }
+
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-keys
+  const RFC_CID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+  #[test]
+  fn client_initial_keys_v1() {
+    let keys = initial_keys(&cid(&RFC_CID), 1, false).unwrap();
+    assert_eq!(
+      keys,
+      PacketKeys {
+        key: [
+          0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef, 0xcb, 0xe3, 0xb1, 0xa2,
+          0x2d
+        ],
+        iv: [0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c],
+        hp: [
+          0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad, 0xed,
+          0xd2
+        ],
+      }
+    );
+  }
+
+  #[test]
+  fn server_initial_keys_v1() {
+    let keys = initial_keys(&cid(&RFC_CID), 1, true).unwrap();
+    assert_eq!(
+      keys,
+      PacketKeys {
+        key: [
+          0xcf, 0x3a, 0x53, 0x31, 0x65, 0x3c, 0x36, 0x4c, 0x88, 0xf0, 0xf3, 0x79, 0xb6, 0x06, 0x7e,
+          0x37
+        ],
+        iv: [0x0a, 0xc1, 0x49, 0x3c, 0xa1, 0x90, 0x58, 0x53, 0xb0, 0xbb, 0xa0, 0x3e],
+        hp: [
+          0xc2, 0x06, 0xb8, 0xd9, 0xb9, 0xf0, 0xf3, 0x76, 0x44, 0x43, 0x0b, 0x49, 0x0e, 0xea, 0xa3,
+          0x14
+        ],
+      }
+    );
+  }
+
+  // https://datatracker.ietf.org/doc/html/rfc9369#name-keys
+  #[test]
+  fn client_initial_keys_v2() {
+    let keys = initial_keys(&cid(&RFC_CID), VERSION_2, false).unwrap();
+    assert_eq!(
+      keys,
+      PacketKeys {
+        key: [
+          0x8b, 0x1a, 0x0b, 0xc1, 0x21, 0x28, 0x42, 0x90, 0xa2, 0x9e, 0x09, 0x71, 0xb5, 0xcd, 0x04,
+          0x5d
+        ],
+        iv: [0x91, 0xf7, 0x3e, 0x23, 0x51, 0xd8, 0xfa, 0x91, 0x66, 0x0e, 0x90, 0x9f],
+        hp: [
+          0x45, 0xb9, 0x5e, 0x15, 0x23, 0x5d, 0x6f, 0x45, 0xa6, 0xb1, 0x9c, 0xbc, 0xb0, 0x29, 0x4b,
+          0xa9
+        ],
+      }
+    );
+  }
+
+  #[test]
+  fn server_initial_keys_v2() {
+    let keys = initial_keys(&cid(&RFC_CID), VERSION_2, true).unwrap();
+    assert_eq!(
+      keys,
+      PacketKeys {
+        key: [
+          0x82, 0xdb, 0x63, 0x78, 0x61, 0xd5, 0x5e, 0x1d, 0x01, 0x1f, 0x19, 0xea, 0x71, 0xd5, 0xd2,
+          0xa7
+        ],
+        iv: [0xdd, 0x13, 0xc2, 0x76, 0x49, 0x9c, 0x02, 0x49, 0xd3, 0x31, 0x06, 0x52],
+        hp: [
+          0xed, 0xf6, 0xd0, 0x5c, 0x83, 0x12, 0x12, 0x01, 0xb4, 0x36, 0xe1, 0x68, 0x77, 0x59, 0x3c,
+          0x3a
+        ],
+      }
+    );
+  }
+
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-chacha20-poly1305-short-hea
+  #[test]
+  fn next_secret_v1() {
+    let secret = [
+      0x9a, 0xc3, 0x12, 0xa7, 0xf8, 0x77, 0x46, 0x8e, 0xbe, 0x69, 0x42, 0x27, 0x48, 0xad, 0x00,
+      0xa1, 0x54, 0x43, 0xf1, 0x82, 0x03, 0xa0, 0x7d, 0x60, 0x60, 0xf6, 0x88, 0xf3, 0x0f, 0x21,
+      0x63, 0x2b,
+    ];
+    let next = [
+      0x12, 0x23, 0x50, 0x47, 0x55, 0x03, 0x6d, 0x55, 0x63, 0x42, 0xee, 0x93, 0x61, 0xd2, 0x53,
+      0x42, 0x1a, 0x82, 0x6c, 0x9e, 0xcd, 0xf3, 0xc7, 0x14, 0x86, 0x84, 0xb3, 0x6b, 0x71, 0x48,
+      0x81, 0xf9,
+    ];
+    assert_eq!(next_secret(&secret, VERSION_1).unwrap(), next);
+    assert_ne!(next_secret(&secret, VERSION_2).unwrap(), next);
+  }
+
+  #[test]
+  fn initial_keys_unknown_version_fails() {
+    assert!(initial_keys(&cid(&RFC_CID), 0x0a0a_0a0a, false).is_err());
+  }
+}
@@ -1,19 +1,186 @@
-use quik_core::crypto::Crypto;
+mod header_protection;
+mod initial;
+mod reset;
+mod retry;
+
+pub use header_protection::*;
+pub use initial::*;
+use quik_core::crypto::{Crypto, EncryptionLevel, AEAD_TAG_LEN, HP_SAMPLE_LEN};
 use quik_core::wire::ConnectionId;
 use quik_util::*;
+pub use reset::*;
+pub use retry::*;
 
 pub struct DefaultCrypto;
 
 impl Crypto for DefaultCrypto {
   async fn decrypt_initial_data(
     &self,
-    cid: ConnectionId,
+    _cid: ConnectionId,
+    _version: u32,
+    _is_server: bool,
+    _data: &mut impl Buffer,
+  ) -> Result<Vec<u8>> {
+    todo!()
+  }
+
+  fn header_protection_mask(
+    &self,
+    level: EncryptionLevel,
+    cid: &ConnectionId,
     version: u32,
     is_server: bool,
-    data: &mut impl Buffer,
-  ) -> Result<Vec<u8>> {
-    todo!();
-    // Ok(&[][..])
-    Ok(Vec::new())
+    sample: &[u8; HP_SAMPLE_LEN],
+  ) -> Result<[u8; 5]> {
+    match level {
+      // Initial packets are always protected with AEAD_AES_128_GCM
+      EncryptionLevel::Initial => {
+        let keys = initial_keys(cid, version, is_server)?;
+        HeaderProtectionKey::aes_128(&keys.hp)?.mask(sample)
+      }
+      _ => Err("No keys for encryption level")?,
+    }
+  }
+
+  fn retry_integrity_tag(&self, version: u32, pseudo_packet: &[u8]) -> Result<[u8; AEAD_TAG_LEN]> {
+    retry_integrity_tag(version, pseudo_packet)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use quik_core::handler::Handler;
+  use quik_core::transport::{Connection, Io};
+  use quik_core::wire::packet::{Initial, PacketContext, RemainingBuf};
+  use quik_core::wire::{Frame, Packet, PacketNumber, PacketNumberSpaces, VERSION_1};
+
+  use super::*;
+
+  // Keeps every datagram sent
+  #[derive(Default)]
+  struct RecordIo {
+    sent: std::sync::Mutex<Vec<Vec<u8>>>,
+  }
+
+  impl Io for &RecordIo {
+    async fn send(&self, data: &[u8]) -> Result<()> {
+      self.sent.lock().unwrap().push(data.to_vec());
+      Ok(())
+    }
+
+    async fn recv(&self, _data: &mut [u8]) -> Result<()> {
+      Ok(())
+    }
+
+    async fn close(self) {}
+  }
+
+  // Protects headers like DefaultCrypto but leaves payloads as is, checking
+  // that they'd be decrypted with keys from `key_cid`
+  struct HeaderOnlyCrypto {
+    key_cid: ConnectionId,
+  }
+
+  impl Crypto for HeaderOnlyCrypto {
+    async fn decrypt_initial_data(
+      &self,
+      cid: ConnectionId,
+      _version: u32,
+      _is_server: bool,
+      data: &mut impl Buffer,
+    ) -> Result<Vec<u8>> {
+      if cid != self.key_cid {
+        Err("Wrong Initial keys")?;
+      }
+      let mut payload = Vec::new();
+      data.read_to_end(&mut payload)?;
+      Ok(payload)
+    }
+
+    fn header_protection_mask(
+      &self,
+      level: EncryptionLevel,
+      cid: &ConnectionId,
+      version: u32,
+      is_server: bool,
+      sample: &[u8; HP_SAMPLE_LEN],
+    ) -> Result<[u8; 5]> {
+      DefaultCrypto.header_protection_mask(level, cid, version, is_server, sample)
+    }
+
+    fn retry_integrity_tag(
+      &self,
+      version: u32,
+      pseudo_packet: &[u8],
+    ) -> Result<[u8; AEAD_TAG_LEN]> {
+      DefaultCrypto.retry_integrity_tag(version, pseudo_packet)
+    }
+  }
+
+  struct IgnoreHandler;
+
+  impl Handler for IgnoreHandler {
+    async fn handle<'a>(
+      &self,
+      _packet: Packet<'a>,
+      _frames: impl Iterator<Item = Result<Frame<'a>>>,
+    ) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  fn cid(bytes: &[u8]) -> ConnectionId {
+    ConnectionId::new(bytes).unwrap()
+  }
+
+  // The server's Initials go to the client's Source Connection ID, but are
+  // still protected with keys from the Destination Connection ID the client
+  // first chose
+  #[tokio::test]
+  async fn server_initial_uses_original_dst_cid_keys() {
+    let original_dst_cid = cid(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
+    let crypto = || HeaderOnlyCrypto {
+      key_cid: original_dst_cid.clone(),
+    };
+    let server_ctx = PacketContext {
+      is_server: true,
+      version: VERSION_1,
+      initial_dst_cid: Some(original_dst_cid.clone()),
+      ..Default::default()
+    };
+    let io = RecordIo::default();
+    let server = Connection::new(crypto(), &io, IgnoreHandler, server_ctx);
+    let packet = Packet::Initial(Initial {
+      src_cid: cid(&[0x5e, 0x5e, 0x5e]),
+      dst_cid: cid(&[0xc1, 0xc2]),
+      version: VERSION_1,
+      token: &[],
+      packet_number: PacketNumber::from(7u32),
+    });
+    let frames = [Frame::Ping, Frame::Padding].into_iter();
+    server.send(packet, frames).await.unwrap();
+    let sent = io.sent.lock().unwrap().pop().unwrap();
+
+    let client_ctx = PacketContext {
+      is_server: false,
+      version: VERSION_1,
+      original_dst_cid: Some(original_dst_cid.clone()),
+      ..Default::default()
+    };
+    let spaces = PacketNumberSpaces::default();
+    let (packet, remaining) = Packet::parse(&crypto(), &sent, &client_ctx, &spaces)
+      .await
+      .unwrap();
+    assert_eq!(packet.packet_number(), Some(PacketNumber::from(7u32)));
+    let RemainingBuf::Decrypted { payload, .. } = remaining else {
+      panic!("Initial without payload");
+    };
+    assert_eq!(payload[..2], [0x01, 0x00]);
+
+    // Keys from the Connection ID the packet was sent to don't work
+    let ctx = PacketContext::default();
+    assert!(Packet::parse(&crypto(), &sent, &ctx, &spaces)
+      .await
+      .is_err());
   }
 }
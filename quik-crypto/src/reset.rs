@@ -0,0 +1,61 @@
+use quik_core::reset::{ResetTokenGenerator, RESET_TOKEN_LEN};
+use quik_core::wire::ConnectionId;
+use quik_util::*;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Stateless reset tokens as the truncated HMAC-SHA256 of the Connection ID,
+// keyed with a secret shared by every server that can receive the connection
+// https://datatracker.ietf.org/doc/html/rfc9000#name-calculating-a-stateless-res
+pub struct HmacResetTokenGenerator(hmac::Key);
+
+impl HmacResetTokenGenerator {
+  pub fn new(static_key: &[u8]) -> Self {
+    Self(hmac::Key::new(hmac::HMAC_SHA256, static_key))
+  }
+
+  // Key that only lives as long as this endpoint, so tokens can't be
+  // recomputed after a restart
+  pub fn random() -> Result<Self> {
+    let mut key = [0; 32];
+    SystemRandom::new()
+      .fill(&mut key)
+      .map_err(|_| "Failed to generate stateless reset key")?;
+    Ok(Self::new(&key))
+  }
+}
+
+impl ResetTokenGenerator for HmacResetTokenGenerator {
+  fn token(&self, cid: &ConnectionId) -> Result<u128> {
+    let tag = hmac::sign(&self.0, cid.as_bytes());
+    let token = tag.as_ref()[..RESET_TOKEN_LEN].try_into()?;
+    Ok(u128::from_be_bytes(token))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tokens_depend_on_key_and_cid() {
+    let cid = ConnectionId::new(&[0x01, 0x02, 0x03, 0x04]).unwrap();
+    let other_cid = ConnectionId::new(&[0x01, 0x02, 0x03, 0x05]).unwrap();
+    let generator = HmacResetTokenGenerator::new(b"static key");
+
+    let token = generator.token(&cid).unwrap();
+    assert_eq!(
+      token,
+      HmacResetTokenGenerator::new(b"static key")
+        .token(&cid)
+        .unwrap()
+    );
+    assert_ne!(token, generator.token(&other_cid).unwrap());
+    assert_ne!(
+      token,
+      HmacResetTokenGenerator::new(b"other key")
+        .token(&cid)
+        .unwrap()
+    );
+  }
+}
@@ -0,0 +1,154 @@
+use quik_core::crypto::AEAD_TAG_LEN;
+use quik_core::wire::{VERSION_1, VERSION_2};
+use quik_util::*;
+use ring::aead;
+
+// Fixed key and nonce, as Retry packets are only protected against accidental
+// modification and off-path injection
+// https://datatracker.ietf.org/doc/html/rfc9001#name-retry-packet-integrity
+const RETRY_KEY_V1: [u8; 16] = [
+  0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68, 0xc8, 0x4e,
+];
+const RETRY_NONCE_V1: [u8; 12] = [
+  0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+];
+// https://datatracker.ietf.org/doc/html/rfc9369#name-retry-integrity-tag
+const RETRY_KEY_V2: [u8; 16] = [
+  0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce, 0xad, 0x7c, 0xcc, 0x92,
+];
+const RETRY_NONCE_V2: [u8; 12] = [
+  0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+];
+
+// AEAD_AES_128_GCM tag over an empty plaintext, with the Retry pseudo-packet
+// as the associated data
+pub fn retry_integrity_tag(version: u32, pseudo_packet: &[u8]) -> Result<[u8; AEAD_TAG_LEN]> {
+  let (key, nonce) = match version {
+    VERSION_1 => (&RETRY_KEY_V1, RETRY_NONCE_V1),
+    VERSION_2 => (&RETRY_KEY_V2, RETRY_NONCE_V2),
+    _ => Err("Unsupported version")?,
+  };
+  let key = aead::UnboundKey::new(&aead::AES_128_GCM, key).map_err(|_| "Invalid Retry key")?;
+  let tag = aead::LessSafeKey::new(key)
+    .seal_in_place_separate_tag(
+      aead::Nonce::assume_unique_for_key(nonce),
+      aead::Aad::from(pseudo_packet),
+      &mut [],
+    )
+    .map_err(|_| "Retry integrity tag failed")?;
+  Ok(tag.as_ref().try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+  use quik_core::wire::packet::{PacketContext, Retry};
+  use quik_core::wire::{ConnectionId, Packet, PacketNumberSpaces, ParseLimits};
+
+  use super::*;
+  use crate::DefaultCrypto;
+
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-retry
+  const RETRY_V1: [u8; 36] = [
+    0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5, 0x74,
+    0x6f, 0x6b, 0x65, 0x6e, 0x04, 0xa2, 0x65, 0xba, 0x2e, 0xff, 0x4d, 0x82, 0x90, 0x58, 0xfb, 0x3f,
+    0x0f, 0x24, 0x96, 0xba,
+  ];
+  // https://datatracker.ietf.org/doc/html/rfc9369#name-retry
+  const RETRY_V2: [u8; 36] = [
+    0xcf, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5, 0x74,
+    0x6f, 0x6b, 0x65, 0x6e, 0xc8, 0x64, 0x6c, 0xe8, 0xbf, 0xe3, 0x39, 0x52, 0xd9, 0x55, 0x54, 0x36,
+    0x65, 0xdc, 0xc7, 0xb6,
+  ];
+  const ODCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+  #[test]
+  fn rfc_retry_tag() {
+    let (packet, tag) = RETRY_V1.split_at(RETRY_V1.len() - AEAD_TAG_LEN);
+    let pseudo_packet = [&[ODCID.len() as u8][..], &ODCID, packet].concat();
+    assert_eq!(retry_integrity_tag(1, &pseudo_packet).unwrap(), tag);
+  }
+
+  #[test]
+  fn retry_tag_covers_original_dst_cid() {
+    let (packet, tag) = RETRY_V1.split_at(RETRY_V1.len() - AEAD_TAG_LEN);
+    let pseudo_packet = [&[1][..], &[0x83], packet].concat();
+    assert_ne!(retry_integrity_tag(1, &pseudo_packet).unwrap(), tag);
+  }
+
+  fn client_ctx() -> PacketContext {
+    PacketContext {
+      is_server: false,
+      local_cid_len: 0,
+      version: 1,
+      original_dst_cid: Some(ConnectionId::new(&ODCID).unwrap()),
+      initial_dst_cid: None,
+      grease_quic_bit: false,
+      limits: ParseLimits::default(),
+    }
+  }
+
+  #[tokio::test]
+  async fn rfc_retry_packet_is_accepted() {
+    let (packet, _) = Packet::parse(
+      &DefaultCrypto,
+      &RETRY_V1,
+      &client_ctx(),
+      &PacketNumberSpaces::default(),
+    )
+    .await
+    .unwrap();
+    let Packet::Retry(retry) = packet else {
+      panic!("not a Retry packet");
+    };
+    assert_eq!(retry.retry_token, b"token");
+  }
+
+  #[tokio::test]
+  async fn rfc_retry_packet_v2_is_accepted() {
+    let (packet, _) = Packet::parse(
+      &DefaultCrypto,
+      &RETRY_V2,
+      &client_ctx(),
+      &PacketNumberSpaces::default(),
+    )
+    .await
+    .unwrap();
+    let Packet::Retry(retry) = packet else {
+      panic!("not a Retry packet");
+    };
+    assert_eq!(retry.version, VERSION_2);
+    assert_eq!(retry.retry_token, b"token");
+  }
+
+  #[tokio::test]
+  async fn built_retry_packet_is_accepted() {
+    let retry = Retry::new(
+      &DefaultCrypto,
+      &ConnectionId::new(&ODCID).unwrap(),
+      ConnectionId::new(&[0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5]).unwrap(),
+      ConnectionId::EMPTY,
+      1,
+      b"token",
+    )
+    .unwrap();
+    let mut buf = Vec::new();
+    Packet::Retry(retry.clone())
+      .encode_header(0, None, &mut buf)
+      .unwrap();
+
+    let (packet, _) = Packet::parse(
+      &DefaultCrypto,
+      &buf,
+      &client_ctx(),
+      &PacketNumberSpaces::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(packet, Packet::Retry(retry));
+  }
+
+  #[test]
+  fn unknown_version_fails() {
+    assert!(retry_integrity_tag(0xff00_001d, &[]).is_err());
+  }
+}
@@ -0,0 +1,433 @@
+use std::array::TryFromSliceError;
+use std::borrow::Cow;
+use std::num::TryFromIntError;
+use std::{fmt, io};
+
+// https://datatracker.ietf.org/doc/html/rfc9000#name-transport-error-codes
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum TransportErrorCode {
+  NoError,
+  InternalError,
+  ConnectionRefused,
+  FlowControlError,
+  StreamLimitError,
+  StreamStateError,
+  FinalSizeError,
+  FrameEncodingError,
+  TransportParameterError,
+  ConnectionIdLimitError,
+  ProtocolViolation,
+  InvalidToken,
+  ApplicationError,
+  CryptoBufferExceeded,
+  KeyUpdateError,
+  AeadLimitReached,
+  NoViablePath,
+  // https://datatracker.ietf.org/doc/html/rfc9368#name-version-downgrade-preventio
+  VersionNegotiationError,
+  // TLS alert carried in the low byte of 0x0100-0x01ff
+  // https://datatracker.ietf.org/doc/html/rfc9001#name-tls-errors
+  CryptoError(u8),
+  // Codes from extensions or future versions that we don't know about
+  Unknown(u64),
+}
+
+impl TransportErrorCode {
+  pub const fn code(self) -> u64 {
+    match self {
+      TransportErrorCode::NoError => 0x00,
+      TransportErrorCode::InternalError => 0x01,
+      TransportErrorCode::ConnectionRefused => 0x02,
+      TransportErrorCode::FlowControlError => 0x03,
+      TransportErrorCode::StreamLimitError => 0x04,
+      TransportErrorCode::StreamStateError => 0x05,
+      TransportErrorCode::FinalSizeError => 0x06,
+      TransportErrorCode::FrameEncodingError => 0x07,
+      TransportErrorCode::TransportParameterError => 0x08,
+      TransportErrorCode::ConnectionIdLimitError => 0x09,
+      TransportErrorCode::ProtocolViolation => 0x0a,
+      TransportErrorCode::InvalidToken => 0x0b,
+      TransportErrorCode::ApplicationError => 0x0c,
+      TransportErrorCode::CryptoBufferExceeded => 0x0d,
+      TransportErrorCode::KeyUpdateError => 0x0e,
+      TransportErrorCode::AeadLimitReached => 0x0f,
+      TransportErrorCode::NoViablePath => 0x10,
+      TransportErrorCode::VersionNegotiationError => 0x11,
+      TransportErrorCode::CryptoError(alert) => 0x0100 | alert as u64,
+      TransportErrorCode::Unknown(code) => code,
+    }
+  }
+}
+
+impl From<u64> for TransportErrorCode {
+  fn from(code: u64) -> Self {
+    match code {
+      0x00 => TransportErrorCode::NoError,
+      0x01 => TransportErrorCode::InternalError,
+      0x02 => TransportErrorCode::ConnectionRefused,
+      0x03 => TransportErrorCode::FlowControlError,
+      0x04 => TransportErrorCode::StreamLimitError,
+      0x05 => TransportErrorCode::StreamStateError,
+      0x06 => TransportErrorCode::FinalSizeError,
+      0x07 => TransportErrorCode::FrameEncodingError,
+      0x08 => TransportErrorCode::TransportParameterError,
+      0x09 => TransportErrorCode::ConnectionIdLimitError,
+      0x0a => TransportErrorCode::ProtocolViolation,
+      0x0b => TransportErrorCode::InvalidToken,
+      0x0c => TransportErrorCode::ApplicationError,
+      0x0d => TransportErrorCode::CryptoBufferExceeded,
+      0x0e => TransportErrorCode::KeyUpdateError,
+      0x0f => TransportErrorCode::AeadLimitReached,
+      0x10 => TransportErrorCode::NoViablePath,
+      0x11 => TransportErrorCode::VersionNegotiationError,
+      0x0100..=0x01ff => TransportErrorCode::CryptoError(code as u8),
+      code => TransportErrorCode::Unknown(code),
+    }
+  }
+}
+
+impl From<TransportErrorCode> for u64 {
+  fn from(code: TransportErrorCode) -> Self {
+    code.code()
+  }
+}
+
+impl fmt::Display for TransportErrorCode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      TransportErrorCode::NoError => "NO_ERROR",
+      TransportErrorCode::InternalError => "INTERNAL_ERROR",
+      TransportErrorCode::ConnectionRefused => "CONNECTION_REFUSED",
+      TransportErrorCode::FlowControlError => "FLOW_CONTROL_ERROR",
+      TransportErrorCode::StreamLimitError => "STREAM_LIMIT_ERROR",
+      TransportErrorCode::StreamStateError => "STREAM_STATE_ERROR",
+      TransportErrorCode::FinalSizeError => "FINAL_SIZE_ERROR",
+      TransportErrorCode::FrameEncodingError => "FRAME_ENCODING_ERROR",
+      TransportErrorCode::TransportParameterError => "TRANSPORT_PARAMETER_ERROR",
+      TransportErrorCode::ConnectionIdLimitError => "CONNECTION_ID_LIMIT_ERROR",
+      TransportErrorCode::ProtocolViolation => "PROTOCOL_VIOLATION",
+      TransportErrorCode::InvalidToken => "INVALID_TOKEN",
+      TransportErrorCode::ApplicationError => "APPLICATION_ERROR",
+      TransportErrorCode::CryptoBufferExceeded => "CRYPTO_BUFFER_EXCEEDED",
+      TransportErrorCode::KeyUpdateError => "KEY_UPDATE_ERROR",
+      TransportErrorCode::AeadLimitReached => "AEAD_LIMIT_REACHED",
+      TransportErrorCode::NoViablePath => "NO_VIABLE_PATH",
+      TransportErrorCode::VersionNegotiationError => "VERSION_NEGOTIATION_ERROR",
+      TransportErrorCode::CryptoError(alert) => return write!(f, "CRYPTO_ERROR({alert:#04x})"),
+      TransportErrorCode::Unknown(code) => return write!(f, "UNKNOWN({code:#x})"),
+    };
+    f.write_str(name)
+  }
+}
+
+// Where in a packet parsing failed, to make sense of errors from peers that
+// disagree with us on the encoding
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseContext {
+  // Packet type, as named in the RFC
+  pub packet_type: Option<&'static str>,
+  // Field of the packet or frame, as named in the RFC
+  pub field: Option<&'static str>,
+  // Offset of the field from the start of the packet
+  pub offset: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+  // Closes the connection with a CONNECTION_CLOSE of type 0x1c
+  Transport {
+    code: TransportErrorCode,
+    // Type of the frame that triggered the error, if any
+    frame_type: Option<u64>,
+    reason: Cow<'static, str>,
+    // Set for errors from parsing packets and frames
+    context: Option<Box<ParseContext>>,
+  },
+  // Closes the connection with a CONNECTION_CLOSE of type 0x1d
+  Application {
+    code: u64,
+    reason: Cow<'static, str>,
+  },
+  Io(io::Error),
+  // Failures that haven't been classified, treated as INTERNAL_ERROR
+  Other(Cow<'static, str>),
+}
+
+impl Error {
+  pub fn transport(code: TransportErrorCode, reason: impl Into<Cow<'static, str>>) -> Self {
+    Error::Transport {
+      code,
+      frame_type: None,
+      reason: reason.into(),
+      context: None,
+    }
+  }
+
+  pub fn application(code: u64, reason: impl Into<Cow<'static, str>>) -> Self {
+    Error::Application {
+      code,
+      reason: reason.into(),
+    }
+  }
+
+  // Transport error code to close the connection with. Application errors
+  // have their own code space, so they only report APPLICATION_ERROR here.
+  pub fn code(&self) -> TransportErrorCode {
+    match self {
+      Error::Transport { code, .. } => *code,
+      Error::Application { .. } => TransportErrorCode::ApplicationError,
+      Error::Io(_) | Error::Other(_) => TransportErrorCode::InternalError,
+    }
+  }
+
+  // Classifies an unclassified failure as `code`, and attributes transport
+  // errors without a frame type to `frame_type`. Application errors and
+  // already classified transport errors are left as is.
+  pub fn classify(self, code: TransportErrorCode, frame_type: Option<u64>) -> Self {
+    match self {
+      Error::Transport {
+        code,
+        frame_type: None,
+        reason,
+        context,
+      } => Error::Transport {
+        code,
+        frame_type,
+        reason,
+        context,
+      },
+      Error::Io(err) => Error::Transport {
+        code,
+        frame_type,
+        reason: err.to_string().into(),
+        context: None,
+      },
+      Error::Other(reason) => Error::Transport {
+        code,
+        frame_type,
+        reason,
+        context: None,
+      },
+      err => err,
+    }
+  }
+
+  pub fn context(&self) -> Option<&ParseContext> {
+    match self {
+      Error::Transport { context, .. } => context.as_deref(),
+      _ => None,
+    }
+  }
+
+  fn with_context(mut self, update: impl FnOnce(&mut ParseContext)) -> Self {
+    if let Error::Transport { context, .. } = &mut self {
+      update(context.get_or_insert_with(Default::default));
+    }
+    self
+  }
+
+  // Attributes a transport error to the field at `offset`, unless a field
+  // nested in it was already blamed
+  pub fn in_field(self, field: &'static str, offset: usize) -> Self {
+    self.with_context(|context| {
+      if context.field.is_none() {
+        context.field = Some(field);
+        context.offset = Some(offset);
+      }
+    })
+  }
+
+  // Moves the offset of a transport error by `base`, for errors from parsing
+  // part of a packet on its own
+  pub fn at_offset(self, base: usize) -> Self {
+    self.with_context(|context| {
+      if let Some(offset) = &mut context.offset {
+        *offset += base;
+      }
+    })
+  }
+
+  pub fn in_packet(self, packet_type: &'static str) -> Self {
+    self.with_context(|context| {
+      context.packet_type.get_or_insert(packet_type);
+    })
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Transport {
+        code,
+        frame_type,
+        reason,
+        context,
+      } => {
+        write!(f, "{code}")?;
+        if let Some(typ) = frame_type {
+          write!(f, " in frame {typ:#x}")?;
+        }
+        if let Some(context) = context {
+          if let Some(field) = context.field {
+            write!(f, " field {field}")?;
+          }
+          if let Some(offset) = context.offset {
+            write!(f, " at offset {offset}")?;
+          }
+          if let Some(packet_type) = context.packet_type {
+            write!(f, " of {packet_type} packet")?;
+          }
+        }
+        write!(f, ": {reason}")
+      }
+      Error::Application { code, reason } => write!(f, "application error {code:#x}: {reason}"),
+      Error::Io(err) => err.fmt(f),
+      Error::Other(reason) => f.write_str(reason),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Self {
+    Error::Io(err)
+  }
+}
+
+impl From<TryFromIntError> for Error {
+  fn from(err: TryFromIntError) -> Self {
+    Error::Other(err.to_string().into())
+  }
+}
+
+impl From<TryFromSliceError> for Error {
+  fn from(err: TryFromSliceError) -> Self {
+    Error::Other(err.to_string().into())
+  }
+}
+
+impl From<&'static str> for Error {
+  fn from(reason: &'static str) -> Self {
+    Error::Other(reason.into())
+  }
+}
+
+impl From<String> for Error {
+  fn from(reason: String) -> Self {
+    Error::Other(reason.into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn transport_error_code_round_trips() {
+    for code in (0x00..=0x11).chain([0x0100, 0x0128, 0x01ff, 0x0200, 0x1234]) {
+      assert_eq!(TransportErrorCode::from(code).code(), code);
+    }
+    assert_eq!(
+      TransportErrorCode::from(0x07),
+      TransportErrorCode::FrameEncodingError
+    );
+    assert_eq!(
+      TransportErrorCode::from(0x0150),
+      TransportErrorCode::CryptoError(0x50)
+    );
+    assert_eq!(
+      TransportErrorCode::from(0x11),
+      TransportErrorCode::VersionNegotiationError
+    );
+    assert_eq!(
+      TransportErrorCode::from(0x12),
+      TransportErrorCode::Unknown(0x12)
+    );
+  }
+
+  #[test]
+  fn error_codes() {
+    let err = Error::transport(TransportErrorCode::ProtocolViolation, "bad");
+    assert_eq!(err.code(), TransportErrorCode::ProtocolViolation);
+    assert_eq!(
+      Error::application(3, "app").code(),
+      TransportErrorCode::ApplicationError
+    );
+    assert_eq!(
+      Error::from("oops").code(),
+      TransportErrorCode::InternalError
+    );
+    let io = io::Error::from(io::ErrorKind::UnexpectedEof);
+    assert_eq!(Error::from(io).code(), TransportErrorCode::InternalError);
+  }
+
+  #[test]
+  fn classify_only_changes_unclassified_errors() {
+    let err = Error::from("short").classify(TransportErrorCode::FrameEncodingError, Some(0x06));
+    assert_eq!(err.code(), TransportErrorCode::FrameEncodingError);
+    assert!(matches!(
+      err,
+      Error::Transport {
+        frame_type: Some(0x06),
+        ..
+      }
+    ));
+
+    let err = Error::transport(TransportErrorCode::FlowControlError, "too much")
+      .classify(TransportErrorCode::FrameEncodingError, Some(0x08));
+    assert_eq!(err.code(), TransportErrorCode::FlowControlError);
+    assert!(matches!(
+      err,
+      Error::Transport {
+        frame_type: Some(0x08),
+        ..
+      }
+    ));
+
+    let err = Error::application(1, "app").classify(TransportErrorCode::FrameEncodingError, None);
+    assert!(matches!(err, Error::Application { code: 1, .. }));
+  }
+
+  #[test]
+  fn error_display() {
+    let err = Error::transport(TransportErrorCode::FrameEncodingError, "Unknown frame type")
+      .classify(TransportErrorCode::InternalError, Some(0x21));
+    assert_eq!(
+      err.to_string(),
+      "FRAME_ENCODING_ERROR in frame 0x21: Unknown frame type"
+    );
+    let err = Error::from("Buffer too short")
+      .classify(TransportErrorCode::FrameEncodingError, Some(0x06))
+      .in_field("Length", 2)
+      .in_field("Crypto Data", 4)
+      .at_offset(10)
+      .in_packet("Initial");
+    assert_eq!(
+      err.to_string(),
+      "FRAME_ENCODING_ERROR in frame 0x6 field Length at offset 12 of Initial packet: Buffer too \
+       short"
+    );
+    assert_eq!(
+      err.context(),
+      Some(&ParseContext {
+        packet_type: Some("Initial"),
+        field: Some("Length"),
+        offset: Some(12),
+      })
+    );
+    // Only transport errors are given a context
+    assert_eq!(Error::from("oops").in_field("Length", 2).context(), None);
+    assert_eq!(
+      TransportErrorCode::CryptoError(0x28).to_string(),
+      "CRYPTO_ERROR(0x28)"
+    );
+  }
+}
@@ -1,7 +1,11 @@
 pub use std::future::*;
 
-pub use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+mod error;
+pub use error::*;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub use tokio::sync::Mutex;
 
@@ -17,9 +21,7 @@ pub trait Buffer: ReadBytesExt {
 impl Buffer for &[u8] {
   fn slice(&mut self, len: usize) -> Result<Self> {
     let ret;
-    (ret, *self) = self
-      .split_at_checked(len)
-      .ok_or_else(|| "Buffer too short")?;
+    (ret, *self) = self.split_at_checked(len).ok_or("Buffer too short")?;
     Ok(ret)
   }
 
@@ -28,12 +30,12 @@ impl Buffer for &[u8] {
     if let Some(off) = off {
       (_, *self) = self
         .split_at_checked(off)
-        .ok_or_else(|| "Buffer too short for offset")?;
+        .ok_or("Buffer too short for offset")?;
     }
     if let Some(len) = len {
       (ret, *self) = self
         .split_at_checked(len)
-        .ok_or_else(|| "Buffer too short for length")?;
+        .ok_or("Buffer too short for length")?;
       return Ok(ret);
     }
     let data = *self;